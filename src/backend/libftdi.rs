@@ -0,0 +1,362 @@
+use std::convert::TryInto;
+use std::io;
+
+use super::Backend;
+use crate::error::{ErrorContext, UsbError};
+use crate::{
+    ffi, BitMode, Bits, ChipType, DeviceId, Error, FlowControl, ModemStatus, Parity, Result,
+    StopBits,
+};
+
+/// The `libftdi1` backend, owning an opened `ftdi_context`
+pub struct LibFtdi {
+    context: *mut ffi::ftdi_context,
+    device: DeviceId,
+    disconnected: bool,
+}
+
+impl LibFtdi {
+    /// Takes over an opened context, `device` being what the opener knows about it
+    pub(crate) fn from_context(context: *mut ffi::ftdi_context, device: DeviceId) -> Self {
+        let mut backend = LibFtdi {
+            context,
+            device,
+            disconnected: false,
+        };
+        backend.identify();
+        backend
+    }
+
+    pub fn context(&mut self) -> *mut ffi::ftdi_context {
+        self.context
+    }
+
+    /// Fills in the identity of the device from its descriptors
+    #[cfg(feature = "libusb1-sys")]
+    fn identify(&mut self) {
+        use ffi::libusb1_sys as usb;
+
+        let handle = unsafe { (*self.context).usb_dev };
+        if handle.is_null() {
+            return;
+        }
+        let device = unsafe { usb::libusb_get_device(handle) };
+        self.device.bus = Some(unsafe { usb::libusb_get_bus_number(device) });
+        self.device.address = Some(unsafe { usb::libusb_get_device_address(device) });
+        let mut ports = [0u8; 7];
+        let len =
+            unsafe { usb::libusb_get_port_numbers(device, ports.as_mut_ptr(), ports.len() as i32) };
+        if len >= 0 {
+            self.device.port_path = Some(ports[..len as usize].to_vec());
+        }
+
+        let mut descriptor = std::mem::MaybeUninit::<usb::libusb_device_descriptor>::uninit();
+        if unsafe { usb::libusb_get_device_descriptor(device, descriptor.as_mut_ptr()) } != 0 {
+            return;
+        }
+        let descriptor = unsafe { descriptor.assume_init() };
+        self.device.vid = Some(descriptor.idVendor);
+        self.device.pid = Some(descriptor.idProduct);
+        if descriptor.iSerialNumber == 0 {
+            return;
+        }
+        let mut serial = [0u8; 64];
+        let len = unsafe {
+            usb::libusb_get_string_descriptor_ascii(
+                handle,
+                descriptor.iSerialNumber,
+                serial.as_mut_ptr(),
+                serial.len() as i32,
+            )
+        };
+        if len >= 0 {
+            let serial = &serial[..len as usize];
+            self.device.serial = Some(String::from_utf8_lossy(serial).into_owned());
+        }
+    }
+
+    #[cfg(not(feature = "libusb1-sys"))]
+    fn identify(&mut self) {}
+
+    fn error_context(&self, operation: &'static str, code: i32) -> ErrorContext {
+        ErrorContext::new(operation)
+            .with_code(code)
+            .with_libftdi_message(self.context)
+            .with_device(&self.device)
+    }
+
+    fn unknown(&self, operation: &'static str, code: i32) -> Error {
+        Error::unknown(self.error_context(operation, code))
+    }
+
+    /// Checks whether a failure was caused by the device going away
+    ///
+    /// `libftdi` reports failed control requests without the underlying `libusb`
+    /// error, so the device is probed with a standard `GET_STATUS` request when
+    /// `libusb` itself is available. Otherwise only bulk transfers failing with
+    /// `LIBUSB_ERROR_NO_DEVICE` reveal the disconnection.
+    fn check_disconnected(&mut self) -> bool {
+        if !self.disconnected {
+            self.disconnected = self.probe_disconnected();
+        }
+        self.disconnected
+    }
+
+    #[cfg(feature = "libusb1-sys")]
+    fn probe_disconnected(&mut self) -> bool {
+        use ffi::libusb1_sys::{self as usb, constants};
+
+        let handle = unsafe { (*self.context).usb_dev };
+        if handle.is_null() {
+            return true;
+        }
+        let mut status = [0u8; 2];
+        let result = unsafe {
+            usb::libusb_control_transfer(
+                handle,
+                constants::LIBUSB_ENDPOINT_IN,
+                constants::LIBUSB_REQUEST_GET_STATUS,
+                0,
+                0,
+                status.as_mut_ptr(),
+                2,
+                1000,
+            )
+        };
+        result == constants::LIBUSB_ERROR_NO_DEVICE
+    }
+
+    #[cfg(not(feature = "libusb1-sys"))]
+    fn probe_disconnected(&mut self) -> bool {
+        unsafe { (*self.context).usb_dev }.is_null()
+    }
+
+    fn request_failed(&mut self, operation: &'static str, code: i32) -> Error {
+        if self.check_disconnected() {
+            Error::Disconnected
+        } else {
+            Error::request_failed(self.error_context(operation, code))
+        }
+    }
+
+    fn transfer_failed(&mut self, code: i32) -> io::Error {
+        let error = UsbError::from_code(code);
+        if error == UsbError::NoDevice || self.check_disconnected() {
+            self.disconnected = true;
+            UsbError::NoDevice.into()
+        } else {
+            error.into()
+        }
+    }
+}
+
+impl Backend for LibFtdi {
+    fn device_id(&self) -> Option<&DeviceId> {
+        Some(&self.device)
+    }
+
+    fn chip_type(&self) -> Option<ChipType> {
+        ChipType::from_ffi(unsafe { (*self.context).type_ })
+    }
+
+    fn set_baud_rate(&mut self, rate: u32) -> Result<()> {
+        let rate = rate
+            .try_into()
+            .map_err(|_| Error::InvalidInput("unsupported baudrate"))?;
+        let result = unsafe { ffi::ftdi_set_baudrate(self.context, rate) };
+        match result {
+            0 => Ok(()),
+            -1 => Err(Error::InvalidInput("unsupported baudrate")),
+            -2 => Err(self.request_failed("set_baud_rate", result)),
+            _ => Err(self.unknown("set_baud_rate", result)),
+        }
+    }
+
+    fn set_line_property(&mut self, bits: Bits, stop_bits: StopBits, parity: Parity) -> Result<()> {
+        let result = unsafe {
+            ffi::ftdi_set_line_property(self.context, bits.into(), stop_bits.into(), parity.into())
+        };
+        match result {
+            0 => Ok(()),
+            -1 => Err(self.request_failed("set_line_property", result)),
+            _ => Err(self.unknown("set_line_property", result)),
+        }
+    }
+
+    fn usb_reset(&mut self) -> Result<()> {
+        let result = unsafe { ffi::ftdi_usb_reset(self.context) };
+        match result {
+            0 => Ok(()),
+            -1 => Err(self.request_failed("usb_reset", result)),
+            _ => Err(self.unknown("usb_reset", result)),
+        }
+    }
+
+    fn usb_purge_buffers(&mut self) -> Result<()> {
+        let result = unsafe { ffi::ftdi_usb_purge_buffers(self.context) };
+        match result {
+            0 => Ok(()),
+            -1 /* read */ | -2 /* write */ => Err(self.request_failed("usb_purge_buffers", result)),
+            _ => Err(self.unknown("usb_purge_buffers", result)),
+        }
+    }
+
+    fn usb_purge_tx_buffer(&mut self) -> Result<()> {
+        let result = unsafe { ffi::ftdi_usb_purge_tx_buffer(self.context) };
+        match result {
+            0 => Ok(()),
+            -1 => Err(self.request_failed("usb_purge_tx_buffer", result)),
+            _ => Err(self.unknown("usb_purge_tx_buffer", result)),
+        }
+    }
+
+    fn set_event_char(&mut self, value: Option<u8>) -> Result<()> {
+        let result = if let Some(v) = value {
+            unsafe { ffi::ftdi_set_event_char(self.context, v, 1) }
+        } else {
+            unsafe { ffi::ftdi_set_event_char(self.context, 0, 0) }
+        };
+
+        match result {
+            0 => Ok(()),
+            -1 => Err(self.request_failed("set_event_char", result)),
+            _ => Err(self.unknown("set_event_char", result)),
+        }
+    }
+
+    fn set_error_char(&mut self, value: Option<u8>) -> Result<()> {
+        let result = if let Some(v) = value {
+            unsafe { ffi::ftdi_set_error_char(self.context, v, 1) }
+        } else {
+            unsafe { ffi::ftdi_set_error_char(self.context, 0, 0) }
+        };
+
+        match result {
+            0 => Ok(()),
+            -1 => Err(self.request_failed("set_error_char", result)),
+            _ => Err(self.unknown("set_error_char", result)),
+        }
+    }
+
+    fn set_latency_timer(&mut self, value: u8) -> Result<()> {
+        let result = unsafe { ffi::ftdi_set_latency_timer(self.context, value) };
+        match result {
+            0 => Ok(()),
+            -1 => Err(Error::InvalidInput("latency value out of range")),
+            -2 => Err(self.request_failed("set_latency_timer", result)),
+            _ => Err(self.unknown("set_latency_timer", result)),
+        }
+    }
+
+    fn latency_timer(&mut self) -> Result<u8> {
+        let mut value = 0u8;
+        let result = unsafe { ffi::ftdi_get_latency_timer(self.context, &mut value) };
+        match result {
+            0 => Ok(value),
+            -1 => Err(self.request_failed("latency_timer", result)),
+            _ => Err(self.unknown("latency_timer", result)),
+        }
+    }
+
+    fn set_write_chunksize(&mut self, value: u32) -> Result<()> {
+        let result = unsafe { ffi::ftdi_write_data_set_chunksize(self.context, value) };
+        match result {
+            0 => Ok(()),
+            _ => Err(self.unknown("set_write_chunksize", result)),
+        }
+    }
+
+    fn write_chunksize(&mut self) -> Result<u32> {
+        let mut value = 0;
+        let result = unsafe { ffi::ftdi_write_data_get_chunksize(self.context, &mut value) };
+        match result {
+            0 => Ok(value),
+            _ => Err(self.unknown("write_chunksize", result)),
+        }
+    }
+
+    fn set_read_chunksize(&mut self, value: u32) -> Result<()> {
+        let result = unsafe { ffi::ftdi_read_data_set_chunksize(self.context, value) };
+        match result {
+            0 => Ok(()),
+            _ => Err(self.unknown("set_read_chunksize", result)),
+        }
+    }
+
+    fn read_chunksize(&mut self) -> Result<u32> {
+        let mut value = 0;
+        let result = unsafe { ffi::ftdi_read_data_get_chunksize(self.context, &mut value) };
+        match result {
+            0 => Ok(value),
+            _ => Err(self.unknown("read_chunksize", result)),
+        }
+    }
+
+    fn set_flow_control(&mut self, flowctrl: FlowControl) -> Result<()> {
+        let result = unsafe { ffi::ftdi_setflowctrl(self.context, flowctrl.to_ffi()) };
+        match result {
+            0 => Ok(()),
+            -1 => Err(self.request_failed("set_flow_control", result)),
+            _ => Err(self.unknown("set_flow_control", result)),
+        }
+    }
+
+    fn set_bitmode(&mut self, output_mask: u8, mode: BitMode) -> Result<()> {
+        let mode = mode
+            .to_ffi()
+            .0
+            .try_into()
+            .map_err(|_| Error::InvalidInput("unsupported bit mode"))?;
+        let result = unsafe { ffi::ftdi_set_bitmode(self.context, output_mask, mode) };
+        match result {
+            0 => Ok(()),
+            -1 => Err(self.request_failed("set_bitmode", result)),
+            _ => Err(self.unknown("set_bitmode", result)),
+        }
+    }
+
+    fn modem_status(&mut self) -> Result<ModemStatus> {
+        let mut status = 0u16;
+        let result = unsafe { ffi::ftdi_poll_modem_status(self.context, &mut status) };
+        match result {
+            0 => Ok(ModemStatus(status)),
+            -1 => Err(self.request_failed("modem_status", result)),
+            -2 => Err(Error::Disconnected),
+            _ => Err(self.unknown("modem_status", result)),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let len = buf.len().try_into().unwrap_or(std::i32::MAX);
+        let result = unsafe { ffi::ftdi_read_data(self.context, buf.as_mut_ptr(), len) };
+        match result {
+            count if count >= 0 => Ok(count as usize),
+            err => Err(self.transfer_failed(err)),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let len = buf.len().try_into().unwrap_or(std::i32::MAX);
+        let result = unsafe { ffi::ftdi_write_data(self.context, buf.as_ptr(), len) };
+        match result {
+            count if count >= 0 => Ok(count as usize),
+            err => Err(self.transfer_failed(err)),
+        }
+    }
+
+    fn readbuffer_remaining(&self) -> usize {
+        unsafe { (*self.context).readbuffer_remaining }
+            .try_into()
+            .unwrap_or(0)
+    }
+}
+
+impl Drop for LibFtdi {
+    fn drop(&mut self) {
+        // a failure to release the device cannot be reported from here
+        unsafe {
+            ffi::ftdi_usb_close(self.context);
+            ffi::ftdi_free(self.context);
+        }
+    }
+}
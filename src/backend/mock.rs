@@ -0,0 +1,366 @@
+//! A scriptable backend for testing code built on top of [`Device`](crate::Device)
+//!
+//! The mock is given the calls it should expect, in order, along with the reply
+//! to each of them. Data replies to reads are buffered the way `libftdi` buffers
+//! USB packets, so a single expected read may satisfy several smaller reads.
+//!
+//! # Example
+//!
+//! ```
+//! use ftdi::backend::mock::{Call, Mock, Reply};
+//! use ftdi::{BitMode, Device, Error};
+//! use std::io::{Read, Write};
+//!
+//! let mut mock = Mock::new();
+//! mock.expect(Call::SetBitmode(0x0b, BitMode::Mpsse), Reply::Done)
+//!     .expect_write(&[0x81])
+//!     .expect_read(&[0x08])
+//!     .expect(Call::UsbReset, Reply::Error(Error::Disconnected));
+//!
+//! let mut device = Device::with_backend(mock);
+//! device.set_bitmode(0x0b, BitMode::Mpsse).unwrap();
+//! device.write_all(&[0x81]).unwrap();
+//! let mut pins = [0];
+//! device.read_exact(&mut pins).unwrap();
+//! assert_eq!(pins, [0x08]);
+//! assert!(device.usb_reset().is_err());
+//! device.backend().assert_done();
+//! ```
+
+use std::collections::VecDeque;
+use std::io;
+
+use super::Backend;
+use crate::error::libftdi_to_io;
+use crate::stats::Transfers;
+use crate::{BitMode, Bits, ChipType, Error, FlowControl, ModemStatus, Parity, Result, StopBits};
+
+/// A call to the backend
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Call {
+    SetBaudRate(u32),
+    SetLineProperty(Bits, StopBits, Parity),
+    UsbReset,
+    UsbPurgeBuffers,
+    UsbPurgeTxBuffer,
+    SetEventChar(Option<u8>),
+    SetErrorChar(Option<u8>),
+    SetLatencyTimer(u8),
+    LatencyTimer,
+    SetWriteChunksize(u32),
+    WriteChunksize,
+    SetReadChunksize(u32),
+    ReadChunksize,
+    SetFlowControl(FlowControl),
+    SetBitmode(u8, BitMode),
+    ModemStatus,
+    /// A read finding the receive buffer empty
+    Read,
+    Write(Vec<u8>),
+}
+
+/// The reply to an expected call
+#[derive(Debug)]
+pub enum Reply {
+    /// Success for calls without a result; writes accept all the data
+    Done,
+    LatencyTimer(u8),
+    Chunksize(u32),
+    ModemStatus(ModemStatus),
+    /// Data received from the chip, empty if nothing arrived yet
+    Data(Vec<u8>),
+    /// Data received from the chip, leaving a number of bytes in the receive buffer
+    ///
+    /// The buffered bytes are only counted by `readbuffer_remaining`, the reads
+    /// returning them are expected separately. This reproduces recorded sessions,
+    /// see [`record`](super::record).
+    Buffered(Vec<u8>, usize),
+    /// An injected failure, reported as an `io::Error` by reads and writes
+    Error(Error),
+}
+
+/// A backend replaying scripted replies to expected calls
+///
+/// # Panics
+///
+/// Calls that do not match the next expectation, or a reply that does not fit
+/// the call, panic with a description of the mismatch.
+#[derive(Debug, Default)]
+pub struct Mock {
+    chip_type: Option<ChipType>,
+    expectations: VecDeque<(Call, Reply)>,
+    received: VecDeque<u8>,
+    buffered: usize,
+    transfers: Transfers,
+}
+
+impl Mock {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Sets the chip type reported by the backend, `None` by default
+    pub fn with_chip_type(mut self, chip_type: ChipType) -> Self {
+        self.chip_type = Some(chip_type);
+        self
+    }
+
+    pub fn expect(&mut self, call: Call, reply: Reply) -> &mut Self {
+        self.expectations.push_back((call, reply));
+        self
+    }
+
+    /// Expects `data` to be written in a single call
+    pub fn expect_write(&mut self, data: &[u8]) -> &mut Self {
+        self.expect(Call::Write(data.to_vec()), Reply::Done)
+    }
+
+    /// Expects a read and answers it with `data`
+    pub fn expect_read(&mut self, data: &[u8]) -> &mut Self {
+        self.expect(Call::Read, Reply::Data(data.to_vec()))
+    }
+
+    /// Whether all the expected calls have been made and the received data read
+    pub fn is_done(&self) -> bool {
+        self.expectations.is_empty() && self.received.is_empty()
+    }
+
+    /// Panics if some expected calls have not been made or some data is left unread
+    pub fn assert_done(&self) {
+        assert!(
+            self.expectations.is_empty(),
+            "expected calls not made: {:?}",
+            self.expectations
+        );
+        assert!(
+            self.received.is_empty(),
+            "received data not read: {:?}",
+            self.received
+        );
+    }
+
+    fn next(&mut self, call: Call) -> Reply {
+        match self.expectations.pop_front() {
+            Some((expected, reply)) if expected == call => reply,
+            Some((expected, _)) => panic!("expected {:?}, got {:?}", expected, call),
+            None => panic!("unexpected {:?}", call),
+        }
+    }
+
+    fn done(&mut self, call: Call) -> Result<()> {
+        match self.next(call) {
+            Reply::Done => Ok(()),
+            Reply::Error(e) => Err(e),
+            reply => panic!("reply {:?} does not fit a call without result", reply),
+        }
+    }
+
+    fn chunksize(&mut self, call: Call) -> Result<u32> {
+        match self.next(call) {
+            Reply::Chunksize(value) => Ok(value),
+            Reply::Error(e) => Err(e),
+            reply => panic!("reply {:?} does not fit a chunk size query", reply),
+        }
+    }
+}
+
+impl Backend for Mock {
+    fn chip_type(&self) -> Option<ChipType> {
+        self.chip_type
+    }
+
+    fn set_baud_rate(&mut self, rate: u32) -> Result<()> {
+        self.done(Call::SetBaudRate(rate))
+    }
+
+    fn set_line_property(&mut self, bits: Bits, stop_bits: StopBits, parity: Parity) -> Result<()> {
+        self.done(Call::SetLineProperty(bits, stop_bits, parity))
+    }
+
+    fn usb_reset(&mut self) -> Result<()> {
+        self.done(Call::UsbReset)
+    }
+
+    fn usb_purge_buffers(&mut self) -> Result<()> {
+        self.received.clear();
+        self.buffered = 0;
+        self.done(Call::UsbPurgeBuffers)
+    }
+
+    fn usb_purge_tx_buffer(&mut self) -> Result<()> {
+        self.done(Call::UsbPurgeTxBuffer)
+    }
+
+    fn set_event_char(&mut self, value: Option<u8>) -> Result<()> {
+        self.done(Call::SetEventChar(value))
+    }
+
+    fn set_error_char(&mut self, value: Option<u8>) -> Result<()> {
+        self.done(Call::SetErrorChar(value))
+    }
+
+    fn set_latency_timer(&mut self, value: u8) -> Result<()> {
+        self.done(Call::SetLatencyTimer(value))
+    }
+
+    fn latency_timer(&mut self) -> Result<u8> {
+        match self.next(Call::LatencyTimer) {
+            Reply::LatencyTimer(value) => Ok(value),
+            Reply::Error(e) => Err(e),
+            reply => panic!("reply {:?} does not fit a latency timer query", reply),
+        }
+    }
+
+    fn set_write_chunksize(&mut self, value: u32) -> Result<()> {
+        self.done(Call::SetWriteChunksize(value))
+    }
+
+    fn write_chunksize(&mut self) -> Result<u32> {
+        self.chunksize(Call::WriteChunksize)
+    }
+
+    fn set_read_chunksize(&mut self, value: u32) -> Result<()> {
+        self.done(Call::SetReadChunksize(value))
+    }
+
+    fn read_chunksize(&mut self) -> Result<u32> {
+        self.chunksize(Call::ReadChunksize)
+    }
+
+    fn set_flow_control(&mut self, flowctrl: FlowControl) -> Result<()> {
+        self.done(Call::SetFlowControl(flowctrl))
+    }
+
+    fn set_bitmode(&mut self, output_mask: u8, mode: BitMode) -> Result<()> {
+        self.done(Call::SetBitmode(output_mask, mode))
+    }
+
+    fn modem_status(&mut self) -> Result<ModemStatus> {
+        match self.next(Call::ModemStatus) {
+            Reply::ModemStatus(status) => Ok(status),
+            Reply::Error(e) => Err(e),
+            reply => panic!("reply {:?} does not fit a modem status query", reply),
+        }
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.received.is_empty() {
+            self.transfers.read += 1;
+            match self.next(Call::Read) {
+                Reply::Data(data) => {
+                    self.received.extend(data);
+                    self.buffered = 0;
+                }
+                Reply::Buffered(data, buffered) => {
+                    self.received.extend(data);
+                    self.buffered = buffered;
+                }
+                Reply::Error(e) => return Err(libftdi_to_io(e)),
+                reply => panic!("reply {:?} does not fit a read", reply),
+            }
+        }
+        let len = buf.len().min(self.received.len());
+        for (byte, received) in buf.iter_mut().zip(self.received.drain(..len)) {
+            *byte = received;
+        }
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.transfers.write += 1;
+        match self.next(Call::Write(buf.to_vec())) {
+            Reply::Done => Ok(buf.len()),
+            Reply::Error(e) => Err(libftdi_to_io(e)),
+            reply => panic!("reply {:?} does not fit a write", reply),
+        }
+    }
+
+    fn readbuffer_remaining(&self) -> usize {
+        self.received.len() + self.buffered
+    }
+
+    /// Each scripted read and write counts as one transfer
+    fn transfers(&self) -> Option<Transfers> {
+        Some(self.transfers)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Device;
+    use std::io::{Read, Write};
+
+    #[test]
+    fn data_replies_are_buffered_across_reads() {
+        let mut mock = Mock::new();
+        mock.expect_read(&[1, 2, 3]).expect_read(&[4]);
+        let mut device = Device::with_backend(mock);
+        let mut buf = [0; 2];
+        assert_eq!(device.read(&mut buf).unwrap(), 2);
+        assert_eq!(buf, [1, 2]);
+        assert_eq!(device.backend().readbuffer_remaining(), 1);
+        assert_eq!(device.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 3);
+        assert_eq!(device.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 4);
+        device.backend().assert_done();
+    }
+
+    #[test]
+    fn injected_errors_are_returned() {
+        let mut mock = Mock::new();
+        mock.expect(Call::SetLatencyTimer(16), Reply::Error(Error::Disconnected))
+            .expect(Call::Write(vec![0x87]), Reply::Error(Error::Disconnected))
+            .expect(Call::Read, Reply::Error(Error::Disconnected));
+        let mut device = Device::with_backend(mock);
+        assert!(matches!(
+            device.set_latency_timer(16),
+            Err(Error::Disconnected)
+        ));
+        let err = device.write(&[0x87]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        let err = device.read(&mut [0]).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::NotConnected);
+        assert!(device.backend().is_done());
+    }
+
+    #[test]
+    #[should_panic(expected = "expected Write([1]), got Write([2])")]
+    fn mismatched_call_panics() {
+        let mut mock = Mock::new();
+        mock.expect_write(&[1]);
+        let _ = mock.write(&[2]);
+    }
+
+    #[test]
+    #[should_panic(expected = "unexpected UsbReset")]
+    fn unexpected_call_panics() {
+        let _ = Mock::new().usb_reset();
+    }
+
+    #[test]
+    #[should_panic(expected = "does not fit a latency timer query")]
+    fn mismatched_reply_panics() {
+        let mut mock = Mock::new();
+        mock.expect(Call::LatencyTimer, Reply::Done);
+        let _ = mock.latency_timer();
+    }
+
+    #[test]
+    #[should_panic(expected = "expected calls not made")]
+    fn missing_call_panics() {
+        let mut mock = Mock::new();
+        mock.expect(Call::UsbPurgeBuffers, Reply::Done);
+        mock.assert_done();
+    }
+
+    #[test]
+    #[should_panic(expected = "received data not read")]
+    fn unread_data_panics() {
+        let mut mock = Mock::new();
+        mock.expect_read(&[1, 2]);
+        let _ = mock.read(&mut [0]);
+        mock.assert_done();
+    }
+}
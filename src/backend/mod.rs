@@ -0,0 +1,84 @@
+//! Transports a [`Device`](crate::Device) can talk to the chip through
+//!
+//! `LibFtdi` is the default backend, driving real hardware through `libftdi1`.
+//! `Rusb`, behind the `rusb` feature, implements the FTDI vendor protocol
+//! directly on top of `rusb` and does not need `libftdi1` at all. The [`mock`]
+//! backend replays a scripted sequence of calls, so that code built
+//! on top of `Device` can be unit-tested without a USB stack, and [`record`]
+//! captures real sessions so that they can be replayed through it.
+
+use std::io;
+
+use super::stats::Transfers;
+use super::{
+    BitMode, Bits, ChipType, DeviceId, FlowControl, ModemStatus, Parity, Result, StopBits,
+};
+
+#[cfg(feature = "libftdi1-sys")]
+mod libftdi;
+pub mod mock;
+#[cfg(feature = "rusb")]
+mod native;
+pub mod protocol;
+pub mod record;
+
+#[cfg(feature = "libftdi1-sys")]
+pub use libftdi::LibFtdi;
+#[cfg(feature = "rusb")]
+pub use native::Rusb;
+
+/// The operations a [`Device`](crate::Device) needs from the underlying transport
+pub trait Backend {
+    /// The chip type detected when opening the device
+    fn chip_type(&self) -> Option<ChipType>;
+
+    /// The identity of the device, used to tell adapters apart in diagnostics
+    fn device_id(&self) -> Option<&DeviceId> {
+        None
+    }
+
+    fn set_baud_rate(&mut self, rate: u32) -> Result<()>;
+
+    fn set_line_property(&mut self, bits: Bits, stop_bits: StopBits, parity: Parity) -> Result<()>;
+
+    fn usb_reset(&mut self) -> Result<()>;
+
+    fn usb_purge_buffers(&mut self) -> Result<()>;
+
+    fn usb_purge_tx_buffer(&mut self) -> Result<()>;
+
+    fn set_event_char(&mut self, value: Option<u8>) -> Result<()>;
+
+    fn set_error_char(&mut self, value: Option<u8>) -> Result<()>;
+
+    fn set_latency_timer(&mut self, value: u8) -> Result<()>;
+
+    fn latency_timer(&mut self) -> Result<u8>;
+
+    fn set_write_chunksize(&mut self, value: u32) -> Result<()>;
+
+    fn write_chunksize(&mut self) -> Result<u32>;
+
+    fn set_read_chunksize(&mut self, value: u32) -> Result<()>;
+
+    fn read_chunksize(&mut self) -> Result<u32>;
+
+    fn set_flow_control(&mut self, flowctrl: FlowControl) -> Result<()>;
+
+    fn set_bitmode(&mut self, output_mask: u8, mode: BitMode) -> Result<()>;
+
+    fn modem_status(&mut self) -> Result<ModemStatus>;
+
+    /// Reads the data already received from the chip, returning 0 if there is none
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize>;
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize>;
+
+    /// Number of bytes received from the chip and not read yet
+    fn readbuffer_remaining(&self) -> usize;
+
+    /// Bulk transfers made since opening the device, if the backend makes them itself
+    fn transfers(&self) -> Option<Transfers> {
+        None
+    }
+}
@@ -0,0 +1,386 @@
+use std::collections::VecDeque;
+use std::io;
+use std::time::Duration;
+
+use rusb::{DeviceHandle, GlobalContext, UsbContext};
+
+use super::protocol::{self, ControlRequest};
+use super::Backend;
+use crate::error::{ErrorContext, UsbError};
+use crate::stats::Transfers;
+use crate::trace;
+use crate::{
+    BitMode, Bits, ChipType, DeviceId, Error, FlowControl, Interface, ModemStatus, Parity, Result,
+    StopBits,
+};
+
+/// The default USB timeouts of `libftdi`
+const TIMEOUT: Duration = Duration::from_secs(5);
+
+fn usb_to_io(err: rusb::Error) -> io::Error {
+    UsbError::from(err).into()
+}
+
+/// Encodes the version decoded by `rusb` back into the raw `bcdDevice` field
+///
+/// `rusb` decodes the two major digits as a decimal number, so `0x1000` becomes
+/// version 10.0.0.
+fn bcd_device(version: rusb::Version) -> u16 {
+    let major = u16::from(version.major());
+    ((major / 10) << 12)
+        | ((major % 10) << 8)
+        | (u16::from(version.minor()) << 4)
+        | u16::from(version.sub_minor())
+}
+
+/// A backend implementing the FTDI vendor protocol directly on top of `rusb`
+///
+/// # Example
+///
+/// ```no_run
+/// use ftdi::backend::Rusb;
+/// use ftdi::{Device, Interface};
+///
+/// # fn main() -> ftdi::Result<()> {
+/// let handle = rusb::open_device_with_vid_pid(0x0403, 0x6014).ok_or(ftdi::Error::DeviceNotFound)?;
+/// let mut device = Device::with_backend(Rusb::open(handle, Interface::A)?);
+/// device.set_baud_rate(115_200)?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct Rusb<T: UsbContext = GlobalContext> {
+    handle: DeviceHandle<T>,
+    device: DeviceId,
+    interface: Interface,
+    usb_interface: u8,
+    in_endpoint: u8,
+    out_endpoint: u8,
+    packet_size: usize,
+    chip_type: Option<ChipType>,
+    read_chunksize: u32,
+    write_chunksize: u32,
+    bitbang: bool,
+    received: VecDeque<u8>,
+    transfers: Transfers,
+}
+
+impl<T: UsbContext> Rusb<T> {
+    /// Claims the interface of an opened device and resets it, as `libftdi` does
+    pub fn open(handle: DeviceHandle<T>, interface: Interface) -> Result<Self> {
+        let device = handle.device();
+        let device_id = DeviceId {
+            bus: Some(device.bus_number()),
+            address: Some(device.address()),
+            ..DeviceId::default()
+        };
+        let span = trace::Span::enter(Some(&device_id), "open");
+        let result = Self::open_interface(handle, interface);
+        span.finish(interface, result)
+    }
+
+    fn open_interface(mut handle: DeviceHandle<T>, interface: Interface) -> Result<Self> {
+        let device = handle.device();
+        let descriptor = device
+            .device_descriptor()
+            .map_err(|_| Error::EnumerationFailed)?;
+        let bcd_device = bcd_device(descriptor.device_version());
+        let device_id = DeviceId {
+            bus: Some(device.bus_number()),
+            address: Some(device.address()),
+            port_path: device.port_numbers().ok(),
+            vid: Some(descriptor.vendor_id()),
+            pid: Some(descriptor.product_id()),
+            serial: handle.read_serial_number_string_ascii(&descriptor).ok(),
+        };
+        let chip_type = protocol::chip_type(
+            bcd_device,
+            descriptor.serial_number_string_index().is_some(),
+        );
+
+        let (usb_interface, in_endpoint, out_endpoint) = protocol::endpoints(interface);
+        let config = device
+            .active_config_descriptor()
+            .map_err(|_| Error::EnumerationFailed)?;
+        let packet_size = config
+            .interfaces()
+            .find(|i| i.number() == usb_interface)
+            .ok_or(Error::NoSuchInterface)?
+            .descriptors()
+            .flat_map(|d| d.endpoint_descriptors().collect::<Vec<_>>())
+            .find(|e| e.address() == in_endpoint)
+            .map_or(64, |e| usize::from(e.max_packet_size()));
+
+        // not supported on all platforms, claiming reports the actual failure
+        let _ = handle.set_auto_detach_kernel_driver(true);
+        handle
+            .claim_interface(usb_interface)
+            .map_err(|_| Error::ClaimFailed)?;
+
+        let mut backend = Rusb {
+            handle,
+            device: device_id,
+            interface,
+            usb_interface,
+            in_endpoint,
+            out_endpoint,
+            packet_size,
+            chip_type,
+            read_chunksize: 4096,
+            write_chunksize: 4096,
+            bitbang: false,
+            received: VecDeque::new(),
+            transfers: Transfers::default(),
+        };
+        backend.control("usb_reset", protocol::reset(interface))?;
+        backend.set_baud_rate(9600)?;
+        Ok(backend)
+    }
+
+    pub fn handle(&self) -> &DeviceHandle<T> {
+        &self.handle
+    }
+
+    /// Converts a failed control transfer, keeping the operation and device
+    fn request_failed(&self, operation: &'static str, err: rusb::Error) -> Error {
+        match UsbError::from(err) {
+            UsbError::NoDevice => Error::Disconnected,
+            err => Error::request_failed(
+                ErrorContext::new(operation)
+                    .with_code(err.code())
+                    .with_device(&self.device),
+            ),
+        }
+    }
+
+    fn control(&mut self, operation: &'static str, request: ControlRequest) -> Result<()> {
+        self.handle
+            .write_control(
+                request.request_type,
+                request.request,
+                request.value,
+                request.index,
+                &[],
+                TIMEOUT,
+            )
+            .map_err(|err| self.request_failed(operation, err))?;
+        Ok(())
+    }
+
+    fn control_in(
+        &mut self,
+        operation: &'static str,
+        request: ControlRequest,
+        buf: &mut [u8],
+    ) -> Result<()> {
+        let len = self
+            .handle
+            .read_control(
+                request.request_type,
+                request.request,
+                request.value,
+                request.index,
+                &mut buf[..usize::from(request.length)],
+                TIMEOUT,
+            )
+            .map_err(|err| self.request_failed(operation, err))?;
+        if len == usize::from(request.length) {
+            Ok(())
+        } else {
+            Err(Error::request_failed(
+                ErrorContext::new(operation).with_device(&self.device),
+            ))
+        }
+    }
+}
+
+impl<T: UsbContext> Backend for Rusb<T> {
+    fn device_id(&self) -> Option<&DeviceId> {
+        Some(&self.device)
+    }
+
+    fn chip_type(&self) -> Option<ChipType> {
+        self.chip_type
+    }
+
+    fn set_baud_rate(&mut self, rate: u32) -> Result<()> {
+        let (request, _) =
+            protocol::set_baud_rate(self.interface, self.chip_type, rate, self.bitbang)?;
+        self.control("set_baud_rate", request)
+    }
+
+    fn set_line_property(&mut self, bits: Bits, stop_bits: StopBits, parity: Parity) -> Result<()> {
+        self.control(
+            "set_line_property",
+            protocol::set_line_property(self.interface, bits, stop_bits, parity),
+        )
+    }
+
+    fn usb_reset(&mut self) -> Result<()> {
+        self.received.clear();
+        self.control("usb_reset", protocol::reset(self.interface))
+    }
+
+    fn usb_purge_buffers(&mut self) -> Result<()> {
+        self.received.clear();
+        self.control(
+            "usb_purge_buffers",
+            protocol::purge_rx_buffer(self.interface),
+        )?;
+        self.control(
+            "usb_purge_buffers",
+            protocol::purge_tx_buffer(self.interface),
+        )
+    }
+
+    fn usb_purge_tx_buffer(&mut self) -> Result<()> {
+        self.control(
+            "usb_purge_tx_buffer",
+            protocol::purge_tx_buffer(self.interface),
+        )
+    }
+
+    fn set_event_char(&mut self, value: Option<u8>) -> Result<()> {
+        self.control(
+            "set_event_char",
+            protocol::set_event_char(self.interface, value),
+        )
+    }
+
+    fn set_error_char(&mut self, value: Option<u8>) -> Result<()> {
+        self.control(
+            "set_error_char",
+            protocol::set_error_char(self.interface, value),
+        )
+    }
+
+    fn set_latency_timer(&mut self, value: u8) -> Result<()> {
+        self.control(
+            "set_latency_timer",
+            protocol::set_latency_timer(self.interface, value)?,
+        )
+    }
+
+    fn latency_timer(&mut self) -> Result<u8> {
+        let mut buf = [0u8; 1];
+        self.control_in(
+            "latency_timer",
+            protocol::get_latency_timer(self.interface),
+            &mut buf,
+        )?;
+        Ok(buf[0])
+    }
+
+    fn set_write_chunksize(&mut self, value: u32) -> Result<()> {
+        if value == 0 {
+            return Err(Error::InvalidInput("chunk size must not be zero"));
+        }
+        self.write_chunksize = value;
+        Ok(())
+    }
+
+    fn write_chunksize(&mut self) -> Result<u32> {
+        Ok(self.write_chunksize)
+    }
+
+    fn set_read_chunksize(&mut self, value: u32) -> Result<()> {
+        if value == 0 {
+            return Err(Error::InvalidInput("chunk size must not be zero"));
+        }
+        self.read_chunksize = value;
+        Ok(())
+    }
+
+    fn read_chunksize(&mut self) -> Result<u32> {
+        Ok(self.read_chunksize)
+    }
+
+    fn set_flow_control(&mut self, flowctrl: FlowControl) -> Result<()> {
+        self.control(
+            "set_flow_control",
+            protocol::set_flow_control(self.interface, flowctrl),
+        )
+    }
+
+    fn set_bitmode(&mut self, output_mask: u8, mode: BitMode) -> Result<()> {
+        self.control(
+            "set_bitmode",
+            protocol::set_bitmode(self.interface, output_mask, mode),
+        )?;
+        self.bitbang = mode != BitMode::Reset;
+        Ok(())
+    }
+
+    fn modem_status(&mut self) -> Result<ModemStatus> {
+        let mut buf = [0u8; 2];
+        self.control_in(
+            "modem_status",
+            protocol::poll_modem_status(self.interface),
+            &mut buf,
+        )?;
+        Ok(ModemStatus(u16::from_le_bytes(buf)))
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        if self.received.is_empty() {
+            let mut data = vec![0u8; self.read_chunksize as usize];
+            self.transfers.read += 1;
+            let len = self
+                .handle
+                .read_bulk(self.in_endpoint, &mut data, TIMEOUT)
+                .map_err(usb_to_io)?;
+            let mut payload = Vec::with_capacity(len);
+            protocol::strip_status(&data[..len], self.packet_size, &mut payload);
+            self.received.extend(payload);
+        }
+
+        let len = buf.len().min(self.received.len());
+        for (byte, received) in buf.iter_mut().zip(self.received.drain(..len)) {
+            *byte = received;
+        }
+        Ok(len)
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut written = 0;
+        for chunk in buf.chunks(self.write_chunksize as usize) {
+            self.transfers.write += 1;
+            let len = self
+                .handle
+                .write_bulk(self.out_endpoint, chunk, TIMEOUT)
+                .map_err(usb_to_io)?;
+            written += len;
+            if len < chunk.len() {
+                break;
+            }
+        }
+        Ok(written)
+    }
+
+    fn readbuffer_remaining(&self) -> usize {
+        self.received.len()
+    }
+
+    fn transfers(&self) -> Option<Transfers> {
+        Some(self.transfers)
+    }
+}
+
+impl<T: UsbContext> Drop for Rusb<T> {
+    fn drop(&mut self) {
+        let _ = self.handle.release_interface(self.usb_interface);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bcd_device_round_trips() {
+        for &raw in &[0x0200, 0x0400, 0x0700, 0x0900, 0x1000, 0x2315] {
+            assert_eq!(bcd_device(rusb::Version::from_bcd(raw)), raw);
+        }
+        let ft230x = bcd_device(rusb::Version::from_bcd(0x1000));
+        assert_eq!(protocol::chip_type(ft230x, true), Some(ChipType::Ft230X));
+    }
+}
@@ -0,0 +1,479 @@
+//! Encoding of the FTDI vendor requests
+//!
+//! The control transfers are described as plain [`ControlRequest`] values, so
+//! that their encoding can be checked without any USB stack. The baud rate
+//! divisor calculation follows the one of `libftdi`.
+//!
+//! # Example
+//!
+//! ```
+//! use ftdi::backend::protocol;
+//! use ftdi::{ChipType, Interface};
+//!
+//! let (request, actual) = protocol::set_baud_rate(Interface::A, Some(ChipType::Ft232H), 115_200, false).unwrap();
+//! assert_eq!(actual, 115_246);
+//! assert_eq!(request.setup_packet(), [0x40, 0x03, 0x68, 0xc0, 0x01, 0x02, 0x00, 0x00]);
+//! ```
+
+use crate::{BitMode, Bits, ChipType, Error, FlowControl, Interface, Parity, Result, StopBits};
+
+/// `bmRequestType` of vendor requests to the device
+pub const REQUEST_TYPE_OUT: u8 = 0x40;
+/// `bmRequestType` of vendor requests from the device
+pub const REQUEST_TYPE_IN: u8 = 0xc0;
+
+pub const SIO_RESET: u8 = 0x00;
+pub const SIO_SET_MODEM_CTRL: u8 = 0x01;
+pub const SIO_SET_FLOW_CTRL: u8 = 0x02;
+pub const SIO_SET_BAUDRATE: u8 = 0x03;
+pub const SIO_SET_DATA: u8 = 0x04;
+pub const SIO_POLL_MODEM_STATUS: u8 = 0x05;
+pub const SIO_SET_EVENT_CHAR: u8 = 0x06;
+pub const SIO_SET_ERROR_CHAR: u8 = 0x07;
+pub const SIO_SET_LATENCY_TIMER: u8 = 0x09;
+pub const SIO_GET_LATENCY_TIMER: u8 = 0x0a;
+pub const SIO_SET_BITMODE: u8 = 0x0b;
+pub const SIO_READ_PINS: u8 = 0x0c;
+
+/// Number of modem status bytes at the start of every bulk IN packet
+pub const STATUS_LEN: usize = 2;
+
+/// A vendor control transfer
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct ControlRequest {
+    pub request_type: u8,
+    pub request: u8,
+    pub value: u16,
+    pub index: u16,
+    /// Number of bytes to read, zero for requests to the device
+    pub length: u16,
+}
+
+impl ControlRequest {
+    fn out(request: u8, value: u16, index: u16) -> Self {
+        Self {
+            request_type: REQUEST_TYPE_OUT,
+            request,
+            value,
+            index,
+            length: 0,
+        }
+    }
+
+    fn input(request: u8, index: u16, length: u16) -> Self {
+        Self {
+            request_type: REQUEST_TYPE_IN,
+            request,
+            value: 0,
+            index,
+            length,
+        }
+    }
+
+    /// The USB setup packet of the request
+    pub fn setup_packet(&self) -> [u8; 8] {
+        let value = self.value.to_le_bytes();
+        let index = self.index.to_le_bytes();
+        let length = self.length.to_le_bytes();
+        [
+            self.request_type,
+            self.request,
+            value[0],
+            value[1],
+            index[0],
+            index[1],
+            length[0],
+            length[1],
+        ]
+    }
+}
+
+/// The USB interface number and the bulk IN and OUT endpoints of an interface
+pub fn endpoints(interface: Interface) -> (u8, u8, u8) {
+    match interface {
+        Interface::A | Interface::Any => (0, 0x81, 0x02),
+        Interface::B => (1, 0x83, 0x04),
+        Interface::C => (2, 0x85, 0x06),
+        Interface::D => (3, 0x87, 0x08),
+    }
+}
+
+/// The interface number used in the `wIndex` of the requests
+fn port(interface: Interface) -> u16 {
+    u16::from(endpoints(interface).0) + 1
+}
+
+/// Detects the chip type from `bcdDevice` of the device descriptor
+pub fn chip_type(bcd_device: u16, has_serial_number: bool) -> Option<ChipType> {
+    match bcd_device {
+        0x0200 if !has_serial_number => Some(ChipType::Bm),
+        0x0200 => Some(ChipType::Am),
+        0x0400 => Some(ChipType::Bm),
+        0x0500 => Some(ChipType::Ft2232C),
+        0x0600 => Some(ChipType::R),
+        0x0700 => Some(ChipType::Ft2232H),
+        0x0800 => Some(ChipType::Ft4232H),
+        0x0900 => Some(ChipType::Ft232H),
+        0x1000 => Some(ChipType::Ft230X),
+        _ => None,
+    }
+}
+
+pub fn reset(interface: Interface) -> ControlRequest {
+    ControlRequest::out(SIO_RESET, 0, port(interface))
+}
+
+pub fn purge_rx_buffer(interface: Interface) -> ControlRequest {
+    ControlRequest::out(SIO_RESET, 1, port(interface))
+}
+
+pub fn purge_tx_buffer(interface: Interface) -> ControlRequest {
+    ControlRequest::out(SIO_RESET, 2, port(interface))
+}
+
+const FRAC_CODE: [u32; 8] = [0, 3, 2, 4, 1, 5, 6, 7];
+
+/// Divisor of the original AM chips, which support fewer fractions
+fn am_divisor(baud_rate: u32) -> (u32, u32) {
+    const ADJUST_UP: [u32; 8] = [0, 0, 0, 1, 0, 3, 2, 1];
+    const ADJUST_DOWN: [u32; 8] = [0, 0, 0, 1, 0, 1, 2, 3];
+
+    let mut divisor = 24_000_000 / baud_rate;
+    divisor -= ADJUST_DOWN[(divisor & 7) as usize];
+
+    let candidate = |i: u32| {
+        let mut candidate = divisor + i;
+        if candidate <= 8 {
+            candidate = 8;
+        } else if divisor < 16 {
+            candidate = 16;
+        } else {
+            candidate += ADJUST_UP[(candidate & 7) as usize];
+            candidate = candidate.min(0x1fff8);
+        }
+        let estimate = (24_000_000 + candidate / 2) / candidate;
+        (candidate, estimate, estimate.abs_diff(baud_rate))
+    };
+
+    let first = candidate(0);
+    let second = candidate(1);
+    let (divisor, estimate, _) = if first.2 != 0 && second.2 < first.2 {
+        second
+    } else {
+        first
+    };
+    let encoded = match (divisor >> 3) | (FRAC_CODE[(divisor & 7) as usize] << 14) {
+        1 => 0,
+        0x4001 => 1,
+        encoded => encoded,
+    };
+    (encoded, estimate)
+}
+
+/// Divisor of the chips with fractional divisors on the `clock / clock_div` base
+fn clock_divisor(baud_rate: u32, clock: u32, clock_div: u32) -> (u32, u32) {
+    if baud_rate >= clock / clock_div {
+        (0, clock / clock_div)
+    } else if baud_rate >= clock / (clock_div + clock_div / 2) {
+        (1, clock / (clock_div + clock_div / 2))
+    } else if baud_rate >= clock / (2 * clock_div) {
+        (2, clock / (2 * clock_div))
+    } else {
+        // 3 fractional bits and one bit for rounding
+        let divisor = u64::from(clock) * 16 / u64::from(clock_div) / u64::from(baud_rate);
+        let mut divisor = (divisor / 2 + (divisor & 1)) as u32;
+        if divisor > 0x20000 {
+            divisor = 0x1ffff;
+        }
+        let estimate = u64::from(clock) * 16 / u64::from(clock_div) / u64::from(divisor);
+        let estimate = (estimate / 2 + (estimate & 1)) as u32;
+        let encoded = (divisor >> 3) | (FRAC_CODE[(divisor & 7) as usize] << 14);
+        (encoded, estimate)
+    }
+}
+
+/// Encodes the baud rate request, returning it along with the actual baud rate
+///
+/// In bitbang modes the rate is multiplied by 4, like `libftdi` does. Rates
+/// that cannot be achieved within 5% are rejected.
+pub fn set_baud_rate(
+    interface: Interface,
+    chip_type: Option<ChipType>,
+    baud_rate: u32,
+    bitbang: bool,
+) -> Result<(ControlRequest, u32)> {
+    let baud_rate = if bitbang {
+        baud_rate.saturating_mul(4)
+    } else {
+        baud_rate
+    };
+    if baud_rate == 0 {
+        return Err(Error::InvalidInput("unsupported baudrate"));
+    }
+
+    let h_series = chip_type.is_some_and(ChipType::is_h_series);
+    let (encoded, actual) = match chip_type {
+        _ if h_series && baud_rate > 120_000_000 / 10 / 0x3fff => {
+            let (encoded, actual) = clock_divisor(baud_rate, 120_000_000, 10);
+            (encoded | 0x20000, actual)
+        }
+        Some(ChipType::Am) => am_divisor(baud_rate),
+        _ => clock_divisor(baud_rate, 48_000_000, 16),
+    };
+
+    let value = encoded as u16;
+    let index = if h_series {
+        ((encoded >> 8) as u16 & 0xff00) | port(interface)
+    } else {
+        (encoded >> 16) as u16
+    };
+
+    let (low, high) = (
+        u64::from(actual.min(baud_rate)),
+        u64::from(actual.max(baud_rate)),
+    );
+    if low * 21 < high * 20 {
+        return Err(Error::InvalidInput("unsupported baudrate"));
+    }
+
+    Ok((ControlRequest::out(SIO_SET_BAUDRATE, value, index), actual))
+}
+
+pub fn set_line_property(
+    interface: Interface,
+    bits: Bits,
+    stop_bits: StopBits,
+    parity: Parity,
+) -> ControlRequest {
+    let bits = match bits {
+        Bits::Seven => 7,
+        Bits::Eight => 8,
+    };
+    let parity = match parity {
+        Parity::None => 0,
+        Parity::Odd => 1,
+        Parity::Even => 2,
+        Parity::Mark => 3,
+        Parity::Space => 4,
+    };
+    let stop_bits = match stop_bits {
+        StopBits::One => 0,
+        StopBits::OneHalf => 1,
+        StopBits::Two => 2,
+    };
+    ControlRequest::out(
+        SIO_SET_DATA,
+        bits | (parity << 8) | (stop_bits << 11),
+        port(interface),
+    )
+}
+
+/// Encodes the flow control request, using DC1/DC3 for XON/XOFF
+pub fn set_flow_control(interface: Interface, flowctrl: FlowControl) -> ControlRequest {
+    let (value, mode) = match flowctrl {
+        FlowControl::Disabled => (0, 0x0000),
+        FlowControl::RtsCts => (0, 0x0100),
+        FlowControl::DtrDsr => (0, 0x0200),
+        FlowControl::XonXoff => (0x1311, 0x0400),
+    };
+    ControlRequest::out(SIO_SET_FLOW_CTRL, value, mode | port(interface))
+}
+
+fn special_char(value: Option<u8>) -> u16 {
+    match value {
+        Some(value) => u16::from(value) | 0x0100,
+        None => 0,
+    }
+}
+
+pub fn set_event_char(interface: Interface, value: Option<u8>) -> ControlRequest {
+    ControlRequest::out(SIO_SET_EVENT_CHAR, special_char(value), port(interface))
+}
+
+pub fn set_error_char(interface: Interface, value: Option<u8>) -> ControlRequest {
+    ControlRequest::out(SIO_SET_ERROR_CHAR, special_char(value), port(interface))
+}
+
+pub fn set_latency_timer(interface: Interface, value: u8) -> Result<ControlRequest> {
+    if value == 0 {
+        return Err(Error::InvalidInput("latency value out of range"));
+    }
+    Ok(ControlRequest::out(
+        SIO_SET_LATENCY_TIMER,
+        value.into(),
+        port(interface),
+    ))
+}
+
+pub fn get_latency_timer(interface: Interface) -> ControlRequest {
+    ControlRequest::input(SIO_GET_LATENCY_TIMER, port(interface), 1)
+}
+
+pub fn set_bitmode(interface: Interface, output_mask: u8, mode: BitMode) -> ControlRequest {
+    let mode: u16 = match mode {
+        BitMode::Reset => 0x00,
+        BitMode::Bitbang => 0x01,
+        BitMode::Mpsse => 0x02,
+        BitMode::SyncBB => 0x04,
+        BitMode::Mcu => 0x08,
+        BitMode::Opto => 0x10,
+        BitMode::CBus => 0x20,
+        BitMode::Syncff => 0x40,
+        BitMode::Ft1284 => 0x80,
+    };
+    ControlRequest::out(
+        SIO_SET_BITMODE,
+        u16::from(output_mask) | (mode << 8),
+        port(interface),
+    )
+}
+
+pub fn poll_modem_status(interface: Interface) -> ControlRequest {
+    ControlRequest::input(SIO_POLL_MODEM_STATUS, port(interface), 2)
+}
+
+/// Appends the payload of bulk IN data to `out`, dropping the status bytes
+/// at the start of every packet of `packet_size` bytes
+pub fn strip_status(data: &[u8], packet_size: usize, out: &mut Vec<u8>) {
+    for packet in data.chunks(packet_size) {
+        if packet.len() > STATUS_LEN {
+            out.extend_from_slice(&packet[STATUS_LEN..]);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn baud_rate(chip_type: ChipType, interface: Interface, rate: u32) -> (u16, u16, u32) {
+        let (request, actual) = set_baud_rate(interface, Some(chip_type), rate, false).unwrap();
+        assert_eq!(request.request, SIO_SET_BAUDRATE);
+        (request.value, request.index, actual)
+    }
+
+    #[test]
+    fn baud_rate_divisors() {
+        assert_eq!(
+            baud_rate(ChipType::R, Interface::A, 9600),
+            (0x4138, 0, 9600)
+        );
+        assert_eq!(
+            baud_rate(ChipType::R, Interface::A, 115_200),
+            (0x001a, 0, 115_385)
+        );
+        assert_eq!(
+            baud_rate(ChipType::R, Interface::A, 3_000_000),
+            (0, 0, 3_000_000)
+        );
+        assert_eq!(
+            baud_rate(ChipType::R, Interface::A, 2_000_000),
+            (1, 0, 2_000_000)
+        );
+        assert_eq!(
+            baud_rate(ChipType::Ft2232H, Interface::B, 3_000_000),
+            (0x0004, 0x0202, 3_000_000)
+        );
+        assert_eq!(
+            baud_rate(ChipType::Ft2232H, Interface::A, 12_000_000),
+            (0, 0x0201, 12_000_000)
+        );
+    }
+
+    #[test]
+    fn bitbang_baud_rate_is_multiplied() {
+        let (request, actual) = set_baud_rate(Interface::A, Some(ChipType::R), 9600, true).unwrap();
+        assert_eq!(actual, 38_400);
+        let (expected, _) = set_baud_rate(Interface::A, Some(ChipType::R), 38_400, false).unwrap();
+        assert_eq!(request, expected);
+    }
+
+    #[test]
+    fn unachievable_baud_rates_are_rejected() {
+        for &rate in &[0, 1, 3_500_000] {
+            assert!(
+                set_baud_rate(Interface::A, Some(ChipType::R), rate, false).is_err(),
+                "{} baud",
+                rate
+            );
+        }
+    }
+
+    #[test]
+    fn control_requests() {
+        assert_eq!(
+            reset(Interface::A).setup_packet(),
+            [0x40, 0x00, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            purge_rx_buffer(Interface::B).setup_packet(),
+            [0x40, 0x00, 0x01, 0x00, 0x02, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            purge_tx_buffer(Interface::D).setup_packet(),
+            [0x40, 0x00, 0x02, 0x00, 0x04, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            set_line_property(Interface::B, Bits::Seven, StopBits::Two, Parity::Even)
+                .setup_packet(),
+            [0x40, 0x04, 0x07, 0x12, 0x02, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            set_flow_control(Interface::A, FlowControl::XonXoff).setup_packet(),
+            [0x40, 0x02, 0x11, 0x13, 0x01, 0x04, 0x00, 0x00]
+        );
+        assert_eq!(
+            set_flow_control(Interface::C, FlowControl::RtsCts).setup_packet(),
+            [0x40, 0x02, 0x00, 0x00, 0x03, 0x01, 0x00, 0x00]
+        );
+        assert_eq!(
+            set_event_char(Interface::A, Some(0x7e)).setup_packet(),
+            [0x40, 0x06, 0x7e, 0x01, 0x01, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            set_error_char(Interface::A, None).setup_packet(),
+            [0x40, 0x07, 0x00, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            set_latency_timer(Interface::A, 16).unwrap().setup_packet(),
+            [0x40, 0x09, 0x10, 0x00, 0x01, 0x00, 0x00, 0x00]
+        );
+        assert!(set_latency_timer(Interface::A, 0).is_err());
+        assert_eq!(
+            get_latency_timer(Interface::A).setup_packet(),
+            [0xc0, 0x0a, 0x00, 0x00, 0x01, 0x00, 0x01, 0x00]
+        );
+        assert_eq!(
+            set_bitmode(Interface::A, 0x0b, BitMode::Mpsse).setup_packet(),
+            [0x40, 0x0b, 0x0b, 0x02, 0x01, 0x00, 0x00, 0x00]
+        );
+        assert_eq!(
+            poll_modem_status(Interface::D).setup_packet(),
+            [0xc0, 0x05, 0x00, 0x00, 0x04, 0x00, 0x02, 0x00]
+        );
+    }
+
+    #[test]
+    fn status_bytes_are_stripped_from_every_packet() {
+        let mut data = vec![0x31, 0x60];
+        data.extend(1..=62);
+        data.extend([0x31, 0x60, 63, 64]);
+        let mut out = Vec::new();
+        strip_status(&data, 64, &mut out);
+        assert_eq!(out, (1..=64).collect::<Vec<u8>>());
+
+        out.clear();
+        strip_status(&[0x31, 0x60], 64, &mut out);
+        assert!(out.is_empty());
+    }
+
+    #[test]
+    fn chip_types() {
+        assert_eq!(chip_type(0x0200, false), Some(ChipType::Bm));
+        assert_eq!(chip_type(0x0200, true), Some(ChipType::Am));
+        assert_eq!(chip_type(0x0700, true), Some(ChipType::Ft2232H));
+        assert_eq!(chip_type(0x0900, true), Some(ChipType::Ft232H));
+        assert_eq!(chip_type(0x1000, true), Some(ChipType::Ft230X));
+        assert_eq!(chip_type(0x0a00, true), None);
+    }
+}
@@ -0,0 +1,684 @@
+//! Recording device sessions and replaying them later
+//!
+//! A [`Recorder`] wraps any backend and logs every call made through it,
+//! together with its result and the time since the recording started. The log
+//! is a line-oriented text file, one call per line:
+//!
+//! ```text
+//! # ftdi session v1
+//! chip Ft232H
+//! 0.000000 set_bitmode 0b Mpsse -> ok
+//! 0.000412 write 8a97 -> ok
+//! 0.001023 read -> data fab0 remaining 2
+//! 0.001107 read -> data 1234
+//! 0.001510 latency_timer -> error Disconnected
+//! ```
+//!
+//! A read leaving data in the receive buffer of the backend records how much is
+//! left, as `readbuffer_remaining` reports it until the following reads.
+//!
+//! [`replay`] turns such a log back into a [`Mock`] expecting the same calls,
+//! in the same order, and serving the recorded replies. Timestamps are only
+//! informative and are ignored on replay, so the session is reproduced
+//! deterministically.
+//!
+//! # Example
+//!
+//! ```
+//! use ftdi::backend::mock::Mock;
+//! use ftdi::backend::record::{replay, Recorder};
+//! use ftdi::Device;
+//! use std::io::{Read, Write};
+//!
+//! # fn main() -> std::io::Result<()> {
+//! # let mut mock = Mock::new();
+//! # mock.expect_write(&[0x81]).expect_read(&[0x08]);
+//! # let backend = mock;
+//! let recorder = Recorder::new(backend, Vec::new())?;
+//! let mut device = Device::with_backend(recorder);
+//! device.write_all(&[0x81])?;
+//! let mut pins = [0];
+//! device.read_exact(&mut pins)?;
+//! let log = device.into_backend().into_inner().1;
+//!
+//! let mut device = Device::with_backend(replay(&log[..])?);
+//! device.write_all(&[0x81])?;
+//! device.read_exact(&mut pins)?;
+//! assert_eq!(pins, [0x08]);
+//! device.backend().assert_done();
+//! # Ok(())
+//! # }
+//! ```
+
+use std::fmt::{Debug, Write as _};
+use std::fs::File;
+use std::io::{self, BufRead, BufReader, BufWriter, Write};
+use std::path::Path;
+use std::time::Instant;
+
+use super::mock::{Call, Mock, Reply};
+use super::Backend;
+use crate::error::ErrorContext;
+use crate::stats::Transfers;
+use crate::{
+    BitMode, Bits, ChipType, DeviceId, Error, FlowControl, ModemStatus, Parity, Result, StopBits,
+};
+
+const HEADER: &str = "# ftdi session v1";
+
+/// A backend wrapper logging every call made through it
+///
+/// Failing to write the log is reported as a failure of the call being logged.
+pub struct Recorder<B: Backend, W: Write> {
+    backend: B,
+    log: W,
+    started: Instant,
+}
+
+impl<B: Backend> Recorder<B, BufWriter<File>> {
+    /// Records the session to a newly created file at `path`
+    pub fn create<P: AsRef<Path>>(backend: B, path: P) -> io::Result<Self> {
+        Self::new(backend, BufWriter::new(File::create(path)?))
+    }
+}
+
+impl<B: Backend, W: Write> Recorder<B, W> {
+    pub fn new(backend: B, mut log: W) -> io::Result<Self> {
+        writeln!(log, "{}", HEADER)?;
+        match backend.chip_type() {
+            Some(chip_type) => writeln!(log, "chip {:?}", chip_type)?,
+            None => writeln!(log, "chip none")?,
+        }
+        log.flush()?;
+        Ok(Recorder {
+            backend,
+            log,
+            started: Instant::now(),
+        })
+    }
+
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    /// Returns the wrapped backend and the log
+    pub fn into_inner(self) -> (B, W) {
+        (self.backend, self.log)
+    }
+
+    fn log(&mut self, call: &Call, reply: &str) -> io::Result<()> {
+        let elapsed = self.started.elapsed();
+        writeln!(
+            self.log,
+            "{}.{:06} {} -> {}",
+            elapsed.as_secs(),
+            elapsed.subsec_micros(),
+            format_call(call),
+            reply
+        )?;
+        self.log.flush()
+    }
+
+    fn record<T>(
+        &mut self,
+        call: Call,
+        result: Result<T>,
+        format: impl FnOnce(&T) -> String,
+    ) -> Result<T> {
+        let reply = match &result {
+            Ok(value) => format(value),
+            Err(e) => format!("error {}", format_error(e)),
+        };
+        self.log(&call, &reply)?;
+        result
+    }
+
+    fn record_done(&mut self, call: Call, result: Result<()>) -> Result<()> {
+        self.record(call, result, |_| "ok".to_string())
+    }
+
+    fn record_io<T>(
+        &mut self,
+        call: Call,
+        result: io::Result<T>,
+        format: impl FnOnce(&T) -> String,
+    ) -> io::Result<T> {
+        let reply = match &result {
+            Ok(value) => format(value),
+            Err(e) => format!("error {}", format_io_error(e)),
+        };
+        self.log(&call, &reply)?;
+        result
+    }
+}
+
+impl<B: Backend, W: Write> Backend for Recorder<B, W> {
+    fn chip_type(&self) -> Option<ChipType> {
+        self.backend.chip_type()
+    }
+
+    fn device_id(&self) -> Option<&DeviceId> {
+        self.backend.device_id()
+    }
+
+    fn transfers(&self) -> Option<Transfers> {
+        self.backend.transfers()
+    }
+
+    fn set_baud_rate(&mut self, rate: u32) -> Result<()> {
+        let result = self.backend.set_baud_rate(rate);
+        self.record_done(Call::SetBaudRate(rate), result)
+    }
+
+    fn set_line_property(&mut self, bits: Bits, stop_bits: StopBits, parity: Parity) -> Result<()> {
+        let result = self.backend.set_line_property(bits, stop_bits, parity);
+        self.record_done(Call::SetLineProperty(bits, stop_bits, parity), result)
+    }
+
+    fn usb_reset(&mut self) -> Result<()> {
+        let result = self.backend.usb_reset();
+        self.record_done(Call::UsbReset, result)
+    }
+
+    fn usb_purge_buffers(&mut self) -> Result<()> {
+        let result = self.backend.usb_purge_buffers();
+        self.record_done(Call::UsbPurgeBuffers, result)
+    }
+
+    fn usb_purge_tx_buffer(&mut self) -> Result<()> {
+        let result = self.backend.usb_purge_tx_buffer();
+        self.record_done(Call::UsbPurgeTxBuffer, result)
+    }
+
+    fn set_event_char(&mut self, value: Option<u8>) -> Result<()> {
+        let result = self.backend.set_event_char(value);
+        self.record_done(Call::SetEventChar(value), result)
+    }
+
+    fn set_error_char(&mut self, value: Option<u8>) -> Result<()> {
+        let result = self.backend.set_error_char(value);
+        self.record_done(Call::SetErrorChar(value), result)
+    }
+
+    fn set_latency_timer(&mut self, value: u8) -> Result<()> {
+        let result = self.backend.set_latency_timer(value);
+        self.record_done(Call::SetLatencyTimer(value), result)
+    }
+
+    fn latency_timer(&mut self) -> Result<u8> {
+        let result = self.backend.latency_timer();
+        self.record(Call::LatencyTimer, result, u8::to_string)
+    }
+
+    fn set_write_chunksize(&mut self, value: u32) -> Result<()> {
+        let result = self.backend.set_write_chunksize(value);
+        self.record_done(Call::SetWriteChunksize(value), result)
+    }
+
+    fn write_chunksize(&mut self) -> Result<u32> {
+        let result = self.backend.write_chunksize();
+        self.record(Call::WriteChunksize, result, u32::to_string)
+    }
+
+    fn set_read_chunksize(&mut self, value: u32) -> Result<()> {
+        let result = self.backend.set_read_chunksize(value);
+        self.record_done(Call::SetReadChunksize(value), result)
+    }
+
+    fn read_chunksize(&mut self) -> Result<u32> {
+        let result = self.backend.read_chunksize();
+        self.record(Call::ReadChunksize, result, u32::to_string)
+    }
+
+    fn set_flow_control(&mut self, flowctrl: FlowControl) -> Result<()> {
+        let result = self.backend.set_flow_control(flowctrl);
+        self.record_done(Call::SetFlowControl(flowctrl), result)
+    }
+
+    fn set_bitmode(&mut self, output_mask: u8, mode: BitMode) -> Result<()> {
+        let result = self.backend.set_bitmode(output_mask, mode);
+        self.record_done(Call::SetBitmode(output_mask, mode), result)
+    }
+
+    fn modem_status(&mut self) -> Result<ModemStatus> {
+        let result = self.backend.modem_status();
+        self.record(Call::ModemStatus, result, |status| {
+            format!("{:04x}", status.0)
+        })
+    }
+
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let result = self.backend.read(buf);
+        let remaining = self.backend.readbuffer_remaining();
+        self.record_io(Call::Read, result, |&len| match remaining {
+            0 => format!("data {}", to_hex(&buf[..len])),
+            remaining => format!("data {} remaining {}", to_hex(&buf[..len]), remaining),
+        })
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let result = self.backend.write(buf);
+        // a short write is logged as a write of the accepted part only
+        let written = match &result {
+            Ok(len) => &buf[..*len],
+            Err(_) => buf,
+        };
+        let call = Call::Write(written.to_vec());
+        self.record_io(call, result, |_| "ok".to_string())
+    }
+
+    fn readbuffer_remaining(&self) -> usize {
+        self.backend.readbuffer_remaining()
+    }
+}
+
+/// Builds a mock backend replaying a session recorded by a [`Recorder`]
+///
+/// Recorded errors are reproduced as the same `Error` variant where possible
+/// and as an `io::Error` of the same kind and message otherwise.
+pub fn replay<R: BufRead>(log: R) -> io::Result<Mock> {
+    let mut lines = log.lines().enumerate();
+    let header = lines.next().map(|(_, line)| line).transpose()?;
+    if header.as_deref() != Some(HEADER) {
+        return Err(invalid(1, "not an ftdi session log"));
+    }
+
+    let mut mock = Mock::new();
+    for (index, line) in lines {
+        let line = line?;
+        let number = index + 1;
+        let line = line.trim();
+        if line.is_empty() || line.starts_with('#') {
+            continue;
+        }
+        if let Some(chip) = line.strip_prefix("chip ") {
+            if chip != "none" {
+                let chip_type = parse_named(chip, &CHIP_TYPES)
+                    .ok_or_else(|| invalid(number, "unknown chip type"))?;
+                mock = mock.with_chip_type(chip_type);
+            }
+            continue;
+        }
+        let (call, reply) = parse_entry(line).ok_or_else(|| invalid(number, line))?;
+        mock.expect(call, reply);
+    }
+    Ok(mock)
+}
+
+/// Builds a mock backend replaying the session recorded to the file at `path`
+pub fn replay_file<P: AsRef<Path>>(path: P) -> io::Result<Mock> {
+    replay(BufReader::new(File::open(path)?))
+}
+
+fn invalid(line: usize, message: &str) -> io::Error {
+    io::Error::new(
+        io::ErrorKind::InvalidData,
+        format!("session log line {}: {}", line, message),
+    )
+}
+
+const BITS: [Bits; 2] = [Bits::Seven, Bits::Eight];
+const STOP_BITS: [StopBits; 3] = [StopBits::One, StopBits::OneHalf, StopBits::Two];
+const PARITIES: [Parity; 5] = [
+    Parity::None,
+    Parity::Odd,
+    Parity::Even,
+    Parity::Mark,
+    Parity::Space,
+];
+const FLOW_CONTROLS: [FlowControl; 4] = [
+    FlowControl::Disabled,
+    FlowControl::RtsCts,
+    FlowControl::DtrDsr,
+    FlowControl::XonXoff,
+];
+const BIT_MODES: [BitMode; 9] = [
+    BitMode::Reset,
+    BitMode::Bitbang,
+    BitMode::Mpsse,
+    BitMode::SyncBB,
+    BitMode::Mcu,
+    BitMode::Opto,
+    BitMode::CBus,
+    BitMode::Syncff,
+    BitMode::Ft1284,
+];
+const CHIP_TYPES: [ChipType; 8] = [
+    ChipType::Am,
+    ChipType::Bm,
+    ChipType::Ft2232C,
+    ChipType::R,
+    ChipType::Ft2232H,
+    ChipType::Ft4232H,
+    ChipType::Ft232H,
+    ChipType::Ft230X,
+];
+const IO_ERROR_KINDS: [io::ErrorKind; 10] = [
+    io::ErrorKind::NotFound,
+    io::ErrorKind::PermissionDenied,
+    io::ErrorKind::NotConnected,
+    io::ErrorKind::BrokenPipe,
+    io::ErrorKind::WouldBlock,
+    io::ErrorKind::InvalidInput,
+    io::ErrorKind::InvalidData,
+    io::ErrorKind::TimedOut,
+    io::ErrorKind::Interrupted,
+    io::ErrorKind::UnexpectedEof,
+];
+
+fn parse_named<T: Debug + Copy>(name: &str, values: &[T]) -> Option<T> {
+    values
+        .iter()
+        .copied()
+        .find(|value| format!("{:?}", value) == name)
+}
+
+fn to_hex(data: &[u8]) -> String {
+    let mut hex = String::with_capacity(data.len() * 2);
+    for byte in data {
+        write!(hex, "{:02x}", byte).unwrap();
+    }
+    hex
+}
+
+fn from_hex(hex: &str) -> Option<Vec<u8>> {
+    hex.as_bytes()
+        .chunks(2)
+        .map(|pair| match pair {
+            [high, low] => Some((hex_digit(*high)? << 4) | hex_digit(*low)?),
+            _ => None,
+        })
+        .collect()
+}
+
+fn hex_digit(digit: u8) -> Option<u8> {
+    char::from(digit).to_digit(16).map(|value| value as u8)
+}
+
+fn format_char(value: Option<u8>) -> String {
+    value.map_or_else(|| "none".to_string(), |c| format!("{:02x}", c))
+}
+
+fn parse_char(value: &str) -> Option<Option<u8>> {
+    match value {
+        "none" => Some(None),
+        value => u8::from_str_radix(value, 16).ok().map(Some),
+    }
+}
+
+fn call_name(call: &Call) -> &'static str {
+    match call {
+        Call::SetBaudRate(_) => "set_baud_rate",
+        Call::SetLineProperty(..) => "set_line_property",
+        Call::UsbReset => "usb_reset",
+        Call::UsbPurgeBuffers => "usb_purge_buffers",
+        Call::UsbPurgeTxBuffer => "usb_purge_tx_buffer",
+        Call::SetEventChar(_) => "set_event_char",
+        Call::SetErrorChar(_) => "set_error_char",
+        Call::SetLatencyTimer(_) => "set_latency_timer",
+        Call::LatencyTimer => "latency_timer",
+        Call::SetWriteChunksize(_) => "set_write_chunksize",
+        Call::WriteChunksize => "write_chunksize",
+        Call::SetReadChunksize(_) => "set_read_chunksize",
+        Call::ReadChunksize => "read_chunksize",
+        Call::SetFlowControl(_) => "set_flow_control",
+        Call::SetBitmode(..) => "set_bitmode",
+        Call::ModemStatus => "modem_status",
+        Call::Read => "read",
+        Call::Write(_) => "write",
+    }
+}
+
+fn format_call(call: &Call) -> String {
+    let name = call_name(call);
+    match call {
+        Call::SetBaudRate(rate) => format!("{} {}", name, rate),
+        Call::SetLineProperty(bits, stop_bits, parity) => {
+            format!("{} {:?} {:?} {:?}", name, bits, stop_bits, parity)
+        }
+        Call::SetEventChar(value) | Call::SetErrorChar(value) => {
+            format!("{} {}", name, format_char(*value))
+        }
+        Call::SetLatencyTimer(value) => format!("{} {}", name, value),
+        Call::SetWriteChunksize(value) | Call::SetReadChunksize(value) => {
+            format!("{} {}", name, value)
+        }
+        Call::SetFlowControl(flowctrl) => format!("{} {:?}", name, flowctrl),
+        Call::SetBitmode(mask, mode) => format!("{} {:02x} {:?}", name, mask, mode),
+        Call::Write(data) => format!("{} {}", name, to_hex(data)),
+        _ => name.to_string(),
+    }
+}
+
+fn parse_call(call: &str) -> Option<Call> {
+    let mut words = call.split_whitespace();
+    let name = words.next()?;
+    let args: Vec<&str> = words.collect();
+    let call = match (name, &args[..]) {
+        ("set_baud_rate", [rate]) => Call::SetBaudRate(rate.parse().ok()?),
+        ("set_line_property", [bits, stop_bits, parity]) => Call::SetLineProperty(
+            parse_named(bits, &BITS)?,
+            parse_named(stop_bits, &STOP_BITS)?,
+            parse_named(parity, &PARITIES)?,
+        ),
+        ("usb_reset", []) => Call::UsbReset,
+        ("usb_purge_buffers", []) => Call::UsbPurgeBuffers,
+        ("usb_purge_tx_buffer", []) => Call::UsbPurgeTxBuffer,
+        ("set_event_char", [value]) => Call::SetEventChar(parse_char(value)?),
+        ("set_error_char", [value]) => Call::SetErrorChar(parse_char(value)?),
+        ("set_latency_timer", [value]) => Call::SetLatencyTimer(value.parse().ok()?),
+        ("latency_timer", []) => Call::LatencyTimer,
+        ("set_write_chunksize", [value]) => Call::SetWriteChunksize(value.parse().ok()?),
+        ("write_chunksize", []) => Call::WriteChunksize,
+        ("set_read_chunksize", [value]) => Call::SetReadChunksize(value.parse().ok()?),
+        ("read_chunksize", []) => Call::ReadChunksize,
+        ("set_flow_control", [flowctrl]) => {
+            Call::SetFlowControl(parse_named(flowctrl, &FLOW_CONTROLS)?)
+        }
+        ("set_bitmode", [mask, mode]) => Call::SetBitmode(
+            u8::from_str_radix(mask, 16).ok()?,
+            parse_named(mode, &BIT_MODES)?,
+        ),
+        ("modem_status", []) => Call::ModemStatus,
+        ("read", []) => Call::Read,
+        ("write", [data]) => Call::Write(from_hex(data)?),
+        ("write", []) => Call::Write(Vec::new()),
+        _ => return None,
+    };
+    Some(call)
+}
+
+fn format_error(error: &Error) -> String {
+    match error {
+        Error::MpsseBadCommand(opcode) => format!("MpsseBadCommand {:02x}", opcode),
+        Error::RequestFailed { .. } => "RequestFailed".to_string(),
+        Error::InvalidInput(message) => format!("InvalidInput {}", message),
+        Error::Unknown { context, .. } => {
+            let code = context
+                .code()
+                .map_or("none".to_string(), |code| code.to_string());
+            match context.message() {
+                Some(message) => format!("Unknown {} {}", code, message),
+                None => format!("Unknown {}", code),
+            }
+        }
+        Error::__NonExhaustive => format!("io Other {}", error),
+        Error::Io(e) => format_io_error(e),
+        unit => format!("{:?}", unit),
+    }
+}
+
+fn format_io_error(error: &io::Error) -> String {
+    let kind = error.kind();
+    let kind = if IO_ERROR_KINDS.contains(&kind) {
+        format!("{:?}", kind)
+    } else {
+        "Other".to_string()
+    };
+    format!("io {} {}", kind, error)
+}
+
+/// Parses a recorded error, attributing context-carrying errors to `call`
+fn parse_error(call: &Call, error: &str) -> Option<Error> {
+    let mut words = error.splitn(3, ' ');
+    let error = match (words.next()?, words.next(), words.next()) {
+        ("EnumerationFailed", None, None) => Error::EnumerationFailed,
+        ("DeviceNotFound", None, None) => Error::DeviceNotFound,
+        ("AllocationFailed", None, None) => Error::AllocationFailed,
+        ("AccessFailed", None, None) => Error::AccessFailed,
+        ("ClaimFailed", None, None) => Error::ClaimFailed,
+        ("Disconnected", None, None) => Error::Disconnected,
+        ("NoSuchInterface", None, None) => Error::NoSuchInterface,
+        ("RequestFailed", None, None) => Error::request_failed(ErrorContext::new(call_name(call))),
+        ("InvalidInput", Some(first), rest) => {
+            let message = match rest {
+                Some(rest) => format!("{} {}", first, rest),
+                None => first.to_string(),
+            };
+            Error::InvalidInput(leak(message))
+        }
+        ("Unknown", Some(code), message) => {
+            let mut context = ErrorContext::new(call_name(call));
+            if code != "none" {
+                context = context.with_code(code.parse().ok()?);
+            }
+            if let Some(message) = message {
+                context = context.with_message(leak(message.to_string()));
+            }
+            Error::unknown(context)
+        }
+        ("MpsseSyncFailed", None, None) => Error::MpsseSyncFailed,
+        ("MpsseBadCommand", Some(opcode), None) => {
+            Error::MpsseBadCommand(u8::from_str_radix(opcode, 16).ok()?)
+        }
+        ("io", Some(kind), message) => {
+            let kind = parse_named(kind, &IO_ERROR_KINDS).unwrap_or(io::ErrorKind::Other);
+            Error::Io(io::Error::new(kind, message.unwrap_or_default()))
+        }
+        _ => return None,
+    };
+    Some(error)
+}
+
+/// Gives a replayed error message the lifetime of the ones of live sessions
+///
+/// Error messages are rare in session logs, so the leaked memory stays small.
+fn leak(message: String) -> &'static str {
+    Box::leak(message.into_boxed_str())
+}
+
+fn parse_reply(call: &Call, reply: &str) -> Option<Reply> {
+    if let Some(error) = reply.strip_prefix("error ") {
+        return parse_error(call, error).map(Reply::Error);
+    }
+    let reply = match call {
+        Call::LatencyTimer => Reply::LatencyTimer(reply.parse().ok()?),
+        Call::WriteChunksize | Call::ReadChunksize => Reply::Chunksize(reply.parse().ok()?),
+        Call::ModemStatus => Reply::ModemStatus(ModemStatus(u16::from_str_radix(reply, 16).ok()?)),
+        Call::Read => {
+            let (data, remaining) = match reply.split_once(" remaining ") {
+                Some((data, remaining)) => (data, remaining.parse().ok()?),
+                None => (reply, 0),
+            };
+            let data = match data {
+                "data" => Vec::new(),
+                data => from_hex(data.strip_prefix("data ")?)?,
+            };
+            match remaining {
+                0 => Reply::Data(data),
+                remaining => Reply::Buffered(data, remaining),
+            }
+        }
+        _ if reply == "ok" => Reply::Done,
+        _ => return None,
+    };
+    Some(reply)
+}
+
+fn parse_entry(line: &str) -> Option<(Call, Reply)> {
+    // the leading timestamp is informative only
+    let (_, entry) = line.split_once(' ')?;
+    let (call, reply) = entry.split_once(" -> ")?;
+    let call = parse_call(call)?;
+    let reply = parse_reply(&call, reply)?;
+    Some((call, reply))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::Device;
+
+    /// Records a session on top of `mock` and replays the log
+    fn record_and_replay(mock: Mock, session: fn(&mut dyn Backend)) -> Mock {
+        let mut recorder = Recorder::new(mock, Vec::new()).unwrap();
+        session(&mut recorder);
+        let (mock, log) = recorder.into_inner();
+        mock.assert_done();
+        replay(&log[..]).unwrap()
+    }
+
+    fn read_packet<B: Backend>(backend: B) -> Vec<u8> {
+        let mut device = Device::with_backend(backend);
+        let mut buf = [0; 8];
+        let len = device.read_packet(&mut buf).unwrap();
+        buf[..len].to_vec()
+    }
+
+    #[test]
+    fn buffered_reads_replay_the_remaining_count() {
+        let mut mock = Mock::new();
+        mock.expect_read(&[1, 2, 3, 4]);
+        let replayed = record_and_replay(mock, |backend| {
+            let mut buf = [0; 1];
+            assert_eq!(backend.read(&mut buf).unwrap(), 1);
+            assert_eq!(backend.readbuffer_remaining(), 3);
+            let mut buf = [0; 3];
+            assert_eq!(backend.read(&mut buf).unwrap(), 3);
+        });
+        assert_eq!(replayed.readbuffer_remaining(), 0);
+        assert_eq!(read_packet(replayed), [1, 2, 3, 4]);
+    }
+
+    #[test]
+    fn errors_replay_as_the_same_variant() {
+        let mut mock = Mock::new();
+        let unknown = ErrorContext::new("usb_reset")
+            .with_code(-1)
+            .with_message("USB reset failed");
+        mock.expect(
+            Call::SetBaudRate(1),
+            Reply::Error(Error::InvalidInput("baud rate out of range")),
+        )
+        .expect(Call::UsbReset, Reply::Error(Error::unknown(unknown)))
+        .expect(
+            Call::UsbPurgeBuffers,
+            Reply::Error(Error::unknown(ErrorContext::new("usb_purge_buffers"))),
+        );
+        let session = |backend: &mut dyn Backend| {
+            let _ = backend.set_baud_rate(1);
+            let _ = backend.usb_reset();
+            let _ = backend.usb_purge_buffers();
+        };
+        let mut replayed = record_and_replay(mock, session);
+
+        match replayed.set_baud_rate(1) {
+            Err(Error::InvalidInput(message)) => assert_eq!(message, "baud rate out of range"),
+            result => panic!("unexpected {:?}", result),
+        }
+        let context = match replayed.usb_reset() {
+            Err(Error::Unknown { context, .. }) => context,
+            result => panic!("unexpected {:?}", result),
+        };
+        assert_eq!(context.operation(), "usb_reset");
+        assert_eq!(context.code(), Some(-1));
+        assert_eq!(context.message(), Some("USB reset failed"));
+        match replayed.usb_purge_buffers() {
+            Err(Error::Unknown { context, .. }) => {
+                assert_eq!((context.code(), context.message()), (None, None))
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+        replayed.assert_done();
+    }
+}
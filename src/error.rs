@@ -1,9 +1,13 @@
 use thiserror::Error;
 
+#[cfg(feature = "libftdi1-sys")]
 use std::ffi::CStr;
+use std::fmt;
 use std::io;
 
+#[cfg(feature = "libftdi1-sys")]
 use super::ffi;
+use super::DeviceId;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -21,13 +25,22 @@ pub enum Error {
     Disconnected,
     #[error("the device does not have the specified interface")]
     NoSuchInterface,
-    #[error("libftdi reported error to perform operation")]
-    RequestFailed,
+    #[error("request failed: {context}")]
+    RequestFailed { context: Box<ErrorContext> },
     #[error("input value invalid: {0}")]
     InvalidInput(&'static str),
+    #[error("MPSSE rejected opcode {0:#04x} as a bad command")]
+    MpsseBadCommand(u8),
+    #[error("failed to synchronize with the MPSSE command processor")]
+    MpsseSyncFailed,
+    #[error("I/O error while communicating with the device")]
+    Io(#[source] io::Error),
 
-    #[error("unknown or unexpected libftdi error")]
-    Unknown { source: LibFtdiError },
+    #[error("unknown or unexpected libftdi error: {context}")]
+    Unknown {
+        source: LibFtdiError,
+        context: Box<ErrorContext>,
+    },
 
     #[error("INTERNAL, DO NOT USE")]
     #[doc(hidden)]
@@ -35,29 +48,253 @@ pub enum Error {
 }
 
 impl Error {
-    pub(crate) fn unknown(context: *mut ffi::ftdi_context) -> Self {
+    pub(crate) fn request_failed(context: ErrorContext) -> Self {
+        Error::RequestFailed {
+            context: Box::new(context),
+        }
+    }
+
+    pub(crate) fn unknown(context: ErrorContext) -> Self {
+        Error::Unknown {
+            source: LibFtdiError {
+                message: context.message.unwrap_or("no error message"),
+            },
+            context: Box::new(context),
+        }
+    }
+
+    /// The operation and device the error occurred on, if known
+    pub fn context(&self) -> Option<&ErrorContext> {
+        match self {
+            Error::RequestFailed { context } | Error::Unknown { context, .. } => Some(context),
+            _ => None,
+        }
+    }
+}
+
+/// Details of a failed operation, for diagnostics
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct ErrorContext {
+    operation: &'static str,
+    code: Option<i32>,
+    message: Option<&'static str>,
+    device: Option<DeviceId>,
+}
+
+impl ErrorContext {
+    pub(crate) fn new(operation: &'static str) -> Self {
+        ErrorContext {
+            operation,
+            code: None,
+            message: None,
+            device: None,
+        }
+    }
+
+    pub(crate) fn with_code(mut self, code: i32) -> Self {
+        self.code = Some(code);
+        self
+    }
+
+    pub(crate) fn with_message(mut self, message: &'static str) -> Self {
+        self.message = Some(message);
+        self
+    }
+
+    /// Takes the error string `libftdi` stored for the last failure
+    #[cfg(feature = "libftdi1-sys")]
+    pub(crate) fn with_libftdi_message(self, context: *mut ffi::ftdi_context) -> Self {
         let message = unsafe { CStr::from_ptr(ffi::ftdi_get_error_string(context)) }
             .to_str()
-            .expect("all error strings are expected to be ASCII");
-        Error::Unknown {
-            source: LibFtdiError { message },
+            .unwrap_or("libftdi error message is not valid UTF-8");
+        self.with_message(message)
+    }
+
+    pub(crate) fn with_device(mut self, device: &DeviceId) -> Self {
+        self.device = Some(device.clone());
+        self
+    }
+
+    /// Name of the failed operation, such as `set_latency_timer`
+    pub fn operation(&self) -> &'static str {
+        self.operation
+    }
+
+    /// The raw return code of the failed library call
+    pub fn code(&self) -> Option<i32> {
+        self.code
+    }
+
+    /// The error string reported by `libftdi`
+    pub fn message(&self) -> Option<&'static str> {
+        self.message
+    }
+
+    pub fn device(&self) -> Option<&DeviceId> {
+        self.device.as_ref()
+    }
+}
+
+impl fmt::Display for ErrorContext {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(self.operation)?;
+        if let Some(device) = &self.device {
+            write!(f, " on {}", device)?;
+        }
+        if let Some(code) = self.code {
+            write!(f, " (code {})", code)?;
+        }
+        if let Some(message) = self.message {
+            write!(f, ": {}", message)?;
+        }
+        Ok(())
+    }
+}
+
+/// Device disconnection reported by a read or write is turned into `Disconnected`
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::NotConnected {
+            Error::Disconnected
+        } else {
+            Error::Io(err)
         }
     }
 }
 
 pub type Result<T> = std::result::Result<T, Error>;
 
+/// A `libusb` error code
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash, Error)]
+pub enum UsbError {
+    #[error("input/output error")]
+    Io,
+    #[error("invalid parameter")]
+    InvalidParam,
+    #[error("access denied (insufficient permissions)")]
+    Access,
+    #[error("no such device (it may have been disconnected)")]
+    NoDevice,
+    #[error("entity not found")]
+    NotFound,
+    #[error("resource busy")]
+    Busy,
+    #[error("operation timed out")]
+    Timeout,
+    #[error("overflow")]
+    Overflow,
+    #[error("pipe error")]
+    Pipe,
+    #[error("system call interrupted")]
+    Interrupted,
+    #[error("insufficient memory")]
+    NoMem,
+    #[error("operation not supported or unimplemented on this platform")]
+    NotSupported,
+    #[error("other error")]
+    Other,
+}
+
+impl UsbError {
+    /// Converts a negative `libusb` return code, unknown codes becoming `Other`
+    pub fn from_code(code: i32) -> Self {
+        match code {
+            -1 => UsbError::Io,
+            -2 => UsbError::InvalidParam,
+            -3 => UsbError::Access,
+            -4 => UsbError::NoDevice,
+            -5 => UsbError::NotFound,
+            -6 => UsbError::Busy,
+            -7 => UsbError::Timeout,
+            -8 => UsbError::Overflow,
+            -9 => UsbError::Pipe,
+            -10 => UsbError::Interrupted,
+            -11 => UsbError::NoMem,
+            -12 => UsbError::NotSupported,
+            _ => UsbError::Other,
+        }
+    }
+
+    /// The `libusb` return code of this error
+    pub fn code(self) -> i32 {
+        match self {
+            UsbError::Io => -1,
+            UsbError::InvalidParam => -2,
+            UsbError::Access => -3,
+            UsbError::NoDevice => -4,
+            UsbError::NotFound => -5,
+            UsbError::Busy => -6,
+            UsbError::Timeout => -7,
+            UsbError::Overflow => -8,
+            UsbError::Pipe => -9,
+            UsbError::Interrupted => -10,
+            UsbError::NoMem => -11,
+            UsbError::NotSupported => -12,
+            UsbError::Other => -99,
+        }
+    }
+
+    /// The closest matching `io::ErrorKind`
+    pub fn kind(self) -> io::ErrorKind {
+        match self {
+            UsbError::InvalidParam => io::ErrorKind::InvalidInput,
+            UsbError::Access => io::ErrorKind::PermissionDenied,
+            UsbError::NoDevice => io::ErrorKind::NotConnected,
+            UsbError::NotFound => io::ErrorKind::NotFound,
+            UsbError::Timeout => io::ErrorKind::TimedOut,
+            UsbError::Overflow => io::ErrorKind::InvalidData,
+            UsbError::Pipe => io::ErrorKind::BrokenPipe,
+            UsbError::Interrupted => io::ErrorKind::Interrupted,
+            UsbError::NoMem => io::ErrorKind::OutOfMemory,
+            UsbError::NotSupported => io::ErrorKind::Unsupported,
+            UsbError::Io | UsbError::Busy | UsbError::Other => io::ErrorKind::Other,
+        }
+    }
+}
+
+impl From<UsbError> for io::Error {
+    fn from(err: UsbError) -> Self {
+        io::Error::new(err.kind(), err)
+    }
+}
+
+#[cfg(feature = "rusb")]
+impl From<rusb::Error> for UsbError {
+    fn from(err: rusb::Error) -> Self {
+        match err {
+            rusb::Error::Io => UsbError::Io,
+            rusb::Error::InvalidParam => UsbError::InvalidParam,
+            rusb::Error::Access => UsbError::Access,
+            rusb::Error::NoDevice => UsbError::NoDevice,
+            rusb::Error::NotFound => UsbError::NotFound,
+            rusb::Error::Busy => UsbError::Busy,
+            rusb::Error::Timeout => UsbError::Timeout,
+            rusb::Error::Overflow => UsbError::Overflow,
+            rusb::Error::Pipe => UsbError::Pipe,
+            rusb::Error::Interrupted => UsbError::Interrupted,
+            rusb::Error::NoMem => UsbError::NoMem,
+            rusb::Error::NotSupported => UsbError::NotSupported,
+            _ => UsbError::Other,
+        }
+    }
+}
+
 #[derive(Debug, Error)]
 #[error("libftdi: {message}")]
 pub struct LibFtdiError {
     message: &'static str,
 }
 
-// Ideally this should be using libusb bindings, but we don't depend on any specific USB crate yet
+#[cfg(feature = "libftdi1-sys")]
 pub(crate) fn libusb_to_io(code: i32) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, format!("libusb error code {}", code))
+    UsbError::from_code(code).into()
 }
 
 pub(crate) fn libftdi_to_io(err: Error) -> io::Error {
-    io::Error::new(io::ErrorKind::Other, err)
+    match err {
+        Error::Io(err) => err,
+        Error::Disconnected => io::Error::new(io::ErrorKind::NotConnected, err),
+        Error::InvalidInput(_) => io::Error::new(io::ErrorKind::InvalidInput, err),
+        err => io::Error::new(io::ErrorKind::Other, err),
+    }
 }
@@ -0,0 +1,428 @@
+//! Individual GPIO pins over the MPSSE low (ADBUS) and high (ACBUS) byte commands
+//!
+//! The MPSSE only allows setting a whole bank of eight pins at once, so [`Gpio`]
+//! keeps a cache of the direction and output value of every pin and hands out
+//! [`OutputPin`] and [`InputPin`] objects that update only their own bit.
+//!
+//! With the `embedded-hal` feature enabled the pin objects implement the
+//! `embedded_hal::digital` traits.
+
+use std::cell::RefCell;
+
+use ftdi_mpsse::{MpsseCmdBuilder, MpsseCmdExecutor};
+use thiserror::Error;
+
+/// One of the two GPIO banks of an MPSSE interface
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Bank {
+    /// The low byte (ADBUS / BDBUS)
+    Lower,
+    /// The high byte (ACBUS / BCBUS)
+    Upper,
+}
+
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    #[error("pin {pin} of the {bank:?} bank is already in use")]
+    PinInUse { bank: Bank, pin: u8 },
+    #[error("pin index {0} is out of range")]
+    InvalidPin(u8),
+    #[error("MPSSE command execution failed")]
+    Executor(#[source] E),
+}
+
+#[cfg(feature = "embedded-hal")]
+impl<E: std::fmt::Debug> embedded_hal::digital::Error for Error<E> {
+    fn kind(&self) -> embedded_hal::digital::ErrorKind {
+        embedded_hal::digital::ErrorKind::Other
+    }
+}
+
+#[derive(Clone, Copy, Debug, Default)]
+struct BankState {
+    value: u8,
+    direction: u8,
+    taken: u8,
+}
+
+struct Inner<E> {
+    executor: E,
+    lower: BankState,
+    upper: BankState,
+}
+
+impl<E: MpsseCmdExecutor> Inner<E> {
+    fn bank(&mut self, bank: Bank) -> &mut BankState {
+        match bank {
+            Bank::Lower => &mut self.lower,
+            Bank::Upper => &mut self.upper,
+        }
+    }
+
+    fn write(&mut self, bank: Bank) -> Result<(), Error<E::Error>> {
+        let state = *self.bank(bank);
+        let cmd = match bank {
+            Bank::Lower => MpsseCmdBuilder::new().set_gpio_lower(state.value, state.direction),
+            Bank::Upper => MpsseCmdBuilder::new().set_gpio_upper(state.value, state.direction),
+        };
+        self.executor.send(cmd.as_slice()).map_err(Error::Executor)
+    }
+
+    fn read(&mut self, bank: Bank) -> Result<u8, Error<E::Error>> {
+        let cmd = match bank {
+            Bank::Lower => MpsseCmdBuilder::new().gpio_lower(),
+            Bank::Upper => MpsseCmdBuilder::new().gpio_upper(),
+        }
+        .send_immediate();
+        let mut value = [0u8];
+        self.executor
+            .xfer(cmd.as_slice(), &mut value)
+            .map_err(Error::Executor)?;
+        Ok(value[0])
+    }
+}
+
+/// Shared GPIO state of an MPSSE interface
+///
+/// The executor (usually a [`Device`](crate::Device)) should already be in MPSSE mode.
+/// Pins that are not handed out keep their cached direction and value, so pins
+/// driven by the serial engine can be configured once with [`Gpio::with_state`].
+pub struct Gpio<E> {
+    inner: RefCell<Inner<E>>,
+}
+
+impl<E: MpsseCmdExecutor> Gpio<E> {
+    /// Takes over the executor, configuring all pins of both banks as inputs
+    pub fn new(executor: E) -> Result<Self, Error<E::Error>> {
+        Self::with_state(executor, (0, 0), (0, 0))
+    }
+
+    /// Takes over the executor with the given initial `(value, direction)` of both banks
+    pub fn with_state(
+        executor: E,
+        lower: (u8, u8),
+        upper: (u8, u8),
+    ) -> Result<Self, Error<E::Error>> {
+        let mut inner = Inner {
+            executor,
+            lower: BankState {
+                value: lower.0,
+                direction: lower.1,
+                taken: 0,
+            },
+            upper: BankState {
+                value: upper.0,
+                direction: upper.1,
+                taken: 0,
+            },
+        };
+        inner.write(Bank::Lower)?;
+        inner.write(Bank::Upper)?;
+        Ok(Gpio {
+            inner: RefCell::new(inner),
+        })
+    }
+
+    /// Configures the pin as an output driving the given level
+    pub fn output_pin(
+        &self,
+        bank: Bank,
+        pin: u8,
+        high: bool,
+    ) -> Result<OutputPin<'_, E>, Error<E::Error>> {
+        let mask = self.take(bank, pin)?;
+        let mut inner = self.inner.borrow_mut();
+        let state = inner.bank(bank);
+        state.direction |= mask;
+        if high {
+            state.value |= mask;
+        } else {
+            state.value &= !mask;
+        }
+        if let Err(err) = inner.write(bank) {
+            inner.bank(bank).taken &= !mask;
+            return Err(err);
+        }
+        Ok(OutputPin {
+            gpio: self,
+            bank,
+            mask,
+        })
+    }
+
+    /// Configures the pin as an input
+    pub fn input_pin(&self, bank: Bank, pin: u8) -> Result<InputPin<'_, E>, Error<E::Error>> {
+        let mask = self.take(bank, pin)?;
+        let mut inner = self.inner.borrow_mut();
+        inner.bank(bank).direction &= !mask;
+        if let Err(err) = inner.write(bank) {
+            inner.bank(bank).taken &= !mask;
+            return Err(err);
+        }
+        Ok(InputPin {
+            gpio: self,
+            bank,
+            mask,
+        })
+    }
+
+    /// Reads the current levels of all pins of the bank
+    pub fn read(&self, bank: Bank) -> Result<u8, Error<E::Error>> {
+        self.inner.borrow_mut().read(bank)
+    }
+
+    /// Returns the cached `(value, direction)` of the bank
+    pub fn state(&self, bank: Bank) -> (u8, u8) {
+        let state = *self.inner.borrow_mut().bank(bank);
+        (state.value, state.direction)
+    }
+
+    /// Releases the executor
+    pub fn into_inner(self) -> E {
+        self.inner.into_inner().executor
+    }
+
+    fn take(&self, bank: Bank, pin: u8) -> Result<u8, Error<E::Error>> {
+        if pin >= 8 {
+            return Err(Error::InvalidPin(pin));
+        }
+        let mask = 1 << pin;
+        let mut inner = self.inner.borrow_mut();
+        let state = inner.bank(bank);
+        if state.taken & mask != 0 {
+            return Err(Error::PinInUse { bank, pin });
+        }
+        state.taken |= mask;
+        Ok(mask)
+    }
+
+    fn release(&self, bank: Bank, mask: u8) {
+        self.inner.borrow_mut().bank(bank).taken &= !mask;
+    }
+
+    fn set_level(&self, bank: Bank, mask: u8, high: bool) -> Result<(), Error<E::Error>> {
+        let mut inner = self.inner.borrow_mut();
+        let state = inner.bank(bank);
+        let previous = state.value;
+        if high {
+            state.value |= mask;
+        } else {
+            state.value &= !mask;
+        }
+        if state.value == previous {
+            return Ok(());
+        }
+        let result = inner.write(bank);
+        if result.is_err() {
+            inner.bank(bank).value = previous;
+        }
+        result
+    }
+}
+
+/// A pin configured as an output, released on drop
+pub struct OutputPin<'a, E: MpsseCmdExecutor> {
+    gpio: &'a Gpio<E>,
+    bank: Bank,
+    mask: u8,
+}
+
+impl<'a, E: MpsseCmdExecutor> OutputPin<'a, E> {
+    pub fn set_high(&mut self) -> Result<(), Error<E::Error>> {
+        self.gpio.set_level(self.bank, self.mask, true)
+    }
+
+    pub fn set_low(&mut self) -> Result<(), Error<E::Error>> {
+        self.gpio.set_level(self.bank, self.mask, false)
+    }
+
+    /// Returns the cached output level, no USB transfer is performed
+    pub fn is_set_high(&self) -> bool {
+        self.gpio.state(self.bank).0 & self.mask != 0
+    }
+}
+
+impl<'a, E: MpsseCmdExecutor> Drop for OutputPin<'a, E> {
+    fn drop(&mut self) {
+        self.gpio.release(self.bank, self.mask);
+    }
+}
+
+/// A pin configured as an input, released on drop
+pub struct InputPin<'a, E: MpsseCmdExecutor> {
+    gpio: &'a Gpio<E>,
+    bank: Bank,
+    mask: u8,
+}
+
+impl<'a, E: MpsseCmdExecutor> InputPin<'a, E> {
+    pub fn is_high(&mut self) -> Result<bool, Error<E::Error>> {
+        Ok(self.gpio.read(self.bank)? & self.mask != 0)
+    }
+}
+
+impl<'a, E: MpsseCmdExecutor> Drop for InputPin<'a, E> {
+    fn drop(&mut self) {
+        self.gpio.release(self.bank, self.mask);
+    }
+}
+
+#[cfg(feature = "embedded-hal")]
+mod hal {
+    use super::{Error, InputPin, OutputPin};
+
+    use embedded_hal::digital;
+    use ftdi_mpsse::MpsseCmdExecutor;
+
+    impl<'a, E: MpsseCmdExecutor> digital::ErrorType for OutputPin<'a, E>
+    where
+        E::Error: std::fmt::Debug,
+    {
+        type Error = Error<E::Error>;
+    }
+
+    impl<'a, E: MpsseCmdExecutor> digital::OutputPin for OutputPin<'a, E>
+    where
+        E::Error: std::fmt::Debug,
+    {
+        fn set_low(&mut self) -> Result<(), Self::Error> {
+            OutputPin::set_low(self)
+        }
+
+        fn set_high(&mut self) -> Result<(), Self::Error> {
+            OutputPin::set_high(self)
+        }
+    }
+
+    impl<'a, E: MpsseCmdExecutor> digital::StatefulOutputPin for OutputPin<'a, E>
+    where
+        E::Error: std::fmt::Debug,
+    {
+        fn is_set_high(&mut self) -> Result<bool, Self::Error> {
+            Ok(OutputPin::is_set_high(self))
+        }
+
+        fn is_set_low(&mut self) -> Result<bool, Self::Error> {
+            Ok(!OutputPin::is_set_high(self))
+        }
+    }
+
+    impl<'a, E: MpsseCmdExecutor> digital::ErrorType for InputPin<'a, E>
+    where
+        E::Error: std::fmt::Debug,
+    {
+        type Error = Error<E::Error>;
+    }
+
+    impl<'a, E: MpsseCmdExecutor> digital::InputPin for InputPin<'a, E>
+    where
+        E::Error: std::fmt::Debug,
+    {
+        fn is_high(&mut self) -> Result<bool, Self::Error> {
+            InputPin::is_high(self)
+        }
+
+        fn is_low(&mut self) -> Result<bool, Self::Error> {
+            InputPin::is_high(self).map(|high| !high)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::Mock;
+    use crate::mpsse::emulator::{Emulator, GpioLoopback};
+    use crate::Device;
+    use ftdi_mpsse::MpsseSettings;
+
+    /// A GPIO over a mock expecting the initial writes of both banks
+    fn gpio(writes: &[&[u8]]) -> Gpio<Device<Mock>> {
+        let mut mock = Mock::new();
+        mock.expect_write(&[0x80, 0x08, 0x0b])
+            .expect_write(&[0x82, 0x00, 0x00]);
+        for write in writes {
+            mock.expect_write(write);
+        }
+        Gpio::with_state(Device::with_backend(mock), (0x08, 0x0b), (0x00, 0x00)).unwrap()
+    }
+
+    #[test]
+    fn changing_a_pin_keeps_the_others() {
+        let gpio = gpio(&[
+            &[0x80, 0x18, 0x1b],
+            &[0x80, 0x08, 0x1b],
+            &[0x82, 0x00, 0x02],
+            &[0x82, 0x02, 0x02],
+        ]);
+        let mut lower = gpio.output_pin(Bank::Lower, 4, true).unwrap();
+        lower.set_low().unwrap();
+        // unchanged levels are not written again
+        lower.set_low().unwrap();
+        assert!(!lower.is_set_high());
+        let mut upper = gpio.output_pin(Bank::Upper, 1, false).unwrap();
+        upper.set_high().unwrap();
+
+        assert_eq!(gpio.state(Bank::Lower), (0x08, 0x1b));
+        assert_eq!(gpio.state(Bank::Upper), (0x02, 0x02));
+        drop((lower, upper));
+        gpio.into_inner().backend().assert_done();
+    }
+
+    #[test]
+    fn direction_changes() {
+        let gpio = gpio(&[&[0x80, 0x08, 0x03], &[0x80, 0x08, 0x0b]]);
+        let input = gpio.input_pin(Bank::Lower, 3).unwrap();
+        assert_eq!(gpio.state(Bank::Lower), (0x08, 0x03));
+        drop(input);
+        let output = gpio.output_pin(Bank::Lower, 3, true).unwrap();
+        assert_eq!(gpio.state(Bank::Lower), (0x08, 0x0b));
+        drop(output);
+        gpio.into_inner().backend().assert_done();
+    }
+
+    #[test]
+    fn pin_is_taken_once_and_released_on_drop() {
+        let gpio = gpio(&[&[0x80, 0x08, 0x0b], &[0x80, 0x08, 0x03]]);
+        let output = gpio.output_pin(Bank::Lower, 3, true).unwrap();
+        match gpio.input_pin(Bank::Lower, 3) {
+            Err(Error::PinInUse {
+                bank: Bank::Lower,
+                pin: 3,
+            }) => {}
+            result => panic!("unexpected {:?}", result.map(|_| ())),
+        }
+        drop(output);
+        let input = gpio.input_pin(Bank::Lower, 3).unwrap();
+        drop(input);
+        gpio.into_inner().backend().assert_done();
+    }
+
+    #[test]
+    fn invalid_pin_is_rejected() {
+        let gpio = gpio(&[]);
+        assert!(matches!(
+            gpio.output_pin(Bank::Upper, 8, false),
+            Err(Error::InvalidPin(8))
+        ));
+        assert!(matches!(
+            gpio.input_pin(Bank::Lower, 255),
+            Err(Error::InvalidPin(255))
+        ));
+        gpio.into_inner().backend().assert_done();
+    }
+
+    #[test]
+    fn input_follows_the_connected_output() {
+        let mut emulator = Emulator::new();
+        emulator.attach(GpioLoopback::new().connect(4, 13));
+        emulator.init(&MpsseSettings::default()).unwrap();
+        let gpio = Gpio::new(emulator).unwrap();
+
+        let mut output = gpio.output_pin(Bank::Lower, 4, false).unwrap();
+        let mut input = gpio.input_pin(Bank::Upper, 5).unwrap();
+        assert!(!input.is_high().unwrap());
+        output.set_high().unwrap();
+        assert!(input.is_high().unwrap());
+    }
+}
@@ -0,0 +1,535 @@
+//! Notification of FTDI devices being plugged in and unplugged
+//!
+//! A [`Watcher`] reports the devices matching a [`Filter`] as they arrive and
+//! leave, starting with the ones already connected. It relies on the hotplug
+//! callbacks of `libusb` where the platform supports them, and enumerates the
+//! devices periodically otherwise. The events are produced by a background
+//! thread, which stops when the watcher is dropped.
+//!
+//! # Example
+//!
+//! ```no_run
+//! use ftdi::hotplug::{Event, Filter, Watcher};
+//!
+//! # fn main() -> ftdi::Result<()> {
+//! let watcher = Watcher::new(Filter::new().product(0x6010))?;
+//! for event in watcher {
+//!     match event {
+//!         Event::Arrived(info) => println!("plugged in: {}", info.id),
+//!         Event::Left(info) => println!("unplugged: {}", info.id),
+//!     }
+//! }
+//! # Ok(())
+//! # }
+//! ```
+
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, Sender};
+use std::sync::Arc;
+use std::thread::{self, JoinHandle};
+use std::time::Duration;
+
+use rusb::{Context, Hotplug, HotplugBuilder, UsbContext};
+
+use crate::error::UsbError;
+use crate::{DeviceId, Error, Result};
+
+/// The enumeration period, and how often the background thread checks whether
+/// the watcher was dropped
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// How many times the strings of a device are read before reporting it without
+/// the missing ones
+///
+/// They often cannot be read right after the device arrived, while the system is
+/// still setting it up or adjusting its permissions.
+const READ_ATTEMPTS: u32 = 4;
+
+/// What is known about a device that arrived or left
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct DeviceInfo {
+    pub id: DeviceId,
+    pub manufacturer: Option<String>,
+    pub description: Option<String>,
+}
+
+/// The parts of a USB device the [`Tracker`] needs, so that it can be tested
+/// without one
+trait Probe {
+    /// The bus and address of the device
+    fn key(&self) -> (u8, u8);
+
+    /// The vendor and product IDs, `None` if the descriptor cannot be read
+    fn ids(&self) -> Option<(u16, u16)>;
+
+    /// Reads the identity of the device, along with whether all the strings it
+    /// has could be read
+    ///
+    /// The strings stay unknown when the device cannot be opened.
+    fn read_info(&self) -> Option<(DeviceInfo, bool)>;
+}
+
+impl<T: UsbContext> Probe for rusb::Device<T> {
+    fn key(&self) -> (u8, u8) {
+        (self.bus_number(), self.address())
+    }
+
+    fn ids(&self) -> Option<(u16, u16)> {
+        let descriptor = self.device_descriptor().ok()?;
+        Some((descriptor.vendor_id(), descriptor.product_id()))
+    }
+
+    fn read_info(&self) -> Option<(DeviceInfo, bool)> {
+        let descriptor = self.device_descriptor().ok()?;
+        let mut info = DeviceInfo {
+            id: DeviceId {
+                bus: Some(self.bus_number()),
+                address: Some(self.address()),
+                port_path: self.port_numbers().ok(),
+                vid: Some(descriptor.vendor_id()),
+                pid: Some(descriptor.product_id()),
+                serial: None,
+            },
+            manufacturer: None,
+            description: None,
+        };
+        let handle = match self.open() {
+            Ok(handle) => handle,
+            Err(_) => return Some((info, false)),
+        };
+        info.id.serial = handle.read_serial_number_string_ascii(&descriptor).ok();
+        info.manufacturer = handle.read_manufacturer_string_ascii(&descriptor).ok();
+        info.description = handle.read_product_string_ascii(&descriptor).ok();
+        let complete = (descriptor.serial_number_string_index().is_none()
+            || info.id.serial.is_some())
+            && (descriptor.manufacturer_string_index().is_none() || info.manufacturer.is_some())
+            && (descriptor.product_string_index().is_none() || info.description.is_some());
+        Some((info, complete))
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Event {
+    Arrived(DeviceInfo),
+    /// A device reported as arrived was unplugged
+    Left(DeviceInfo),
+}
+
+/// The devices to watch
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Filter {
+    vid: u16,
+    pid: Option<u16>,
+    serial: Option<String>,
+}
+
+impl Filter {
+    /// Matches every device with the FTDI vendor ID
+    pub fn new() -> Self {
+        Filter {
+            vid: 0x0403,
+            pid: None,
+            serial: None,
+        }
+    }
+
+    pub fn vendor(mut self, vid: u16) -> Self {
+        self.vid = vid;
+        self
+    }
+
+    pub fn product(mut self, pid: u16) -> Self {
+        self.pid = Some(pid);
+        self
+    }
+
+    /// Only matches the device with this serial number
+    ///
+    /// Devices whose serial number still cannot be read after a few attempts are
+    /// not matched.
+    pub fn serial(mut self, serial: &str) -> Self {
+        self.serial = Some(serial.to_owned());
+        self
+    }
+
+    fn matches_ids(&self, (vid, pid): (u16, u16)) -> bool {
+        vid == self.vid && self.pid.map_or(true, |expected| pid == expected)
+    }
+
+    fn matches(&self, info: &DeviceInfo) -> bool {
+        self.serial.is_none() || info.id.serial == self.serial
+    }
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+/// What the tracker knows about a present device
+enum State<D> {
+    /// Some of its strings could not be read yet
+    Reading { device: D, attempts: u32 },
+    /// Reported as arrived
+    Reported(DeviceInfo),
+    /// Not matching the filter
+    Ignored,
+}
+
+/// Turns arrivals and departures of devices into events for the matching ones
+///
+/// A device whose strings cannot be read is read again on the following passes,
+/// and reported once they are complete or after [`READ_ATTEMPTS`].
+struct Tracker<D> {
+    filter: Filter,
+    /// The devices present by bus and address
+    present: HashMap<(u8, u8), State<D>>,
+    events: Sender<Event>,
+}
+
+impl<D: Probe> Tracker<D> {
+    fn new(filter: Filter, events: Sender<Event>) -> Self {
+        Tracker {
+            filter,
+            present: HashMap::new(),
+            events,
+        }
+    }
+
+    /// Returns false once the watcher was dropped
+    fn arrived(&mut self, device: D) -> bool {
+        let key = device.key();
+        let matches = device.ids().is_some_and(|ids| self.filter.matches_ids(ids));
+        if self.present.contains_key(&key) || !matches {
+            return true;
+        }
+        self.read(key, device, 0)
+    }
+
+    fn left(&mut self, key: (u8, u8)) -> bool {
+        match self.present.remove(&key) {
+            Some(State::Reported(info)) => self.events.send(Event::Left(info)).is_ok(),
+            _ => true,
+        }
+    }
+
+    /// Reads the strings of the devices that were incomplete again
+    fn retry(&mut self) -> bool {
+        let keys: Vec<_> = self
+            .present
+            .iter()
+            .filter(|(_, state)| matches!(state, State::Reading { .. }))
+            .map(|(&key, _)| key)
+            .collect();
+        keys.into_iter().all(|key| match self.present.remove(&key) {
+            Some(State::Reading { device, attempts }) => self.read(key, device, attempts),
+            _ => true,
+        })
+    }
+
+    fn read(&mut self, key: (u8, u8), device: D, attempts: u32) -> bool {
+        let attempts = attempts + 1;
+        let info = match device.read_info() {
+            Some((_, false)) | None if attempts < READ_ATTEMPTS => {
+                self.present
+                    .insert(key, State::Reading { device, attempts });
+                return true;
+            }
+            read => read.map(|(info, _)| info),
+        };
+        match info.filter(|info| self.filter.matches(info)) {
+            Some(info) => {
+                self.present.insert(key, State::Reported(info.clone()));
+                self.events.send(Event::Arrived(info)).is_ok()
+            }
+            None => {
+                self.present.insert(key, State::Ignored);
+                true
+            }
+        }
+    }
+
+    /// Compares the present devices with the last enumeration
+    fn enumerate(&mut self, devices: Vec<D>) -> bool {
+        let keys: Vec<_> = devices.iter().map(Probe::key).collect();
+        let gone: Vec<_> = self
+            .present
+            .keys()
+            .filter(|key| !keys.contains(key))
+            .copied()
+            .collect();
+        gone.into_iter().all(|key| self.left(key))
+            && self.retry()
+            && devices.into_iter().all(|device| self.arrived(device))
+    }
+}
+
+/// Forwards hotplug callbacks to the background thread
+///
+/// The strings of a device cannot be read from within the callback, so the
+/// devices are handled once `libusb` returns from handling events.
+struct Forward(Sender<(bool, rusb::Device<Context>)>);
+
+impl Hotplug<Context> for Forward {
+    fn device_arrived(&mut self, device: rusb::Device<Context>) {
+        let _ = self.0.send((true, device));
+    }
+
+    fn device_left(&mut self, device: rusb::Device<Context>) {
+        let _ = self.0.send((false, device));
+    }
+}
+
+/// A source of events for devices being plugged in and unplugged
+pub struct Watcher {
+    events: Receiver<Event>,
+    stop: Arc<AtomicBool>,
+    thread: Option<JoinHandle<()>>,
+    hotplug: bool,
+}
+
+impl Watcher {
+    /// Starts watching the devices matching the filter
+    ///
+    /// The devices already connected are reported as arrived first.
+    pub fn new(filter: Filter) -> Result<Self> {
+        let context = Context::new().map_err(|err| Error::Io(UsbError::from(err).into()))?;
+        let (events, receiver) = mpsc::channel();
+        let mut tracker = Tracker::new(filter, events);
+        let stop = Arc::new(AtomicBool::new(false));
+        let stopped = stop.clone();
+
+        let (forward, forwarded) = mpsc::channel();
+        let registration = if rusb::has_hotplug() {
+            let mut builder = HotplugBuilder::new();
+            builder.vendor_id(tracker.filter.vid).enumerate(true);
+            if let Some(pid) = tracker.filter.pid {
+                builder.product_id(pid);
+            }
+            builder.register(&context, Box::new(Forward(forward))).ok()
+        } else {
+            None
+        };
+        let hotplug = registration.is_some();
+
+        let thread = thread::spawn(move || {
+            // Dropped with the thread, deregistering the callback
+            let _registration = registration;
+            while !stopped.load(Ordering::Relaxed) {
+                let running = if hotplug {
+                    if context.handle_events(Some(POLL_INTERVAL)).is_err() {
+                        thread::sleep(POLL_INTERVAL);
+                    }
+                    tracker.retry()
+                        && forwarded.try_iter().all(|(arrived, device)| {
+                            if arrived {
+                                tracker.arrived(device)
+                            } else {
+                                tracker.left(device.key())
+                            }
+                        })
+                } else {
+                    let running = match context.devices() {
+                        Ok(devices) => tracker.enumerate(devices.iter().collect()),
+                        Err(_) => true,
+                    };
+                    thread::sleep(POLL_INTERVAL);
+                    running
+                };
+                if !running {
+                    break;
+                }
+            }
+        });
+
+        Ok(Watcher {
+            events: receiver,
+            stop,
+            thread: Some(thread),
+            hotplug,
+        })
+    }
+
+    /// Whether `libusb` hotplug callbacks are used rather than periodic enumeration
+    pub fn uses_hotplug(&self) -> bool {
+        self.hotplug
+    }
+
+    /// Waits for the next event
+    pub fn recv(&self) -> Option<Event> {
+        self.events.recv().ok()
+    }
+
+    /// Waits up to `timeout` for the next event
+    pub fn recv_timeout(&self, timeout: Duration) -> Option<Event> {
+        self.events.recv_timeout(timeout).ok()
+    }
+
+    /// Returns the next event if there is one, without waiting
+    pub fn try_recv(&self) -> Option<Event> {
+        self.events.try_recv().ok()
+    }
+}
+
+impl Iterator for Watcher {
+    type Item = Event;
+
+    fn next(&mut self) -> Option<Event> {
+        self.recv()
+    }
+}
+
+impl Drop for Watcher {
+    fn drop(&mut self) {
+        self.stop.store(true, Ordering::Relaxed);
+        if let Some(thread) = self.thread.take() {
+            let _ = thread.join();
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::cell::Cell;
+    use std::rc::Rc;
+
+    #[derive(Clone)]
+    struct Fake {
+        address: u8,
+        pid: u16,
+        serial: &'static str,
+        /// Reads left failing to read the strings
+        failing: Rc<Cell<u32>>,
+    }
+
+    impl Fake {
+        fn new(address: u8, pid: u16, serial: &'static str) -> Self {
+            Fake {
+                address,
+                pid,
+                serial,
+                failing: Rc::new(Cell::new(0)),
+            }
+        }
+
+        fn failing(self, reads: u32) -> Self {
+            self.failing.set(reads);
+            self
+        }
+
+        fn info(&self, serial: Option<&str>) -> DeviceInfo {
+            DeviceInfo {
+                id: DeviceId {
+                    bus: Some(1),
+                    address: Some(self.address),
+                    port_path: None,
+                    vid: Some(0x0403),
+                    pid: Some(self.pid),
+                    serial: serial.map(String::from),
+                },
+                manufacturer: None,
+                description: None,
+            }
+        }
+    }
+
+    impl Probe for Fake {
+        fn key(&self) -> (u8, u8) {
+            (1, self.address)
+        }
+
+        fn ids(&self) -> Option<(u16, u16)> {
+            Some((0x0403, self.pid))
+        }
+
+        fn read_info(&self) -> Option<(DeviceInfo, bool)> {
+            let failing = self.failing.get();
+            if failing > 0 {
+                self.failing.set(failing - 1);
+                return Some((self.info(None), false));
+            }
+            Some((self.info(Some(self.serial)), true))
+        }
+    }
+
+    fn tracker(filter: Filter) -> (Tracker<Fake>, Receiver<Event>) {
+        let (events, receiver) = mpsc::channel();
+        (Tracker::new(filter, events), receiver)
+    }
+
+    fn arrived(device: &Fake) -> Event {
+        Event::Arrived(device.info(Some(device.serial)))
+    }
+
+    fn left(device: &Fake) -> Event {
+        Event::Left(device.info(Some(device.serial)))
+    }
+
+    #[test]
+    fn enumerations_are_compared() {
+        let (mut tracker, events) = tracker(Filter::new().product(0x6010));
+        let a = Fake::new(5, 0x6010, "FT1");
+        let b = Fake::new(6, 0x6010, "FT2");
+        let other = Fake::new(7, 0x6014, "FT3");
+
+        assert!(tracker.enumerate(vec![a.clone(), other.clone()]));
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), [arrived(&a)]);
+        assert!(tracker.enumerate(vec![a.clone(), b.clone(), other.clone()]));
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), [arrived(&b)]);
+        assert!(tracker.enumerate(vec![b.clone()]));
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), [left(&a)]);
+        assert!(tracker.enumerate(vec![b]));
+        assert_eq!(events.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn failed_reads_are_retried() {
+        let (mut tracker, events) = tracker(Filter::new().serial("FT1"));
+        let device = Fake::new(5, 0x6010, "FT1").failing(2);
+
+        assert!(tracker.enumerate(vec![device.clone()]));
+        assert!(tracker.enumerate(vec![device.clone()]));
+        assert_eq!(events.try_iter().count(), 0);
+        assert!(tracker.enumerate(vec![device.clone()]));
+        assert_eq!(events.try_iter().collect::<Vec<_>>(), [arrived(&device)]);
+    }
+
+    #[test]
+    fn incomplete_info_is_reported_after_the_last_attempt() {
+        let (mut tracker, events) = tracker(Filter::new());
+        let device = Fake::new(5, 0x6010, "FT1").failing(READ_ATTEMPTS);
+
+        assert!(tracker.arrived(device.clone()));
+        for _ in 1..READ_ATTEMPTS {
+            assert_eq!(events.try_iter().count(), 0);
+            assert!(tracker.retry());
+        }
+        assert_eq!(
+            events.try_iter().collect::<Vec<_>>(),
+            [Event::Arrived(device.info(None))]
+        );
+        assert!(tracker.retry());
+        assert_eq!(events.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn devices_leaving_before_being_reported_are_not() {
+        let (mut tracker, events) = tracker(Filter::new());
+        let device = Fake::new(5, 0x6010, "FT1").failing(1);
+
+        assert!(tracker.arrived(device.clone()));
+        assert!(tracker.left(device.key()));
+        assert!(tracker.retry());
+        assert_eq!(events.try_iter().count(), 0);
+    }
+
+    #[test]
+    fn tracking_stops_once_the_watcher_is_dropped() {
+        let (mut tracker, events) = tracker(Filter::new());
+        drop(events);
+        assert!(!tracker.arrived(Fake::new(5, 0x6010, "FT1")));
+    }
+}
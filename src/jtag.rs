@@ -0,0 +1,531 @@
+//! JTAG TAP controller access over MPSSE
+//!
+//! The standard MPSSE JTAG pinout is used: TCK on ADBUS0, TDI on ADBUS1,
+//! TDO on ADBUS2 and TMS on ADBUS3. Data is shifted LSB first, with TDI and
+//! TMS changing on the falling edge of TCK and TDO sampled on the rising edge.
+
+use std::collections::VecDeque;
+use std::fmt;
+
+use ftdi_mpsse::{
+    ClockBits, ClockBitsOut, ClockData, ClockDataOut, ClockTMS, ClockTMSOut, MpsseCmdBuilder,
+    MpsseCmdExecutor,
+};
+use thiserror::Error;
+
+/// Maximum number of bytes a single MPSSE clock data command can transfer
+const MAX_CHUNK: usize = 65536;
+
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    #[error("{bits} bits do not fit into {bytes} bytes of data")]
+    InvalidLength { bits: usize, bytes: usize },
+    #[error("scan chain is broken or TDO is stuck low")]
+    ChainBroken,
+    #[error("scan chain has more than {0} devices")]
+    ChainTooLong(usize),
+    #[error("MPSSE command execution failed")]
+    Executor(#[source] E),
+}
+
+/// The states of the IEEE 1149.1 TAP controller
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TapState {
+    TestLogicReset,
+    RunTestIdle,
+    SelectDrScan,
+    CaptureDr,
+    ShiftDr,
+    Exit1Dr,
+    PauseDr,
+    Exit2Dr,
+    UpdateDr,
+    SelectIrScan,
+    CaptureIr,
+    ShiftIr,
+    Exit1Ir,
+    PauseIr,
+    Exit2Ir,
+    UpdateIr,
+}
+
+impl TapState {
+    const ALL: [TapState; 16] = [
+        TapState::TestLogicReset,
+        TapState::RunTestIdle,
+        TapState::SelectDrScan,
+        TapState::CaptureDr,
+        TapState::ShiftDr,
+        TapState::Exit1Dr,
+        TapState::PauseDr,
+        TapState::Exit2Dr,
+        TapState::UpdateDr,
+        TapState::SelectIrScan,
+        TapState::CaptureIr,
+        TapState::ShiftIr,
+        TapState::Exit1Ir,
+        TapState::PauseIr,
+        TapState::Exit2Ir,
+        TapState::UpdateIr,
+    ];
+
+    /// The state the TAP controller moves to on a TCK rising edge with the given TMS level
+    pub fn next(self, tms: bool) -> TapState {
+        use TapState::*;
+        match (self, tms) {
+            (TestLogicReset, false) => RunTestIdle,
+            (TestLogicReset, true) => TestLogicReset,
+            (RunTestIdle, false) => RunTestIdle,
+            (RunTestIdle, true) => SelectDrScan,
+            (SelectDrScan, false) => CaptureDr,
+            (SelectDrScan, true) => SelectIrScan,
+            (CaptureDr, false) | (Exit2Dr, false) | (ShiftDr, false) => ShiftDr,
+            (CaptureDr, true) | (ShiftDr, true) => Exit1Dr,
+            (Exit1Dr, false) | (PauseDr, false) => PauseDr,
+            (Exit1Dr, true) | (Exit2Dr, true) => UpdateDr,
+            (PauseDr, true) => Exit2Dr,
+            (UpdateDr, false) | (UpdateIr, false) => RunTestIdle,
+            (UpdateDr, true) | (UpdateIr, true) => SelectDrScan,
+            (SelectIrScan, false) => CaptureIr,
+            (SelectIrScan, true) => TestLogicReset,
+            (CaptureIr, false) | (Exit2Ir, false) | (ShiftIr, false) => ShiftIr,
+            (CaptureIr, true) | (ShiftIr, true) => Exit1Ir,
+            (Exit1Ir, false) | (PauseIr, false) => PauseIr,
+            (Exit1Ir, true) | (Exit2Ir, true) => UpdateIr,
+            (PauseIr, true) => Exit2Ir,
+        }
+    }
+
+    /// Whether the TAP controller can stay in this state while TCK is clocked
+    pub fn is_stable(self) -> bool {
+        matches!(
+            self,
+            TapState::TestLogicReset
+                | TapState::RunTestIdle
+                | TapState::ShiftDr
+                | TapState::PauseDr
+                | TapState::ShiftIr
+                | TapState::PauseIr
+        )
+    }
+
+    /// The shortest TMS sequence leading from this state to `target`
+    pub fn path_to(self, target: TapState) -> Vec<bool> {
+        let index = |state: TapState| TapState::ALL.iter().position(|&s| s == state).unwrap();
+        let mut previous: [Option<(TapState, bool)>; 16] = [None; 16];
+        let mut queue = VecDeque::new();
+        queue.push_back(self);
+        while let Some(state) = queue.pop_front() {
+            if state == target {
+                break;
+            }
+            for &tms in &[false, true] {
+                let next = state.next(tms);
+                if next != self && previous[index(next)].is_none() {
+                    previous[index(next)] = Some((state, tms));
+                    queue.push_back(next);
+                }
+            }
+        }
+
+        let mut path = vec![];
+        let mut state = target;
+        while state != self {
+            let (from, tms) = previous[index(state)].expect("all TAP states are reachable");
+            path.push(tms);
+            state = from;
+        }
+        path.reverse();
+        path
+    }
+}
+
+/// A JTAG IDCODE register value
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+pub struct IdCode(pub u32);
+
+impl IdCode {
+    /// The JEDEC manufacturer identity (bank and ID without parity)
+    pub fn manufacturer(self) -> u16 {
+        ((self.0 >> 1) & 0x7ff) as u16
+    }
+
+    pub fn part(self) -> u16 {
+        (self.0 >> 12) as u16
+    }
+
+    pub fn version(self) -> u8 {
+        (self.0 >> 28) as u8
+    }
+}
+
+impl fmt::Debug for IdCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "IdCode({:#010x})", self.0)
+    }
+}
+
+impl fmt::Display for IdCode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#010x}", self.0)
+    }
+}
+
+/// A JTAG TAP driver tracking the controller state
+pub struct Jtag<E> {
+    executor: E,
+    state: TapState,
+}
+
+impl<E: MpsseCmdExecutor> Jtag<E> {
+    /// Configures the JTAG pins and resets the TAP controller
+    ///
+    /// The executor should already be in MPSSE mode with the desired TCK frequency.
+    pub fn new(executor: E) -> Result<Self, Error<E::Error>> {
+        let mut jtag = Jtag {
+            executor,
+            state: TapState::TestLogicReset,
+        };
+        let cmd = MpsseCmdBuilder::new().set_gpio_lower(0x08, 0x0b);
+        jtag.executor
+            .send(cmd.as_slice())
+            .map_err(Error::Executor)?;
+        jtag.reset()?;
+        Ok(jtag)
+    }
+
+    /// The current (tracked) state of the TAP controller
+    pub fn state(&self) -> TapState {
+        self.state
+    }
+
+    pub fn executor_mut(&mut self) -> &mut E {
+        &mut self.executor
+    }
+
+    pub fn into_inner(self) -> E {
+        self.executor
+    }
+
+    /// Moves the TAP controller to Test-Logic-Reset by clocking five TMS ones
+    pub fn reset(&mut self) -> Result<(), Error<E::Error>> {
+        let cmd = Self::tms(MpsseCmdBuilder::new(), &[true; 5]);
+        self.executor
+            .send(cmd.as_slice())
+            .map_err(Error::Executor)?;
+        self.state = TapState::TestLogicReset;
+        Ok(())
+    }
+
+    /// Moves the TAP controller to the given state along the shortest path
+    pub fn goto(&mut self, state: TapState) -> Result<(), Error<E::Error>> {
+        let path = self.state.path_to(state);
+        if path.is_empty() {
+            return Ok(());
+        }
+        let cmd = Self::tms(MpsseCmdBuilder::new(), &path);
+        self.executor
+            .send(cmd.as_slice())
+            .map_err(Error::Executor)?;
+        self.state = state;
+        Ok(())
+    }
+
+    /// Clocks TCK the given number of times without leaving the current state
+    ///
+    /// The current state should be a stable one, see [`TapState::is_stable`].
+    pub fn clock(&mut self, cycles: u32) -> Result<(), Error<E::Error>> {
+        // TMS keeps the level of the last bit that led into the current state
+        let mut cmd = MpsseCmdBuilder::new();
+        let mut bytes = (cycles / 8) as usize;
+        let zeros = vec![0u8; std::cmp::min(bytes, MAX_CHUNK)];
+        while bytes > 0 {
+            let chunk = std::cmp::min(bytes, MAX_CHUNK);
+            cmd = cmd.clock_data_out(ClockDataOut::LsbNeg, &zeros[..chunk]);
+            bytes -= chunk;
+        }
+        cmd = cmd.clock_bits_out(ClockBitsOut::LsbNeg, 0, (cycles % 8) as u8);
+        if !cmd.as_slice().is_empty() {
+            self.executor
+                .send(cmd.as_slice())
+                .map_err(Error::Executor)?;
+        }
+        Ok(())
+    }
+
+    /// Moves to Run-Test/Idle and stays there for the given number of TCK cycles
+    pub fn run_test(&mut self, cycles: u32) -> Result<(), Error<E::Error>> {
+        self.goto(TapState::RunTestIdle)?;
+        self.clock(cycles)
+    }
+
+    /// Shifts `bits` bits of `tdi` into the instruction register, returning the TDO bits
+    ///
+    /// Data is LSB first, bit `i` being `tdi[i / 8] >> (i % 8)`. The TAP
+    /// controller is left in the `end` state.
+    pub fn shift_ir(
+        &mut self,
+        tdi: &[u8],
+        bits: usize,
+        end: TapState,
+    ) -> Result<Vec<u8>, Error<E::Error>> {
+        self.shift(TapState::ShiftIr, tdi, bits, end, true)
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Shifts `bits` bits of `tdi` into the data register, returning the TDO bits
+    ///
+    /// See [`shift_ir`](Self::shift_ir) for the data layout.
+    pub fn shift_dr(
+        &mut self,
+        tdi: &[u8],
+        bits: usize,
+        end: TapState,
+    ) -> Result<Vec<u8>, Error<E::Error>> {
+        self.shift(TapState::ShiftDr, tdi, bits, end, true)
+            .map(Option::unwrap_or_default)
+    }
+
+    /// Like [`shift_ir`](Self::shift_ir), but without reading TDO back
+    pub fn write_ir(
+        &mut self,
+        tdi: &[u8],
+        bits: usize,
+        end: TapState,
+    ) -> Result<(), Error<E::Error>> {
+        self.shift(TapState::ShiftIr, tdi, bits, end, false)
+            .map(|_| ())
+    }
+
+    /// Like [`shift_dr`](Self::shift_dr), but without reading TDO back
+    pub fn write_dr(
+        &mut self,
+        tdi: &[u8],
+        bits: usize,
+        end: TapState,
+    ) -> Result<(), Error<E::Error>> {
+        self.shift(TapState::ShiftDr, tdi, bits, end, false)
+            .map(|_| ())
+    }
+
+    /// Discovers the devices on the scan chain
+    ///
+    /// Resets the TAP controllers and reads the data registers, which then contain
+    /// either a 32-bit IDCODE (LSB set) or a single-bit BYPASS register (reported as `None`).
+    /// The devices are listed starting from the one closest to TDO.
+    pub fn scan_chain(
+        &mut self,
+        max_devices: usize,
+    ) -> Result<Vec<Option<IdCode>>, Error<E::Error>> {
+        self.reset()?;
+        let bits = (max_devices + 1) * 32;
+        let ones = vec![0xffu8; bits / 8];
+        let tdo = self.shift_dr(&ones, bits, TapState::RunTestIdle)?;
+        if tdo.iter().all(|&b| b == 0) {
+            return Err(Error::ChainBroken);
+        }
+
+        let bit = |i: usize| (tdo[i / 8] >> (i % 8)) & 1 != 0;
+        let mut devices = vec![];
+        let mut pos = 0;
+        while pos + 32 <= bits {
+            if !bit(pos) {
+                devices.push(None);
+                pos += 1;
+            } else {
+                let word = (0..32).fold(0u32, |acc, i| acc | (u32::from(bit(pos + i)) << i));
+                if word == 0xffff_ffff {
+                    return Ok(devices);
+                }
+                devices.push(Some(IdCode(word)));
+                pos += 32;
+            }
+            if devices.len() > max_devices {
+                break;
+            }
+        }
+        Err(Error::ChainTooLong(max_devices))
+    }
+
+    fn shift(
+        &mut self,
+        shift_state: TapState,
+        tdi: &[u8],
+        bits: usize,
+        end: TapState,
+        read: bool,
+    ) -> Result<Option<Vec<u8>>, Error<E::Error>> {
+        if bits > tdi.len() * 8 {
+            return Err(Error::InvalidLength {
+                bits,
+                bytes: tdi.len(),
+            });
+        }
+        if bits == 0 {
+            self.goto(end)?;
+            return Ok(Some(vec![]));
+        }
+
+        let mut cmd = Self::tms(MpsseCmdBuilder::new(), &self.state.path_to(shift_state));
+
+        // The last bit is clocked together with TMS to leave the shift state
+        let exit = end != shift_state;
+        let body = if exit { bits - 1 } else { bits };
+        let full = body / 8;
+        let rem = (body % 8) as u8;
+
+        for chunk in tdi[..full].chunks(MAX_CHUNK) {
+            cmd = if read {
+                cmd.clock_data(ClockData::LsbPosIn, chunk)
+            } else {
+                cmd.clock_data_out(ClockDataOut::LsbNeg, chunk)
+            };
+        }
+        if rem > 0 {
+            cmd = if read {
+                cmd.clock_bits(ClockBits::LsbPosIn, tdi[full], rem)
+            } else {
+                cmd.clock_bits_out(ClockBitsOut::LsbNeg, tdi[full], rem)
+            };
+        }
+        let mut state = shift_state;
+        if exit {
+            let last = (tdi[(bits - 1) / 8] >> ((bits - 1) % 8)) & 1 != 0;
+            cmd = if read {
+                cmd.clock_tms(ClockTMS::NegTMSPosTDO, 0x01, last, 1)
+            } else {
+                cmd.clock_tms_out(ClockTMSOut::NegEdge, 0x01, last, 1)
+            };
+            state = state.next(true);
+            cmd = Self::tms(cmd, &state.path_to(end));
+        }
+
+        if !read {
+            self.executor
+                .send(cmd.as_slice())
+                .map_err(Error::Executor)?;
+            self.state = end;
+            return Ok(None);
+        }
+
+        let response_len = full + usize::from(rem > 0) + usize::from(exit);
+        let mut response = vec![0u8; response_len];
+        let cmd = cmd.send_immediate();
+        self.executor
+            .xfer(cmd.as_slice(), &mut response)
+            .map_err(Error::Executor)?;
+        self.state = end;
+
+        let mut tdo = vec![0u8; bits.div_ceil(8)];
+        tdo[..full].copy_from_slice(&response[..full]);
+        if rem > 0 {
+            tdo[full] = response[full] >> (8 - rem);
+        }
+        if exit && response[response_len - 1] & 0x80 != 0 {
+            tdo[(bits - 1) / 8] |= 1 << ((bits - 1) % 8);
+        }
+        Ok(Some(tdo))
+    }
+
+    fn tms(mut cmd: MpsseCmdBuilder, path: &[bool]) -> MpsseCmdBuilder {
+        for chunk in path.chunks(7) {
+            let bits = chunk
+                .iter()
+                .enumerate()
+                .fold(0u8, |acc, (i, &tms)| acc | (u8::from(tms) << i));
+            cmd = cmd.clock_tms_out(ClockTMSOut::NegEdge, bits, true, chunk.len() as u8);
+        }
+        cmd
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpsse::emulator::{Emulator, JtagTap};
+    use ftdi_mpsse::MpsseSettings;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    fn follow(from: TapState, path: &[bool]) -> TapState {
+        path.iter().fold(from, |state, &tms| state.next(tms))
+    }
+
+    #[test]
+    fn path_to_known_states() {
+        use TapState::*;
+        assert_eq!(TestLogicReset.path_to(ShiftDr), [false, true, false, false]);
+        assert_eq!(RunTestIdle.path_to(ShiftIr), [true, true, false, false]);
+        assert_eq!(ShiftDr.path_to(RunTestIdle), [true, true, false]);
+        assert_eq!(PauseIr.path_to(ShiftIr), [true, false]);
+        assert_eq!(ShiftDr.path_to(ShiftDr), []);
+    }
+
+    #[test]
+    fn path_to_is_shortest() {
+        for &from in &TapState::ALL {
+            for &to in &TapState::ALL {
+                let path = from.path_to(to);
+                assert_eq!(follow(from, &path), to, "{:?} to {:?}", from, to);
+                // no shorter TMS sequence leads to the target
+                for len in 0..path.len() {
+                    for bits in 0..1u32 << len {
+                        let shorter: Vec<bool> = (0..len).map(|i| bits & (1 << i) != 0).collect();
+                        assert_ne!(follow(from, &shorter), to, "{:?} to {:?}", from, to);
+                    }
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn five_ones_reset_from_any_state() {
+        for &state in &TapState::ALL {
+            assert_eq!(follow(state, &[true; 5]), TapState::TestLogicReset);
+        }
+    }
+
+    #[test]
+    fn scan_chain_lists_the_devices_from_tdo() {
+        // TDI -> first (AD1 to AD4) -> bypass only (AD4 to AD5) -> last (AD5 to AD2) -> TDO
+        let mut emulator = Emulator::new();
+        emulator
+            .attach(
+                JtagTap::new(4)
+                    .with_idcode(0x1, 0x0641_3041)
+                    .with_pins(0, 1, 4, 3),
+            )
+            .attach(JtagTap::new(5).with_pins(0, 4, 5, 3))
+            .attach(
+                JtagTap::new(4)
+                    .with_idcode(0xe, 0x4ba0_0477)
+                    .with_pins(0, 5, 2, 3),
+            );
+        emulator.init(&MpsseSettings::default()).unwrap();
+        let mut jtag = Jtag::new(emulator).unwrap();
+
+        assert_eq!(
+            jtag.scan_chain(8).unwrap(),
+            [Some(IdCode(0x4ba0_0477)), None, Some(IdCode(0x0641_3041))]
+        );
+        assert_eq!(jtag.state(), TapState::RunTestIdle);
+    }
+
+    #[test]
+    fn data_register_round_trip() {
+        let tap = Rc::new(RefCell::new(JtagTap::new(4).with_register(0x2, 12)));
+        let mut emulator = Emulator::new();
+        emulator.attach(tap.clone());
+        emulator.init(&MpsseSettings::default()).unwrap();
+        let mut jtag = Jtag::new(emulator).unwrap();
+
+        let captured = jtag.shift_ir(&[0x2], 4, TapState::RunTestIdle).unwrap();
+        // the IR capture value has its two low bits set to 01
+        assert_eq!(captured[0] & 0x3, 0x1);
+        jtag.write_dr(&[0x34, 0x0a], 12, TapState::PauseDr).unwrap();
+        assert_eq!(tap.borrow().state(), TapState::PauseDr);
+        let previous = jtag.shift_dr(&[0, 0], 12, TapState::RunTestIdle).unwrap();
+        assert_eq!(previous, [0x34, 0x0a]);
+        assert_eq!(tap.borrow().state(), TapState::RunTestIdle);
+    }
+}
@@ -6,22 +6,45 @@ use ftdi_mpsse::MpsseCmdBuilder;
 use ftdi_mpsse::MpsseCmdExecutor;
 use ftdi_mpsse::MpsseSettings;
 
+#[cfg(feature = "libftdi1-sys")]
 use libftdi1_sys as ffi;
 
 use std::convert::TryFrom;
+#[cfg(feature = "libftdi1-sys")]
 use std::convert::TryInto;
+use std::fmt;
 use std::io::{self, Read, Write};
+use std::thread;
+use std::time::{Duration, Instant};
 
+pub mod backend;
 pub mod error;
+pub mod gpio;
+#[cfg(feature = "rusb")]
+pub mod hotplug;
+pub mod jtag;
+pub mod mpsse;
+#[cfg(feature = "libftdi1-sys")]
 mod opener;
-
-pub use error::{Error, Result};
+#[cfg(feature = "libftdi1-sys")]
+pub mod reconnect;
+pub mod stats;
+pub mod svf;
+pub mod swd;
+mod trace;
+pub mod xsvf;
+
+pub use backend::Backend;
+#[cfg(feature = "libftdi1-sys")]
+pub use backend::LibFtdi;
+pub use error::{Error, ErrorContext, Result, UsbError};
+#[cfg(feature = "libftdi1-sys")]
+pub use opener::{find_by_bus_address, find_by_url, find_by_vid_pid, Opener};
 #[cfg(feature = "libusb1-sys")]
-pub use opener::find_by_raw_libusb_device;
-pub use opener::{find_by_bus_address, find_by_vid_pid, Opener};
+pub use opener::{find_by_port_path, find_by_raw_libusb_device};
 
 use error::libftdi_to_io;
-use error::libusb_to_io;
+use stats::{Stats, Transfers};
 
 /// The target interface
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
@@ -33,6 +56,7 @@ pub enum Interface {
     Any,
 }
 
+#[cfg(feature = "libftdi1-sys")]
 impl Into<ffi::ftdi_interface> for Interface {
     fn into(self) -> ffi::ftdi_interface {
         match self {
@@ -45,6 +69,30 @@ impl Into<ffi::ftdi_interface> for Interface {
     }
 }
 
+/// What to do with the kernel driver (`ftdi_sio` on Linux) bound to the interface
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ModuleDetachMode {
+    /// Detach the kernel driver when opening, leaving it detached (the default)
+    AutoDetach,
+    /// Leave the kernel driver alone, opening fails if it is bound
+    DontDetach,
+    /// Detach the kernel driver when opening and reattach it when the device is closed
+    AutoDetachReattach,
+}
+
+#[cfg(feature = "libftdi1-sys")]
+impl Into<ffi::ftdi_module_detach_mode> for ModuleDetachMode {
+    fn into(self) -> ffi::ftdi_module_detach_mode {
+        match self {
+            ModuleDetachMode::AutoDetach => ffi::ftdi_module_detach_mode::AUTO_DETACH_SIO_MODULE,
+            ModuleDetachMode::DontDetach => ffi::ftdi_module_detach_mode::DONT_DETACH_SIO_MODULE,
+            ModuleDetachMode::AutoDetachReattach => {
+                ffi::ftdi_module_detach_mode::AUTO_DETACH_REATACH_SIO_MODULE
+            }
+        }
+    }
+}
+
 #[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
 pub enum Parity {
     None,
@@ -54,6 +102,7 @@ pub enum Parity {
     Space,
 }
 
+#[cfg(feature = "libftdi1-sys")]
 impl Into<ffi::ftdi_parity_type> for Parity {
     fn into(self) -> ffi::ftdi_parity_type {
         match self {
@@ -72,6 +121,7 @@ pub enum Bits {
     Eight,
 }
 
+#[cfg(feature = "libftdi1-sys")]
 impl Into<ffi::ftdi_bits_type> for Bits {
     fn into(self) -> ffi::ftdi_bits_type {
         match self {
@@ -88,6 +138,7 @@ pub enum StopBits {
     Two,
 }
 
+#[cfg(feature = "libftdi1-sys")]
 impl Into<ffi::ftdi_stopbits_type> for StopBits {
     fn into(self) -> ffi::ftdi_stopbits_type {
         match self {
@@ -107,6 +158,7 @@ pub enum FlowControl {
 }
 
 impl FlowControl {
+    #[cfg(feature = "libftdi1-sys")]
     pub fn to_ffi(self) -> i32 {
         match self {
             FlowControl::Disabled => ffi::SIO_XON_XOFF_HS,
@@ -131,6 +183,7 @@ pub enum BitMode {
 }
 
 impl BitMode {
+    #[cfg(feature = "libftdi1-sys")]
     pub fn to_ffi(self) -> ffi::ftdi_mpsse_mode {
         match self {
             BitMode::Reset => ffi::ftdi_mpsse_mode::BITMODE_RESET,
@@ -146,184 +199,330 @@ impl BitMode {
     }
 }
 
-pub struct Device {
-    context: *mut ffi::ftdi_context,
+/// The FTDI chip family of an opened device
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum ChipType {
+    Am,
+    Bm,
+    Ft2232C,
+    R,
+    Ft2232H,
+    Ft4232H,
+    Ft232H,
+    Ft230X,
+}
+
+impl ChipType {
+    #[cfg(feature = "libftdi1-sys")]
+    fn from_ffi(value: ffi::ftdi_chip_type) -> Option<Self> {
+        match value {
+            ffi::ftdi_chip_type::TYPE_AM => Some(ChipType::Am),
+            ffi::ftdi_chip_type::TYPE_BM => Some(ChipType::Bm),
+            ffi::ftdi_chip_type::TYPE_2232C => Some(ChipType::Ft2232C),
+            ffi::ftdi_chip_type::TYPE_R => Some(ChipType::R),
+            ffi::ftdi_chip_type::TYPE_2232H => Some(ChipType::Ft2232H),
+            ffi::ftdi_chip_type::TYPE_4232H => Some(ChipType::Ft4232H),
+            ffi::ftdi_chip_type::TYPE_232H => Some(ChipType::Ft232H),
+            ffi::ftdi_chip_type::TYPE_230X => Some(ChipType::Ft230X),
+            _ => None,
+        }
+    }
+
+    /// Whether the chip is a high-speed one with the 60 MHz MPSSE clock
+    pub fn is_h_series(self) -> bool {
+        matches!(
+            self,
+            ChipType::Ft2232H | ChipType::Ft4232H | ChipType::Ft232H
+        )
+    }
+}
+
+/// What is known about the identity of an opened device
+///
+/// Used to tell adapters apart in error messages, so every part is optional.
+#[derive(Clone, Debug, Default, PartialEq, Eq, Hash)]
+pub struct DeviceId {
+    pub bus: Option<u8>,
+    pub address: Option<u8>,
+    /// The hub ports the device is plugged into, starting from the root hub
+    pub port_path: Option<Vec<u8>>,
+    pub vid: Option<u16>,
+    pub pid: Option<u16>,
+    pub serial: Option<String>,
+}
+
+impl fmt::Display for DeviceId {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let mut separator = "";
+        if let (Some(vid), Some(pid)) = (self.vid, self.pid) {
+            write!(f, "{:04x}:{:04x}", vid, pid)?;
+            separator = " ";
+        }
+        if let Some(serial) = &self.serial {
+            write!(f, "{}serial {}", separator, serial)?;
+            separator = " ";
+        }
+        if let (Some(bus), Some(address)) = (self.bus, self.address) {
+            write!(f, "{}bus {} address {}", separator, bus, address)?;
+            separator = " ";
+        }
+        if let (Some(bus), Some(ports)) = (self.bus, &self.port_path) {
+            // the notation used by Linux in sysfs, such as 1-2.4
+            write!(f, "{}port {}", separator, bus)?;
+            for (i, port) in ports.iter().enumerate() {
+                write!(f, "{}{}", if i == 0 { '-' } else { '.' }, port)?;
+            }
+            separator = " ";
+        }
+        if separator.is_empty() {
+            f.write_str("unknown device")?;
+        }
+        Ok(())
+    }
 }
 
+/// The modem and line status bits reported by the chip
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub struct ModemStatus(pub u16);
+
+impl ModemStatus {
+    fn bit(self, bit: u16) -> bool {
+        self.0 & (1 << bit) != 0
+    }
+
+    /// Clear To Send
+    pub fn cts(self) -> bool {
+        self.bit(4)
+    }
+
+    /// Data Set Ready
+    pub fn dsr(self) -> bool {
+        self.bit(5)
+    }
+
+    /// Ring Indicator
+    pub fn ri(self) -> bool {
+        self.bit(6)
+    }
+
+    /// Data Carrier Detect
+    pub fn dcd(self) -> bool {
+        self.bit(7)
+    }
+
+    pub fn data_ready(self) -> bool {
+        self.bit(8)
+    }
+
+    pub fn overrun_error(self) -> bool {
+        self.bit(9)
+    }
+
+    pub fn parity_error(self) -> bool {
+        self.bit(10)
+    }
+
+    pub fn framing_error(self) -> bool {
+        self.bit(11)
+    }
+
+    pub fn break_interrupt(self) -> bool {
+        self.bit(12)
+    }
+
+    pub fn transmitter_holding_register_empty(self) -> bool {
+        self.bit(13)
+    }
+
+    pub fn transmitter_empty(self) -> bool {
+        self.bit(14)
+    }
+
+    pub fn receiver_fifo_error(self) -> bool {
+        self.bit(15)
+    }
+}
+
+#[cfg(feature = "libftdi1-sys")]
+pub struct Device<B = LibFtdi> {
+    backend: B,
+    mpsse_three_phase: bool,
+    stats: Stats,
+    transfers_baseline: Transfers,
+}
+
+#[cfg(not(feature = "libftdi1-sys"))]
+pub struct Device<B> {
+    backend: B,
+    mpsse_three_phase: bool,
+    stats: Stats,
+    transfers_baseline: Transfers,
+}
+
+#[cfg(feature = "libftdi1-sys")]
 impl Device {
-    pub fn set_baud_rate(&mut self, rate: u32) -> Result<()> {
-        let rate = rate.try_into().expect("baud rate should fit in an i32");
-        let result = unsafe { ffi::ftdi_set_baudrate(self.context, rate) };
-        match result {
-            0 => Ok(()),
-            -1 => Err(Error::InvalidInput("unsupported baudrate")),
-            -2 => Err(Error::RequestFailed),
-            -3 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
+    pub(crate) fn from_context(context: *mut ffi::ftdi_context, device: DeviceId) -> Self {
+        Self::with_backend(LibFtdi::from_context(context, device))
+    }
+
+    pub fn libftdi_context(&mut self) -> *mut ffi::ftdi_context {
+        self.backend.context()
+    }
+}
+
+impl<B: Backend> Device<B> {
+    /// Wraps a device accessed through a custom backend
+    pub fn with_backend(backend: B) -> Self {
+        Device {
+            backend,
+            mpsse_three_phase: false,
+            stats: Stats::default(),
+            transfers_baseline: Transfers::default(),
         }
     }
 
-    pub fn configure(&mut self, bits: Bits, stop_bits: StopBits, parity: Parity) -> Result<()> {
-        let result = unsafe {
-            ffi::ftdi_set_line_property(self.context, bits.into(), stop_bits.into(), parity.into())
-        };
-        match result {
-            0 => Ok(()),
-            -1 => Err(Error::RequestFailed),
-            -2 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
+    pub fn backend(&self) -> &B {
+        &self.backend
+    }
+
+    pub fn backend_mut(&mut self) -> &mut B {
+        &mut self.backend
+    }
+
+    pub fn into_backend(self) -> B {
+        self.backend
+    }
+
+    /// The chip type detected when opening the device
+    pub fn chip_type(&self) -> Option<ChipType> {
+        self.backend.chip_type()
+    }
+
+    /// The identity of the device, if known to the backend
+    pub fn device_id(&self) -> Option<&DeviceId> {
+        self.backend.device_id()
+    }
+
+    /// A snapshot of the I/O statistics since opening or the last reset
+    pub fn stats(&self) -> Stats {
+        Stats {
+            transfers: self
+                .backend
+                .transfers()
+                .map(|transfers| transfers.since(self.transfers_baseline)),
+            ..self.stats.clone()
         }
     }
 
+    pub fn reset_stats(&mut self) {
+        self.stats = Stats::default();
+        self.transfers_baseline = self.backend.transfers().unwrap_or_default();
+    }
+
+    /// Runs a backend call, traced when the `tracing` feature is enabled
+    fn call<T>(
+        &mut self,
+        operation: &'static str,
+        args: impl fmt::Debug,
+        f: impl FnOnce(&mut B) -> Result<T>,
+    ) -> Result<T> {
+        let span = trace::Span::enter(self.backend.device_id(), operation);
+        let result = f(&mut self.backend);
+        span.finish(args, result)
+    }
+
+    pub fn set_baud_rate(&mut self, rate: u32) -> Result<()> {
+        self.call("set_baud_rate", rate, |backend| backend.set_baud_rate(rate))
+    }
+
+    pub fn configure(&mut self, bits: Bits, stop_bits: StopBits, parity: Parity) -> Result<()> {
+        self.call("set_line_property", (bits, stop_bits, parity), |backend| {
+            backend.set_line_property(bits, stop_bits, parity)
+        })
+    }
+
     pub fn usb_reset(&mut self) -> Result<()> {
-        let result = unsafe { ffi::ftdi_usb_reset(self.context) };
-        match result {
-            0 => Ok(()),
-            -1 => Err(Error::RequestFailed),
-            -2 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
-        }
+        self.call("usb_reset", (), |backend| backend.usb_reset())
     }
 
     pub fn usb_purge_buffers(&mut self) -> Result<()> {
-        let result = unsafe { ffi::ftdi_usb_purge_buffers(self.context) };
-        match result {
-            0 => Ok(()),
-            -1 /* read */ | -2 /* write */ => Err(Error::RequestFailed),
-            -3 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
-        }
+        self.call("usb_purge_buffers", (), |backend| {
+            backend.usb_purge_buffers()
+        })
     }
 
     pub fn usb_purge_tx_buffer(&mut self) -> Result<()> {
-        let result = unsafe { ffi::ftdi_usb_purge_tx_buffer(self.context) };
-        match result {
-            0 => Ok(()),
-            -1 => Err(Error::RequestFailed),
-            -2 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
-        }
+        self.call("usb_purge_tx_buffer", (), |backend| {
+            backend.usb_purge_tx_buffer()
+        })
     }
 
     pub fn usb_set_event_char(&mut self, value: Option<u8>) -> Result<()> {
-        let result = if let Some(v) = value {
-            unsafe { ffi::ftdi_set_event_char(self.context, v, 1) }
-        } else {
-            unsafe { ffi::ftdi_set_event_char(self.context, 0, 0) }
-        };
-
-        match result {
-            0 => Ok(()),
-            -1 => Err(Error::RequestFailed),
-            -2 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
-        }
+        self.call("set_event_char", value, |backend| {
+            backend.set_event_char(value)
+        })
     }
 
     pub fn usb_set_error_char(&mut self, value: Option<u8>) -> Result<()> {
-        let result = if let Some(v) = value {
-            unsafe { ffi::ftdi_set_error_char(self.context, v, 1) }
-        } else {
-            unsafe { ffi::ftdi_set_error_char(self.context, 0, 0) }
-        };
-
-        match result {
-            0 => Ok(()),
-            -1 => Err(Error::RequestFailed),
-            -2 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
-        }
+        self.call("set_error_char", value, |backend| {
+            backend.set_error_char(value)
+        })
     }
 
     pub fn set_latency_timer(&mut self, value: u8) -> Result<()> {
-        let result = unsafe { ffi::ftdi_set_latency_timer(self.context, value) };
-        match result {
-            0 => Ok(()),
-            -1 => Err(Error::InvalidInput("latency value out of range")),
-            -2 => Err(Error::RequestFailed),
-            -3 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
-        }
+        self.call("set_latency_timer", value, |backend| {
+            backend.set_latency_timer(value)
+        })
     }
 
     pub fn latency_timer(&mut self) -> Result<u8> {
-        let mut value = 0u8;
-        let result = unsafe { ffi::ftdi_get_latency_timer(self.context, &mut value) };
-        match result {
-            0 => Ok(value),
-            -1 => Err(Error::RequestFailed),
-            -2 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
-        }
+        self.call("latency_timer", (), |backend| backend.latency_timer())
     }
 
-    pub fn set_write_chunksize(&mut self, value: u32) {
-        let result = unsafe { ffi::ftdi_write_data_set_chunksize(self.context, value) };
-        match result {
-            0 => (),
-            -1 => unreachable!("uninitialized context"),
-            err => panic!("unknown set_write_chunksize retval {:?}", err),
-        }
+    pub fn set_write_chunksize(&mut self, value: u32) -> Result<()> {
+        self.call("set_write_chunksize", value, |backend| {
+            backend.set_write_chunksize(value)
+        })
     }
 
-    pub fn write_chunksize(&mut self) -> u32 {
-        let mut value = 0;
-        let result = unsafe { ffi::ftdi_write_data_get_chunksize(self.context, &mut value) };
-        match result {
-            0 => value,
-            -1 => unreachable!("uninitialized context"),
-            err => panic!("unknown get_write_chunksize retval {:?}", err),
-        }
+    pub fn write_chunksize(&mut self) -> Result<u32> {
+        self.call("write_chunksize", (), |backend| backend.write_chunksize())
     }
 
-    pub fn set_read_chunksize(&mut self, value: u32) {
-        let result = unsafe { ffi::ftdi_read_data_set_chunksize(self.context, value) };
-        match result {
-            0 => (),
-            -1 => unreachable!("uninitialized context"),
-            err => panic!("unknown set_write_chunksize retval {:?}", err),
-        }
+    pub fn set_read_chunksize(&mut self, value: u32) -> Result<()> {
+        self.call("set_read_chunksize", value, |backend| {
+            backend.set_read_chunksize(value)
+        })
     }
 
-    pub fn read_chunksize(&mut self) -> u32 {
-        let mut value = 0;
-        let result = unsafe { ffi::ftdi_read_data_get_chunksize(self.context, &mut value) };
-        match result {
-            0 => value,
-            -1 => unreachable!("uninitialized context"),
-            err => panic!("unknown get_write_chunksize retval {:?}", err),
-        }
+    pub fn read_chunksize(&mut self) -> Result<u32> {
+        self.call("read_chunksize", (), |backend| backend.read_chunksize())
     }
 
     pub fn set_flow_control(&mut self, flowctrl: FlowControl) -> Result<()> {
-        let result = unsafe { ffi::ftdi_setflowctrl(self.context, flowctrl.to_ffi()) };
-        match result {
-            0 => Ok(()),
-            -1 => Err(Error::RequestFailed),
-            -2 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
-        }
+        self.call("set_flow_control", flowctrl, |backend| {
+            backend.set_flow_control(flowctrl)
+        })
     }
 
     pub fn set_bitmode(&mut self, output_mask: u8, mode: BitMode) -> Result<()> {
-        let mode = mode.to_ffi().0.try_into().unwrap();
-        let result = unsafe { ffi::ftdi_set_bitmode(self.context, output_mask, mode) };
-        match result {
-            0 => Ok(()),
-            -1 => Err(Error::RequestFailed),
-            -2 => unreachable!("uninitialized context"),
-            _ => Err(Error::unknown(self.context)),
+        self.call("set_bitmode", (output_mask, mode), |backend| {
+            backend.set_bitmode(output_mask, mode)
+        })?;
+        // Resetting or entering MPSSE mode disables three-phase clocking
+        if let BitMode::Reset | BitMode::Mpsse = mode {
+            self.mpsse_three_phase = false;
         }
+        Ok(())
     }
 
-    pub fn libftdi_context(&mut self) -> *mut ffi::ftdi_context {
-        self.context
+    /// Polls the modem and line status of the chip
+    pub fn modem_status(&mut self) -> Result<ModemStatus> {
+        self.call("modem_status", (), |backend| backend.modem_status())
     }
 
     pub fn readbuffer_remaining(&self) -> usize {
-        unsafe { (*self.context).readbuffer_remaining }
-            .try_into()
-            .expect("should be nonnegative")
+        self.backend.readbuffer_remaining()
     }
 
     pub fn read_packet(&mut self, buf: &mut [u8]) -> io::Result<usize> {
@@ -340,42 +539,21 @@ impl Device {
     }
 }
 
-impl Drop for Device {
-    fn drop(&mut self) {
-        let result = unsafe { ffi::ftdi_usb_close(self.context) };
-        match result {
-            0 => {}
-            -1 => { /* TODO emit warning ("usb_release failed") */ }
-            -3 => unreachable!("uninitialized context"),
-            _ => panic!("undocumented ftdi_usb_close return value"),
-        };
-        unsafe {
-            ffi::ftdi_free(self.context);
-        }
-    }
-}
-
-impl Read for Device {
+impl<B: Backend> Read for Device<B> {
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        let len = buf.len().try_into().unwrap_or(std::i32::MAX);
-        let result = unsafe { ffi::ftdi_read_data(self.context, buf.as_mut_ptr(), len) };
-        match result {
-            count if count >= 0 => Ok(count as usize),
-            -666 => unreachable!("uninitialized context"),
-            err => Err(libusb_to_io(err)),
-        }
+        let started = Instant::now();
+        let result = trace::read(&mut self.backend, buf);
+        self.stats.record_read(&result, started.elapsed());
+        result
     }
 }
 
-impl Write for Device {
+impl<B: Backend> Write for Device<B> {
     fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
-        let len = buf.len().try_into().unwrap_or(std::i32::MAX);
-        let result = unsafe { ffi::ftdi_write_data(self.context, buf.as_ptr(), len) };
-        match result {
-            count if count >= 0 => Ok(count as usize),
-            -666 => unreachable!("uninitialized context"),
-            err => Err(libusb_to_io(err)),
-        }
+        let started = Instant::now();
+        let result = trace::write(&mut self.backend, buf);
+        self.stats.record_write(&result, started.elapsed());
+        result
     }
 
     fn flush(&mut self) -> io::Result<()> {
@@ -383,37 +561,97 @@ impl Write for Device {
     }
 }
 
-impl Device {
-    pub fn set_mpsse_clock(&mut self, freq: u32) -> std::result::Result<(), io::Error> {
-        const MAX: u32 = 30_000_000;
-        const MIN: u32 = 92;
-
-        assert!(
-            freq >= MIN,
-            "frequency of {} exceeds minimum of {}",
-            freq,
-            MIN
-        );
-        assert!(
-            freq <= MAX,
-            "frequency of {} exceeds maximum of {}",
-            freq,
-            MAX
-        );
-
-        let (divisor, clkdiv) = if freq <= 6_000_000 {
-            (6_000_000 / freq - 1, Some(true))
+impl<B: Backend> Device<B> {
+    /// Configures the MPSSE clock, returning the achieved frequency
+    ///
+    /// The divisor is chosen for the detected chip so that the achieved frequency
+    /// is as close as possible to the requested one without exceeding it. H-series
+    /// chips can use the 60 MHz base clock (up to 30 MHz), older ones are limited to
+    /// 6 MHz. With three-phase clocking enabled the frequency is 2/3 of the normal one.
+    /// Frequencies out of the range of the chip are reported as `InvalidInput`.
+    pub fn set_mpsse_clock(&mut self, freq: u32) -> Result<u32> {
+        // (TCK with divisor 0, divide-by-5 command)
+        let bases: &[(u32, Option<bool>)] = if self.is_h_series() {
+            &[(30_000_000, Some(false)), (6_000_000, Some(true))]
         } else {
-            (30_000_000 / freq - 1, Some(false))
+            &[(6_000_000, None)]
         };
+        let three_phase = self.mpsse_three_phase;
+        let effective = |base: u32| if three_phase { base / 3 * 2 } else { base };
+        if freq == 0 || freq > effective(bases[0].0) {
+            return Err(Error::InvalidInput("clock frequency out of range"));
+        }
+
+        let mut best: Option<(u32, Option<bool>, u32)> = None;
+        for &(base, clkdiv) in bases {
+            let base = effective(base);
+            let divisor = base.div_ceil(freq) - 1;
+            if divisor > 0xffff {
+                continue;
+            }
+            let achieved = base / (divisor + 1);
+            let better = match best {
+                Some((_, _, best)) => achieved > best,
+                None => true,
+            };
+            if achieved <= freq && better {
+                best = Some((divisor, clkdiv, achieved));
+            }
+        }
+        let (divisor, clkdiv, achieved) =
+            best.ok_or(Error::InvalidInput("clock frequency out of range"))?;
 
         self.write_all(MpsseCmdBuilder::new().set_clock(divisor, clkdiv).as_slice())?;
 
+        Ok(achieved)
+    }
+
+    /// Enables or disables three-phase data clocking (H-series chips only)
+    ///
+    /// Three-phase clocking keeps the data valid on both clock edges, as needed by I2C,
+    /// at the cost of the clock frequency. Set it before [`set_mpsse_clock`](Self::set_mpsse_clock).
+    /// Resetting the bit mode or entering MPSSE mode disables it again.
+    pub fn set_mpsse_three_phase_clocking(&mut self, enabled: bool) -> Result<()> {
+        if !self.is_h_series() {
+            return Err(Error::InvalidInput(
+                "three-phase clocking requires an H-series chip",
+            ));
+        }
+        let cmd = if enabled {
+            MpsseCmdBuilder::new().enable_3phase_data_clocking()
+        } else {
+            MpsseCmdBuilder::new().disable_3phase_data_clocking()
+        };
+        self.write_all(cmd.as_slice())?;
+        self.mpsse_three_phase = enabled;
         Ok(())
     }
+
+    /// Enables or disables adaptive clocking (H-series chips only)
+    ///
+    /// With adaptive clocking the MPSSE waits for the RTCK signal on GPIOL3
+    /// (ADBUS7) to follow TCK, as required by some ARM JTAG targets.
+    pub fn set_mpsse_adaptive_clocking(&mut self, enabled: bool) -> Result<()> {
+        if !self.is_h_series() {
+            return Err(Error::InvalidInput(
+                "adaptive clocking requires an H-series chip",
+            ));
+        }
+        let cmd = if enabled {
+            MpsseCmdBuilder::new().enable_adaptive_data_clocking()
+        } else {
+            MpsseCmdBuilder::new().disable_adaptive_data_clocking()
+        };
+        self.write_all(cmd.as_slice())?;
+        Ok(())
+    }
+
+    fn is_h_series(&self) -> bool {
+        self.chip_type().is_some_and(ChipType::is_h_series)
+    }
 }
 
-impl MpsseCmdExecutor for Device {
+impl<B: Backend> MpsseCmdExecutor for Device<B> {
     type Error = io::Error;
 
     /// Initialize the MPSSE controller.
@@ -429,6 +667,7 @@ impl MpsseCmdExecutor for Device {
     ///    IN requests if the buffer is unable to accept data.
     /// 7. Resets and then enables the MPSSE controller
     /// 8. Optionally configures the MPSSE clock frequency.
+    /// 9. Synchronizes with the MPSSE command processor, see [`Device::mpsse_sync`].
     ///
     /// [FTDI MPSSE Basics]: https://www.ftdichip.com/Support/Documents/AppNotes/AN_135_MPSSE_Basics.pdf
     fn init(&mut self, settings: &MpsseSettings) -> std::result::Result<(), io::Error> {
@@ -440,8 +679,10 @@ impl MpsseCmdExecutor for Device {
         }
 
         self.usb_purge_buffers().map_err(libftdi_to_io)?;
-        self.set_write_chunksize(settings.in_transfer_size);
-        self.set_read_chunksize(settings.in_transfer_size);
+        self.set_write_chunksize(settings.in_transfer_size)
+            .map_err(libftdi_to_io)?;
+        self.set_read_chunksize(settings.in_transfer_size)
+            .map_err(libftdi_to_io)?;
         self.set_latency_timer(millis).map_err(libftdi_to_io)?;
         self.usb_set_event_char(None).map_err(libftdi_to_io)?;
         self.usb_set_error_char(None).map_err(libftdi_to_io)?;
@@ -452,37 +693,152 @@ impl MpsseCmdExecutor for Device {
             .map_err(libftdi_to_io)?;
 
         if let Some(frequency) = settings.clock_frequency {
-            self.set_mpsse_clock(frequency)?;
+            self.set_mpsse_clock(frequency).map_err(libftdi_to_io)?;
         }
 
-        Ok(())
+        self.mpsse_sync().map_err(libftdi_to_io)
     }
 
     /// Write the MPSSE command to the device.
-    ///
-    /// Workaround: perform Tx flush before sending data.
-    /// Otherwise several first readings from FTDI chip in the exchange sequence may be broken.
-    /// E.g. reading during the first exchange returns nothing, but reading during the second
-    /// exchange returns both the first and second replies. So far it is not clear how to get
-    /// rid of this workaround. Note that such a workaround is not needed when FTDI proprietary
-    /// libftd2xx library is used.
     fn send(&mut self, data: &[u8]) -> std::result::Result<(), io::Error> {
-        self.usb_purge_tx_buffer()
-            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))?;
-        self.write_all(data)
+        let span = trace::Span::enter(self.device_id(), "mpsse_send");
+        let result = self.write_all(data);
+        span.finish(data.len(), result)
     }
 
     /// Read the MPSSE response from the device.
+    ///
+    /// An invalid opcode makes the MPSSE insert a two-byte "bad command" response
+    /// (`0xFA` followed by the opcode) into the stream. To tell such responses
+    /// apart from data, the bogus opcode `0xAA` is sent as a sentinel, followed by
+    /// "send immediate" (`0x87`), and the response is read up to its echo. Any extra data before it is reported as
+    /// [`Error::MpsseBadCommand`] wrapped into an `io::Error` of kind `InvalidData`.
     fn recv(&mut self, data: &mut [u8]) -> std::result::Result<(), io::Error> {
-        self.read_exact(data)
+        let len = data.len();
+        let span = trace::Span::enter(self.device_id(), "mpsse_recv");
+        let result = self
+            .write_all(&MPSSE_END_OF_RESPONSE)
+            .and_then(|()| self.recv_response(data));
+        span.finish(len, result)
+    }
+
+    /// Execute the MPSSE command and read its response.
+    ///
+    /// Unlike separate [`send`](Self::send) and [`recv`](Self::recv) calls, the
+    /// sentinel is appended to the command so the transaction takes a single write.
+    fn xfer(&mut self, txdata: &[u8], rxdata: &mut [u8]) -> std::result::Result<(), io::Error> {
+        let mut command = Vec::with_capacity(txdata.len() + MPSSE_END_OF_RESPONSE.len());
+        command.extend_from_slice(txdata);
+        command.extend_from_slice(&MPSSE_END_OF_RESPONSE);
+
+        let span = trace::Span::enter(self.device_id(), "mpsse_send");
+        let result = self.write_all(&command);
+        span.finish(command.len(), result)?;
+
+        let len = rxdata.len();
+        let span = trace::Span::enter(self.device_id(), "mpsse_recv");
+        let result = self.recv_response(rxdata);
+        span.finish(len, result)
     }
 }
 
+/// The first byte of the MPSSE response to an invalid opcode
+const MPSSE_BAD_COMMAND: u8 = 0xfa;
+
+/// A bogus opcode whose echo marks the end of a response
+const MPSSE_SENTINEL: u8 = 0xaa;
+
+/// The sentinel followed by "send immediate", so its echo is not held back until
+/// the latency timer expires
+const MPSSE_END_OF_RESPONSE: [u8; 2] = [MPSSE_SENTINEL, 0x87];
+
+/// How long to wait for more of an MPSSE response before giving up
+const MPSSE_TIMEOUT: Duration = Duration::from_secs(1);
+
+impl<B: Backend> Device<B> {
+    /// Reads the response to a command followed by [`MPSSE_END_OF_RESPONSE`]
+    fn recv_response(&mut self, data: &mut [u8]) -> io::Result<()> {
+        let expected = data.len() + 2;
+        let end = [MPSSE_BAD_COMMAND, MPSSE_SENTINEL];
+        let mut stream = Vec::with_capacity(expected);
+        let complete = self.read_until(&mut stream, |stream| {
+            stream.len() >= expected && stream.ends_with(&end)
+        })?;
+        if !complete {
+            let error = Error::MpsseSyncFailed;
+            return Err(io::Error::new(io::ErrorKind::TimedOut, error));
+        }
+        if stream.len() == expected {
+            data.copy_from_slice(&stream[..data.len()]);
+            return Ok(());
+        }
+
+        let response = &stream[..stream.len() - end.len()];
+        let error = match response
+            .windows(2)
+            .find(|pair| pair[0] == MPSSE_BAD_COMMAND)
+        {
+            Some(pair) => Error::MpsseBadCommand(pair[1]),
+            None => Error::MpsseSyncFailed,
+        };
+        Err(io::Error::new(io::ErrorKind::InvalidData, error))
+    }
+
+    /// Reads into `stream` until `done` holds, returning false if no data arrived
+    /// for [`MPSSE_TIMEOUT`]
+    ///
+    /// Empty reads are retried with an increasing delay, up to the 16 ms of the
+    /// default latency timer.
+    fn read_until(
+        &mut self,
+        stream: &mut Vec<u8>,
+        done: impl Fn(&[u8]) -> bool,
+    ) -> io::Result<bool> {
+        let mut buf = [0u8; 512];
+        let mut deadline = Instant::now() + MPSSE_TIMEOUT;
+        let mut delay = Duration::from_micros(250);
+        while !done(stream) {
+            let len = self.read(&mut buf)?;
+            if len > 0 {
+                stream.extend_from_slice(&buf[..len]);
+                deadline = Instant::now() + MPSSE_TIMEOUT;
+                delay = Duration::from_micros(250);
+            } else if Instant::now() > deadline {
+                return Ok(false);
+            } else {
+                thread::sleep(delay);
+                delay = (delay * 2).min(Duration::from_millis(16));
+            }
+        }
+        Ok(true)
+    }
+
+    /// Synchronizes with the MPSSE command processor as described in AN135
+    ///
+    /// Sends the bogus opcodes `0xAA` and `0xAB` and waits for their "bad command"
+    /// echoes, discarding any stale data received before them.
+    pub fn mpsse_sync(&mut self) -> Result<()> {
+        for &opcode in &[0xaa, 0xab] {
+            self.write_all(&[opcode])?;
+
+            let mut stream = Vec::new();
+            let echo = [MPSSE_BAD_COMMAND, opcode];
+            if !self.read_until(&mut stream, |stream| stream.ends_with(&echo))? {
+                return Err(Error::MpsseSyncFailed);
+            }
+        }
+        Ok(())
+    }
+}
+
+#[cfg(feature = "libftdi1-sys")]
 pub struct AsyncWrite {
     data: Vec<u8>,
     control: *mut ffi::ftdi_transfer_control,
+    device: Option<DeviceId>,
 }
 
+#[cfg(feature = "libftdi1-sys")]
 impl AsyncWrite {
     pub fn is_cancelled(&self) -> bool {
         let status = self.completed();
@@ -500,14 +856,18 @@ impl AsyncWrite {
     pub fn wait(mut self) -> io::Result<usize> {
         let result = unsafe { ffi::ftdi_transfer_data_done(self.control) };
         self.data = vec![];
+        let device = self.device.take();
         std::mem::forget(self);
-        match result {
+        let result = match result {
             count if count >= 0 => Ok(count as usize),
             err => Err(error::libusb_to_io(err)),
-        }
+        };
+        trace::async_completed(device.as_ref(), &result);
+        result
     }
 }
 
+#[cfg(feature = "libftdi1-sys")]
 impl Drop for AsyncWrite {
     fn drop(&mut self) {
         unsafe {
@@ -517,18 +877,144 @@ impl Drop for AsyncWrite {
     }
 }
 
+#[cfg(feature = "libftdi1-sys")]
 impl Device {
     pub fn write_async(&mut self, buf: &[u8]) -> io::Result<AsyncWrite> {
         let mut data = buf.to_owned();
         let len = data.len().try_into().unwrap_or(std::i32::MAX);
-        let control = unsafe { ffi::ftdi_write_data_submit(self.context, data.as_mut_ptr(), len) };
+        let control =
+            unsafe { ffi::ftdi_write_data_submit(self.libftdi_context(), data.as_mut_ptr(), len) };
+        trace::async_submitted(self.device_id(), &data, !control.is_null());
         if control.is_null() {
             Err(io::Error::new(
                 io::ErrorKind::Other,
                 "unknown libftdi or libusb error",
             ))
         } else {
-            Ok(AsyncWrite { control, data })
+            Ok(AsyncWrite {
+                control,
+                data,
+                device: self.device_id().cloned(),
+            })
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::backend::mock::{Call, Mock, Reply};
+
+    fn bad_command(err: io::Error) -> Option<u8> {
+        match *err.into_inner()?.downcast::<Error>().ok()? {
+            Error::MpsseBadCommand(opcode) => Some(opcode),
+            _ => None,
+        }
+    }
+
+    #[test]
+    fn mpsse_response_is_read_up_to_the_sentinel() {
+        let mut mock = Mock::new();
+        mock.expect_write(&[0x24, 0x01, 0x00])
+            .expect_write(&[0xaa, 0x87])
+            .expect_read(&[0x12])
+            .expect_read(&[])
+            .expect_read(&[0xfa, 0xfa, 0xaa]);
+        let mut device = Device::with_backend(mock);
+        device.send(&[0x24, 0x01, 0x00]).unwrap();
+        let mut data = [0; 2];
+        device.recv(&mut data).unwrap();
+        assert_eq!(data, [0x12, 0xfa]);
+        device.backend().assert_done();
+    }
+
+    #[test]
+    fn xfer_writes_the_command_and_sentinel_at_once() {
+        let mut mock = Mock::new();
+        mock.expect_write(&[0x24, 0x01, 0x00, 0xaa, 0x87])
+            .expect_read(&[0x12, 0x34, 0xfa, 0xaa]);
+        let mut device = Device::with_backend(mock);
+        let mut data = [0; 2];
+        device.xfer(&[0x24, 0x01, 0x00], &mut data).unwrap();
+        assert_eq!(data, [0x12, 0x34]);
+        device.backend().assert_done();
+    }
+
+    #[test]
+    fn transfers_are_counted_since_the_last_reset() {
+        let mut mock = Mock::new();
+        mock.expect_write(&[0x87])
+            .expect_read(&[0x01, 0x02])
+            .expect_write(&[0x87])
+            .expect_read(&[]);
+        let mut device = Device::with_backend(mock);
+        let mut buf = [0; 1];
+        device.write_all(&[0x87]).unwrap();
+        device.read_exact(&mut buf).unwrap();
+        device.reset_stats();
+        device.write_all(&[0x87]).unwrap();
+        device.read_exact(&mut buf).unwrap();
+        assert_eq!(device.read(&mut buf).unwrap(), 0);
+
+        let stats = device.stats();
+        assert_eq!(stats.read_calls, 2);
+        assert_eq!(stats.transfers, Some(Transfers { read: 1, write: 1 }));
+        device.backend().assert_done();
+    }
+
+    #[test]
+    fn late_bad_command_echo_is_reported() {
+        let mut mock = Mock::new();
+        mock.expect_write(&[0xab])
+            .expect_write(&[0xaa, 0x87])
+            .expect_read(&[])
+            .expect_read(&[0xfa])
+            .expect_read(&[0xab, 0xfa, 0xaa]);
+        let mut device = Device::with_backend(mock);
+        device.send(&[0xab]).unwrap();
+        let err = device.recv(&mut []).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidData);
+        assert_eq!(bad_command(err), Some(0xab));
+        device.backend().assert_done();
+    }
+
+    #[test]
+    fn mpsse_clock_out_of_range_is_rejected() {
+        let mock = Mock::new().with_chip_type(ChipType::Ft232H);
+        let mut device = Device::with_backend(mock);
+        for &freq in &[0, 30_000_001, 100_000_000] {
+            match device.set_mpsse_clock(freq) {
+                Err(Error::InvalidInput(_)) => {}
+                result => panic!("{} Hz: unexpected {:?}", freq, result),
+            }
         }
+        device.backend().assert_done();
+    }
+
+    #[test]
+    fn entering_mpsse_mode_disables_three_phase_clocking() {
+        let mut mock = Mock::new().with_chip_type(ChipType::Ft232H);
+        mock.expect_write(&[0x8c])
+            .expect(Call::SetBitmode(0x0b, BitMode::Mpsse), Reply::Done)
+            .expect_write(&[0x8a, 0x86, 0x00, 0x00]);
+        let mut device = Device::with_backend(mock);
+        device.set_mpsse_three_phase_clocking(true).unwrap();
+        assert!(device.set_mpsse_clock(30_000_000).is_err());
+        device.set_bitmode(0x0b, BitMode::Mpsse).unwrap();
+        assert_eq!(device.set_mpsse_clock(30_000_000).unwrap(), 30_000_000);
+        device.backend().assert_done();
+    }
+
+    #[test]
+    fn mpsse_sync_discards_stale_data() {
+        let mut mock = Mock::new();
+        mock.expect_write(&[0xaa])
+            .expect_read(&[0x01, 0x02, 0xfa])
+            .expect_read(&[0xaa])
+            .expect_write(&[0xab])
+            .expect_read(&[0xfa, 0xab]);
+        let mut device = Device::with_backend(mock);
+        device.mpsse_sync().unwrap();
+        device.backend().assert_done();
     }
 }
@@ -0,0 +1,787 @@
+//! Disassembler for MPSSE command streams
+//!
+//! Decodes the bytes produced by [`MpsseCmdBuilder`](ftdi_mpsse::MpsseCmdBuilder)
+//! or [`Transaction`](super::Transaction) back into commands, following the
+//! opcode tables of FTDI AN108.
+//!
+//! # Example
+//!
+//! ```
+//! use ftdi::mpsse::disasm;
+//!
+//! for command in disasm::disassemble(&[0x80, 0x08, 0x0b, 0x19, 0x01, 0x00, 0x9f, 0x00]).unwrap() {
+//!     println!("{}", command);
+//! }
+//! ```
+
+use std::fmt;
+
+use thiserror::Error;
+
+/// A clock edge data is written or sampled on
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Edge {
+    Pos,
+    Neg,
+}
+
+impl Edge {
+    fn from_bit(bit: bool) -> Self {
+        if bit {
+            Edge::Neg
+        } else {
+            Edge::Pos
+        }
+    }
+}
+
+impl fmt::Display for Edge {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Edge::Pos => f.write_str("+ve"),
+            Edge::Neg => f.write_str("-ve"),
+        }
+    }
+}
+
+/// The order bits of a byte are clocked in
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum BitOrder {
+    Msb,
+    Lsb,
+}
+
+impl fmt::Display for BitOrder {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            BitOrder::Msb => f.write_str("MSB first"),
+            BitOrder::Lsb => f.write_str("LSB first"),
+        }
+    }
+}
+
+/// A single decoded MPSSE command
+///
+/// Byte counts are the actual number of bytes clocked, bit counts are in
+/// `1..=8` (`1..=7` for TMS commands).
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction<'a> {
+    ClockDataOut {
+        order: BitOrder,
+        edge: Edge,
+        data: &'a [u8],
+    },
+    ClockDataIn {
+        order: BitOrder,
+        edge: Edge,
+        len: usize,
+    },
+    ClockData {
+        order: BitOrder,
+        out_edge: Edge,
+        in_edge: Edge,
+        data: &'a [u8],
+    },
+    ClockBitsOut {
+        order: BitOrder,
+        edge: Edge,
+        data: u8,
+        len: u8,
+    },
+    ClockBitsIn {
+        order: BitOrder,
+        edge: Edge,
+        len: u8,
+    },
+    ClockBits {
+        order: BitOrder,
+        out_edge: Edge,
+        in_edge: Edge,
+        data: u8,
+        len: u8,
+    },
+    ClockTmsOut {
+        edge: Edge,
+        data: u8,
+        tdi: bool,
+        len: u8,
+    },
+    ClockTms {
+        out_edge: Edge,
+        in_edge: Edge,
+        data: u8,
+        tdi: bool,
+        len: u8,
+    },
+    SetGpioLower {
+        value: u8,
+        direction: u8,
+    },
+    SetGpioUpper {
+        value: u8,
+        direction: u8,
+    },
+    GpioLower,
+    GpioUpper,
+    EnableLoopback,
+    DisableLoopback,
+    SetClockDivisor(u16),
+    SendImmediate,
+    WaitOnIoHigh,
+    WaitOnIoLow,
+    DisableClockDivide,
+    EnableClockDivide,
+    EnableThreePhaseClocking,
+    DisableThreePhaseClocking,
+    ClockBitsNoData(u8),
+    ClockBytesNoData(usize),
+    ClockUntilIoHigh,
+    ClockUntilIoLow,
+    EnableAdaptiveClocking,
+    DisableAdaptiveClocking,
+    ClockBytesUntilIoHigh(usize),
+    ClockBytesUntilIoLow(usize),
+    DriveOnlyZero {
+        lower: u8,
+        upper: u8,
+    },
+    CpuReadShort {
+        address: u8,
+    },
+    CpuReadExtended {
+        address: u16,
+    },
+    CpuWriteShort {
+        address: u8,
+        data: u8,
+    },
+    CpuWriteExtended {
+        address: u16,
+        data: u8,
+    },
+}
+
+impl Instruction<'_> {
+    /// Number of response bytes the command produces
+    pub fn response_len(&self) -> usize {
+        match self {
+            Instruction::ClockDataIn { len, .. } => *len,
+            Instruction::ClockData { data, .. } => data.len(),
+            Instruction::ClockBitsIn { .. }
+            | Instruction::ClockBits { .. }
+            | Instruction::ClockTms { .. }
+            | Instruction::GpioLower
+            | Instruction::GpioUpper
+            | Instruction::CpuReadShort { .. }
+            | Instruction::CpuReadExtended { .. } => 1,
+            _ => 0,
+        }
+    }
+}
+
+struct Hex<'a>(&'a [u8]);
+
+impl fmt::Display for Hex<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        for (i, byte) in self.0.iter().enumerate() {
+            if i > 0 {
+                f.write_str(" ")?;
+            }
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+impl fmt::Display for Instruction<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Instruction::ClockDataOut { order, edge, data } => write!(
+                f,
+                "clock {} bytes out, {}, {}: {}",
+                data.len(),
+                order,
+                edge,
+                Hex(data)
+            ),
+            Instruction::ClockDataIn { order, edge, len } => {
+                write!(f, "clock {} bytes in, {}, {}", len, order, edge)
+            }
+            Instruction::ClockData {
+                order,
+                out_edge,
+                in_edge,
+                data,
+            } => write!(
+                f,
+                "clock {} bytes in/out, {}, out {}, in {}: {}",
+                data.len(),
+                order,
+                out_edge,
+                in_edge,
+                Hex(data)
+            ),
+            Instruction::ClockBitsOut {
+                order,
+                edge,
+                data,
+                len,
+            } => write!(
+                f,
+                "clock {} bits out, {}, {}: {:02x}",
+                len, order, edge, data
+            ),
+            Instruction::ClockBitsIn { order, edge, len } => {
+                write!(f, "clock {} bits in, {}, {}", len, order, edge)
+            }
+            Instruction::ClockBits {
+                order,
+                out_edge,
+                in_edge,
+                data,
+                len,
+            } => write!(
+                f,
+                "clock {} bits in/out, {}, out {}, in {}: {:02x}",
+                len, order, out_edge, in_edge, data
+            ),
+            Instruction::ClockTmsOut {
+                edge,
+                data,
+                tdi,
+                len,
+            } => write!(
+                f,
+                "clock {} TMS bits out, {}, TDI {}: {:02x}",
+                len, edge, *tdi as u8, data
+            ),
+            Instruction::ClockTms {
+                out_edge,
+                in_edge,
+                data,
+                tdi,
+                len,
+            } => write!(
+                f,
+                "clock {} TMS bits out, {}, TDO in {}, TDI {}: {:02x}",
+                len, out_edge, in_edge, *tdi as u8, data
+            ),
+            Instruction::SetGpioLower { value, direction } => write!(
+                f,
+                "set lower GPIO value {:02x} direction {:02x}",
+                value, direction
+            ),
+            Instruction::SetGpioUpper { value, direction } => write!(
+                f,
+                "set upper GPIO value {:02x} direction {:02x}",
+                value, direction
+            ),
+            Instruction::GpioLower => f.write_str("read lower GPIO"),
+            Instruction::GpioUpper => f.write_str("read upper GPIO"),
+            Instruction::EnableLoopback => f.write_str("enable loopback"),
+            Instruction::DisableLoopback => f.write_str("disable loopback"),
+            Instruction::SetClockDivisor(divisor) => {
+                write!(f, "set clock divisor {:#06x}", divisor)
+            }
+            Instruction::SendImmediate => f.write_str("send immediate"),
+            Instruction::WaitOnIoHigh => f.write_str("wait on I/O high"),
+            Instruction::WaitOnIoLow => f.write_str("wait on I/O low"),
+            Instruction::DisableClockDivide => f.write_str("disable clock divide by 5"),
+            Instruction::EnableClockDivide => f.write_str("enable clock divide by 5"),
+            Instruction::EnableThreePhaseClocking => f.write_str("enable 3-phase clocking"),
+            Instruction::DisableThreePhaseClocking => f.write_str("disable 3-phase clocking"),
+            Instruction::ClockBitsNoData(len) => write!(f, "clock {} bits, no data", len),
+            Instruction::ClockBytesNoData(len) => write!(f, "clock {} bytes, no data", len),
+            Instruction::ClockUntilIoHigh => f.write_str("clock until I/O high"),
+            Instruction::ClockUntilIoLow => f.write_str("clock until I/O low"),
+            Instruction::EnableAdaptiveClocking => f.write_str("enable adaptive clocking"),
+            Instruction::DisableAdaptiveClocking => f.write_str("disable adaptive clocking"),
+            Instruction::ClockBytesUntilIoHigh(len) => {
+                write!(f, "clock up to {} bytes until I/O high", len)
+            }
+            Instruction::ClockBytesUntilIoLow(len) => {
+                write!(f, "clock up to {} bytes until I/O low", len)
+            }
+            Instruction::DriveOnlyZero { lower, upper } => write!(
+                f,
+                "drive only zero, lower {:02x} upper {:02x}",
+                lower, upper
+            ),
+            Instruction::CpuReadShort { address } => {
+                write!(f, "CPU mode read short address {:02x}", address)
+            }
+            Instruction::CpuReadExtended { address } => {
+                write!(f, "CPU mode read extended address {:04x}", address)
+            }
+            Instruction::CpuWriteShort { address, data } => write!(
+                f,
+                "CPU mode write short address {:02x}: {:02x}",
+                address, data
+            ),
+            Instruction::CpuWriteExtended { address, data } => write!(
+                f,
+                "CPU mode write extended address {:04x}: {:02x}",
+                address, data
+            ),
+        }
+    }
+}
+
+/// A decoded command along with its position in the stream
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Command<'a> {
+    pub offset: usize,
+    pub opcode: u8,
+    pub instruction: Instruction<'a>,
+}
+
+impl fmt::Display for Command<'_> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}: [{:02x}] {}",
+            self.offset, self.opcode, self.instruction
+        )
+    }
+}
+
+#[derive(Debug, Error, Clone, PartialEq, Eq)]
+pub enum Error {
+    #[error("unknown opcode {opcode:#04x} at offset {offset}")]
+    UnknownOpcode { offset: usize, opcode: u8 },
+    #[error("command {opcode:#04x} at offset {offset} is truncated")]
+    Truncated { offset: usize, opcode: u8 },
+    #[error("command {opcode:#04x} at offset {offset} has an invalid bit count {len}")]
+    InvalidBitCount { offset: usize, opcode: u8, len: u8 },
+}
+
+/// An iterator over the commands of an MPSSE stream
+///
+/// Yields `None` after the first error, as the rest of a malformed stream
+/// cannot be decoded reliably.
+#[derive(Clone, Debug)]
+pub struct Disassembler<'a> {
+    data: &'a [u8],
+    offset: usize,
+    failed: bool,
+}
+
+impl<'a> Disassembler<'a> {
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            offset: 0,
+            failed: false,
+        }
+    }
+
+    /// Offset of the next command to decode
+    pub fn offset(&self) -> usize {
+        self.offset
+    }
+
+    fn decode(&self, opcode: u8) -> Result<(Instruction<'a>, usize), Error> {
+        let offset = self.offset;
+        let args = &self.data[offset + 1..];
+        let truncated = Error::Truncated { offset, opcode };
+        let arg = |n: usize| args.get(n).copied().ok_or_else(|| truncated.clone());
+        let byte_len = || Ok(usize::from(u16::from_le_bytes([arg(0)?, arg(1)?])) + 1);
+        let bit_len = |max: u8| {
+            let len = arg(0)?;
+            if len < max {
+                Ok(len + 1)
+            } else {
+                Err(Error::InvalidBitCount {
+                    offset,
+                    opcode,
+                    len: len + 1,
+                })
+            }
+        };
+
+        let decoded = match opcode {
+            // The data shifting commands of AN108 section 3.3 and 3.4, other
+            // combinations of the opcode bits are undocumented
+            0x10 | 0x11 | 0x12 | 0x13 | 0x18 | 0x19 | 0x1a | 0x1b | 0x20 | 0x22 | 0x24 | 0x26
+            | 0x28 | 0x2a | 0x2c | 0x2e | 0x31 | 0x33 | 0x34 | 0x36 | 0x39 | 0x3b | 0x3c | 0x3e => {
+                let order = if opcode & 0x08 != 0 {
+                    BitOrder::Lsb
+                } else {
+                    BitOrder::Msb
+                };
+                let out_edge = Edge::from_bit(opcode & 0x01 != 0);
+                let in_edge = Edge::from_bit(opcode & 0x04 != 0);
+                let write = opcode & 0x10 != 0;
+                let read = opcode & 0x20 != 0;
+
+                if opcode & 0x02 != 0 {
+                    let len = bit_len(8)?;
+                    match (write, read) {
+                        (true, false) => (
+                            Instruction::ClockBitsOut {
+                                order,
+                                edge: out_edge,
+                                data: arg(1)?,
+                                len,
+                            },
+                            3,
+                        ),
+                        (false, true) => (
+                            Instruction::ClockBitsIn {
+                                order,
+                                edge: in_edge,
+                                len,
+                            },
+                            2,
+                        ),
+                        _ => (
+                            Instruction::ClockBits {
+                                order,
+                                out_edge,
+                                in_edge,
+                                data: arg(1)?,
+                                len,
+                            },
+                            3,
+                        ),
+                    }
+                } else {
+                    let len = byte_len()?;
+                    match (write, read) {
+                        (false, true) => (
+                            Instruction::ClockDataIn {
+                                order,
+                                edge: in_edge,
+                                len,
+                            },
+                            3,
+                        ),
+                        _ => {
+                            let data = args.get(2..2 + len).ok_or(truncated)?;
+                            let instruction = if read {
+                                Instruction::ClockData {
+                                    order,
+                                    out_edge,
+                                    in_edge,
+                                    data,
+                                }
+                            } else {
+                                Instruction::ClockDataOut {
+                                    order,
+                                    edge: out_edge,
+                                    data,
+                                }
+                            };
+                            (instruction, 3 + len)
+                        }
+                    }
+                }
+            }
+            0x4a | 0x4b | 0x6a | 0x6b | 0x6e | 0x6f => {
+                let len = bit_len(7)?;
+                let byte = arg(1)?;
+                let out_edge = Edge::from_bit(opcode & 0x01 != 0);
+                let data = byte & 0x7f;
+                let tdi = byte & 0x80 != 0;
+                let instruction = if opcode & 0x20 != 0 {
+                    Instruction::ClockTms {
+                        out_edge,
+                        in_edge: Edge::from_bit(opcode & 0x04 != 0),
+                        data,
+                        tdi,
+                        len,
+                    }
+                } else {
+                    Instruction::ClockTmsOut {
+                        edge: out_edge,
+                        data,
+                        tdi,
+                        len,
+                    }
+                };
+                (instruction, 3)
+            }
+            0x80 => (
+                Instruction::SetGpioLower {
+                    value: arg(0)?,
+                    direction: arg(1)?,
+                },
+                3,
+            ),
+            0x81 => (Instruction::GpioLower, 1),
+            0x82 => (
+                Instruction::SetGpioUpper {
+                    value: arg(0)?,
+                    direction: arg(1)?,
+                },
+                3,
+            ),
+            0x83 => (Instruction::GpioUpper, 1),
+            0x84 => (Instruction::EnableLoopback, 1),
+            0x85 => (Instruction::DisableLoopback, 1),
+            0x86 => (
+                Instruction::SetClockDivisor(u16::from_le_bytes([arg(0)?, arg(1)?])),
+                3,
+            ),
+            0x87 => (Instruction::SendImmediate, 1),
+            0x88 => (Instruction::WaitOnIoHigh, 1),
+            0x89 => (Instruction::WaitOnIoLow, 1),
+            0x8a => (Instruction::DisableClockDivide, 1),
+            0x8b => (Instruction::EnableClockDivide, 1),
+            0x8c => (Instruction::EnableThreePhaseClocking, 1),
+            0x8d => (Instruction::DisableThreePhaseClocking, 1),
+            0x8e => (Instruction::ClockBitsNoData(bit_len(8)?), 2),
+            0x8f => (Instruction::ClockBytesNoData(byte_len()?), 3),
+            0x90 => (Instruction::CpuReadShort { address: arg(0)? }, 2),
+            0x91 => (
+                Instruction::CpuReadExtended {
+                    address: u16::from_be_bytes([arg(0)?, arg(1)?]),
+                },
+                3,
+            ),
+            0x92 => (
+                Instruction::CpuWriteShort {
+                    address: arg(0)?,
+                    data: arg(1)?,
+                },
+                3,
+            ),
+            0x93 => (
+                Instruction::CpuWriteExtended {
+                    address: u16::from_be_bytes([arg(0)?, arg(1)?]),
+                    data: arg(2)?,
+                },
+                4,
+            ),
+            0x94 => (Instruction::ClockUntilIoHigh, 1),
+            0x95 => (Instruction::ClockUntilIoLow, 1),
+            0x96 => (Instruction::EnableAdaptiveClocking, 1),
+            0x97 => (Instruction::DisableAdaptiveClocking, 1),
+            0x9c => (Instruction::ClockBytesUntilIoHigh(byte_len()?), 3),
+            0x9d => (Instruction::ClockBytesUntilIoLow(byte_len()?), 3),
+            0x9e => (
+                Instruction::DriveOnlyZero {
+                    lower: arg(0)?,
+                    upper: arg(1)?,
+                },
+                3,
+            ),
+            _ => return Err(Error::UnknownOpcode { offset, opcode }),
+        };
+        Ok(decoded)
+    }
+}
+
+impl<'a> Iterator for Disassembler<'a> {
+    type Item = Result<Command<'a>, Error>;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        if self.failed {
+            return None;
+        }
+        let opcode = *self.data.get(self.offset)?;
+        match self.decode(opcode) {
+            Ok((instruction, len)) => {
+                let command = Command {
+                    offset: self.offset,
+                    opcode,
+                    instruction,
+                };
+                self.offset += len;
+                Some(Ok(command))
+            }
+            Err(e) => {
+                self.failed = true;
+                Some(Err(e))
+            }
+        }
+    }
+}
+
+/// Decodes a whole MPSSE command stream
+pub fn disassemble(data: &[u8]) -> Result<Vec<Command<'_>>, Error> {
+    Disassembler::new(data).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpsse::Transaction;
+    use ftdi_mpsse::{
+        ClockBitsIn, ClockBitsOut, ClockData, ClockDataIn, ClockDataOut, ClockTMS, ClockTMSOut,
+    };
+
+    #[test]
+    fn undocumented_shift_opcodes_are_unknown() {
+        for opcode in [0x14, 0x15, 0x16, 0x17, 0x1c, 0x21, 0x23, 0x30, 0x35, 0x3f] {
+            assert_eq!(
+                disassemble(&[opcode, 0x00, 0x00, 0x00]),
+                Err(Error::UnknownOpcode { offset: 0, opcode }),
+                "{:#04x}",
+                opcode
+            );
+        }
+    }
+
+    #[test]
+    fn documented_shift_opcodes_are_decoded() {
+        let documented = (0x10..=0x3f).filter(|&opcode| {
+            disassemble(&[opcode, 0x00, 0x00, 0x00])
+                != Err(Error::UnknownOpcode { offset: 0, opcode })
+        });
+        assert_eq!(
+            documented.collect::<Vec<u8>>(),
+            [
+                0x10, 0x11, 0x12, 0x13, 0x18, 0x19, 0x1a, 0x1b, 0x20, 0x22, 0x24, 0x26, 0x28, 0x2a,
+                0x2c, 0x2e, 0x31, 0x33, 0x34, 0x36, 0x39, 0x3b, 0x3c, 0x3e
+            ]
+        );
+    }
+
+    fn instructions(data: &[u8]) -> Vec<Instruction<'_>> {
+        disassemble(data)
+            .unwrap()
+            .into_iter()
+            .map(|command| command.instruction)
+            .collect()
+    }
+
+    #[test]
+    fn transaction_round_trip() {
+        let mut transaction = Transaction::new();
+        transaction
+            .set_gpio_lower(0x08, 0x0b)
+            .set_clock(29, Some(false))
+            .clock_data_out(ClockDataOut::MsbNeg, &[0x9f, 0x01]);
+        transaction.clock_data_in_vec(ClockDataIn::LsbPos, 3);
+        transaction.clock_data_vec(ClockData::MsbPosIn, &[0xa5]);
+        transaction.clock_bits_out(ClockBitsOut::LsbNeg, 0x05, 3);
+        transaction.clock_bits_in(ClockBitsIn::MsbNeg, 8);
+        transaction.clock_tms_out(ClockTMSOut::NegEdge, 0x03, true, 2);
+        transaction.clock_tms(ClockTMS::PosTMSNegTDO, 0x01, false, 1);
+        transaction.gpio_upper();
+
+        let decoded = instructions(transaction.as_slice());
+        assert_eq!(
+            decoded,
+            [
+                Instruction::SetGpioLower {
+                    value: 0x08,
+                    direction: 0x0b
+                },
+                Instruction::DisableClockDivide,
+                Instruction::SetClockDivisor(29),
+                Instruction::ClockDataOut {
+                    order: BitOrder::Msb,
+                    edge: Edge::Neg,
+                    data: &[0x9f, 0x01]
+                },
+                Instruction::ClockDataIn {
+                    order: BitOrder::Lsb,
+                    edge: Edge::Pos,
+                    len: 3
+                },
+                Instruction::ClockData {
+                    order: BitOrder::Msb,
+                    out_edge: Edge::Neg,
+                    in_edge: Edge::Pos,
+                    data: &[0xa5]
+                },
+                Instruction::ClockBitsOut {
+                    order: BitOrder::Lsb,
+                    edge: Edge::Neg,
+                    data: 0x05,
+                    len: 3
+                },
+                Instruction::ClockBitsIn {
+                    order: BitOrder::Msb,
+                    edge: Edge::Neg,
+                    len: 8
+                },
+                Instruction::ClockTmsOut {
+                    edge: Edge::Neg,
+                    data: 0x03,
+                    tdi: true,
+                    len: 2
+                },
+                Instruction::ClockTms {
+                    out_edge: Edge::Pos,
+                    in_edge: Edge::Neg,
+                    data: 0x01,
+                    tdi: false,
+                    len: 1
+                },
+                Instruction::GpioUpper,
+            ]
+        );
+        let response_len: usize = decoded.iter().map(Instruction::response_len).sum();
+        assert_eq!(response_len, transaction.response_len());
+    }
+
+    #[test]
+    fn commands_are_displayed_with_their_offset() {
+        let commands =
+            disassemble(&[0x80, 0x08, 0x0b, 0x19, 0x01, 0x00, 0x9f, 0x00, 0x87]).unwrap();
+        let lines: Vec<String> = commands.iter().map(ToString::to_string).collect();
+        assert_eq!(lines[0], format!("0000: [80] {}", commands[0].instruction));
+        assert!(lines[1].starts_with("0003: [19] "), "{}", lines[1]);
+        assert!(lines[2].starts_with("0008: [87] "), "{}", lines[2]);
+    }
+
+    #[test]
+    fn truncated_commands_are_reported() {
+        for data in [
+            &[0x80, 0x08][..],
+            &[0x19, 0x01],
+            &[0x19, 0x01, 0x00, 0x9f],
+            &[0x24],
+            &[0x4b, 0x01],
+            &[0x93, 0x00, 0x10],
+        ] {
+            assert_eq!(
+                disassemble(data),
+                Err(Error::Truncated {
+                    offset: 0,
+                    opcode: data[0]
+                }),
+                "{:02x?}",
+                data
+            );
+        }
+    }
+
+    #[test]
+    fn invalid_bit_counts_are_reported() {
+        assert_eq!(
+            disassemble(&[0x12, 0x08, 0x00]),
+            Err(Error::InvalidBitCount {
+                offset: 0,
+                opcode: 0x12,
+                len: 9
+            })
+        );
+        assert_eq!(
+            disassemble(&[0x87, 0x4b, 0x07, 0x00]),
+            Err(Error::InvalidBitCount {
+                offset: 1,
+                opcode: 0x4b,
+                len: 8
+            })
+        );
+    }
+
+    #[test]
+    fn decoding_stops_after_an_error() {
+        let mut disassembler = Disassembler::new(&[0x87, 0x00, 0x87]);
+        assert!(matches!(disassembler.next(), Some(Ok(_))));
+        assert_eq!(
+            disassembler.next(),
+            Some(Err(Error::UnknownOpcode {
+                offset: 1,
+                opcode: 0x00
+            }))
+        );
+        assert_eq!(disassembler.next(), None);
+        assert_eq!(disassembler.offset(), 1);
+    }
+}
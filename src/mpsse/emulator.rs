@@ -0,0 +1,1275 @@
+//! Software emulation of the MPSSE command processor
+//!
+//! [`Emulator`] implements [`MpsseCmdExecutor`], consuming the same command
+//! stream a [`Device`](crate::Device) would be sent and producing the response
+//! bytes, so that code built on top of the executor can be exercised without
+//! hardware. The emulated chip is an H-series part with both GPIO banks.
+//!
+//! The pins are modeled at the logic level: undriven pins are pulled up, and
+//! simulated [`Peripheral`]s attached to the emulator can pull any of them low.
+//! Data clocking follows the AN108 timing closely enough for the usual SPI,
+//! I2C and JTAG idioms, while clock frequency, three-phase and adaptive
+//! clocking are recorded but have no effect on the simulation.
+//!
+//! # Example
+//!
+//! ```
+//! use std::cell::RefCell;
+//! use std::rc::Rc;
+//!
+//! use ftdi::mpsse::emulator::{Emulator, SpiSlave};
+//! use ftdi::mpsse::Transaction;
+//! use ftdi_mpsse::{ClockData, MpsseCmdExecutor, MpsseSettings};
+//!
+//! let slave = Rc::new(RefCell::new(SpiSlave::new()));
+//! slave.borrow_mut().queue(&[0xef, 0x40]);
+//!
+//! let mut emulator = Emulator::new();
+//! emulator.attach(slave.clone());
+//! emulator.init(&MpsseSettings::default()).unwrap();
+//!
+//! let mut transaction = Transaction::new();
+//! transaction.set_gpio_lower(0x08, 0x0b);
+//! transaction.set_gpio_lower(0x00, 0x0b);
+//! let id = transaction.clock_data(ClockData::MsbPosIn, &[0x9f, 0x00]);
+//! transaction.set_gpio_lower(0x08, 0x0b);
+//!
+//! let response = transaction.execute(&mut emulator).unwrap();
+//! assert_eq!(response.get(id), [0xef, 0x40]);
+//! assert_eq!(slave.borrow().received(), &[0x9f, 0x00]);
+//! ```
+
+use std::cell::RefCell;
+use std::collections::VecDeque;
+use std::convert::TryFrom;
+use std::rc::Rc;
+
+use ftdi_mpsse::{MpsseCmdExecutor, MpsseSettings};
+use thiserror::Error;
+
+use super::disasm::{self, BitOrder, Disassembler, Edge, Instruction};
+use crate::jtag::TapState;
+
+/// Lower bank pins used by the data clocking commands
+const TCK: u16 = 0x01;
+const TDI: u16 = 0x02;
+const TDO: u16 = 0x04;
+const TMS: u16 = 0x08;
+/// GPIOL1, the pin the wait-on-I/O and clock-until-I/O commands watch
+const GPIOL1: u16 = 0x20;
+
+/// The base clock of H-series chips with the divide-by-5 prescaler disabled
+const BASE_CLOCK: u32 = 30_000_000;
+
+/// Maximum number of passes for peripheral outputs to settle after a change
+const MAX_SETTLE: usize = 16;
+/// Maximum number of clock cycles of a clock-until-I/O command
+const MAX_CLOCK_UNTIL: u32 = 1 << 20;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("malformed MPSSE command stream")]
+    Disasm(#[from] disasm::Error),
+    #[error("opcode {0:#04x} is not supported by the emulator")]
+    Unsupported(u8),
+    #[error("command {0:#04x} waits on GPIOL1 which never changes")]
+    Stalled(u8),
+    #[error("clock frequency {0} Hz is out of range")]
+    ClockOutOfRange(u32),
+    #[error("{requested} response bytes requested but only {available} available")]
+    ResponseUnderrun { requested: usize, available: usize },
+    #[error("MPSSE rejected opcode {0:#04x} as a bad command")]
+    BadCommand(u8),
+    #[error("{0} unexpected bytes in MPSSE response")]
+    UnexpectedResponse(usize),
+}
+
+/// A simulated device connected to the pins of the emulated chip
+///
+/// Pin levels are passed as 16-bit words with AD0..AD7 in the lower byte and
+/// AC0..AC7 in the upper byte.
+pub trait Peripheral {
+    /// Reacts to a change of the pin levels and returns the pins it pulls low
+    ///
+    /// The levels are the ones set up by the MPSSE and the other peripherals, so
+    /// a peripheral never observes its own outputs. It is only called when the
+    /// levels change, but may be called several times for a single command while
+    /// the outputs of the peripherals settle.
+    fn update(&mut self, previous: u16, current: u16) -> u16;
+}
+
+impl<P: Peripheral + ?Sized> Peripheral for Box<P> {
+    fn update(&mut self, previous: u16, current: u16) -> u16 {
+        (**self).update(previous, current)
+    }
+}
+
+/// Allows keeping a handle to an attached peripheral for inspecting it later
+impl<P: Peripheral + ?Sized> Peripheral for Rc<RefCell<P>> {
+    fn update(&mut self, previous: u16, current: u16) -> u16 {
+        self.borrow_mut().update(previous, current)
+    }
+}
+
+fn pin_mask(pin: u8) -> u16 {
+    assert!(pin < 16, "pin index out of range");
+    1 << pin
+}
+
+struct Attached {
+    peripheral: Box<dyn Peripheral>,
+    seen: u16,
+    pull: u16,
+}
+
+#[derive(Clone, Copy, Default)]
+struct Bank {
+    value: u8,
+    direction: u8,
+}
+
+/// An emulated MPSSE engine
+pub struct Emulator {
+    lower: Bank,
+    upper: Bank,
+    drive_only_zero: u16,
+    loopback: bool,
+    divisor: u16,
+    divide_by_5: bool,
+    three_phase: bool,
+    adaptive: bool,
+    levels: u16,
+    peripherals: Vec<Attached>,
+    pending: Vec<u8>,
+    response: VecDeque<u8>,
+}
+
+impl Default for Emulator {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl Emulator {
+    pub fn new() -> Self {
+        Self {
+            lower: Bank::default(),
+            upper: Bank::default(),
+            drive_only_zero: 0,
+            loopback: false,
+            divisor: 0,
+            divide_by_5: true,
+            three_phase: false,
+            adaptive: false,
+            levels: 0xffff,
+            peripherals: Vec::new(),
+            pending: Vec::new(),
+            response: VecDeque::new(),
+        }
+    }
+
+    /// Connects a simulated peripheral to the pins
+    pub fn attach<P: Peripheral + 'static>(&mut self, peripheral: P) -> &mut Self {
+        self.peripherals.push(Attached {
+            peripheral: Box::new(peripheral),
+            seen: 0xffff,
+            pull: 0,
+        });
+        self.propagate();
+        self
+    }
+
+    /// Current levels of the pins, AD0..AD7 in the lower byte
+    pub fn pins(&self) -> u16 {
+        self.levels
+    }
+
+    /// Current clock frequency of the emulated MPSSE
+    pub fn clock_frequency(&self) -> u32 {
+        let base = if self.divide_by_5 {
+            BASE_CLOCK / 5
+        } else {
+            BASE_CLOCK
+        };
+        let frequency = base / (u32::from(self.divisor) + 1);
+        if self.three_phase {
+            frequency / 3 * 2
+        } else {
+            frequency
+        }
+    }
+
+    pub fn is_loopback_enabled(&self) -> bool {
+        self.loopback
+    }
+
+    pub fn is_three_phase_clocking_enabled(&self) -> bool {
+        self.three_phase
+    }
+
+    pub fn is_adaptive_clocking_enabled(&self) -> bool {
+        self.adaptive
+    }
+
+    /// Number of response bytes not read yet
+    pub fn pending_response(&self) -> usize {
+        self.response.len()
+    }
+
+    /// Resets the MPSSE to its state after enabling the MPSSE mode
+    fn reset(&mut self, mask: u8) {
+        self.lower = Bank {
+            value: 0,
+            direction: mask,
+        };
+        self.upper = Bank::default();
+        self.drive_only_zero = 0;
+        self.loopback = false;
+        self.divisor = 0;
+        self.divide_by_5 = true;
+        self.three_phase = false;
+        self.adaptive = false;
+        self.pending.clear();
+        self.response.clear();
+        self.propagate();
+    }
+
+    /// Levels set up by the MPSSE itself, undriven pins being pulled up
+    fn drive(&self) -> u16 {
+        let value = u16::from_le_bytes([self.lower.value, self.upper.value]);
+        let direction = u16::from_le_bytes([self.lower.direction, self.upper.direction]);
+        let driven = direction & !(self.drive_only_zero & value);
+        value | !driven
+    }
+
+    /// Lets the peripherals react to the pins driven by the MPSSE
+    fn propagate(&mut self) {
+        let base = self.drive();
+        for _ in 0..MAX_SETTLE {
+            let mut changed = false;
+            for i in 0..self.peripherals.len() {
+                let others = self
+                    .peripherals
+                    .iter()
+                    .enumerate()
+                    .filter(|&(j, _)| j != i)
+                    .fold(0, |pull, (_, attached)| pull | attached.pull);
+                let seen = base & !others;
+                let attached = &mut self.peripherals[i];
+                if seen != attached.seen {
+                    let previous = attached.seen;
+                    attached.seen = seen;
+                    attached.pull = attached.peripheral.update(previous, seen);
+                    changed = true;
+                }
+            }
+            if !changed {
+                break;
+            }
+        }
+        let pull = self.peripherals.iter().fold(0, |pull, a| pull | a.pull);
+        self.levels = base & !pull;
+    }
+
+    /// Pin levels as seen by the MPSSE, taking the loopback into account
+    fn inputs(&self) -> u16 {
+        if self.loopback {
+            (self.levels & !TDO) | ((self.drive() & TDI) << 1)
+        } else {
+            self.levels
+        }
+    }
+
+    fn set_lower_bits(&mut self, mask: u16, value: bool) {
+        let mask = mask as u8;
+        if value {
+            self.lower.value |= mask;
+        } else {
+            self.lower.value &= !mask;
+        }
+    }
+
+    /// Runs a single clock pulse, optionally changing `out` on `out_edge`,
+    /// and returns the level of TDO/DI sampled on `in_edge`
+    fn clock_cycle(&mut self, out: Option<(u16, bool, Edge)>, in_edge: Edge) -> bool {
+        let (first, second) = if self.lower.value & TCK as u8 != 0 {
+            (Edge::Neg, Edge::Pos)
+        } else {
+            (Edge::Pos, Edge::Neg)
+        };
+        let mut sample = false;
+
+        if let Some((pin, value, edge)) = out {
+            if edge == second {
+                self.set_lower_bits(pin, value);
+                self.propagate();
+            }
+        }
+
+        if in_edge == first {
+            sample = self.inputs() & TDO != 0;
+        }
+        self.lower.value ^= TCK as u8;
+        if let Some((pin, value, edge)) = out {
+            if edge == first {
+                self.set_lower_bits(pin, value);
+            }
+        }
+        self.propagate();
+
+        if in_edge == second {
+            sample = self.inputs() & TDO != 0;
+        }
+        self.lower.value ^= TCK as u8;
+        self.propagate();
+
+        sample
+    }
+
+    fn gpiol1(&self) -> bool {
+        self.inputs() & GPIOL1 != 0
+    }
+
+    fn clock_bits(
+        &mut self,
+        order: BitOrder,
+        out: Option<(u8, Edge)>,
+        in_edge: Edge,
+        len: u8,
+    ) -> u8 {
+        let mut input = 0u8;
+        for i in 0..len {
+            let out = out.map(|(data, edge)| {
+                let bit = match order {
+                    BitOrder::Msb => data & (0x80 >> i) != 0,
+                    BitOrder::Lsb => data & (1 << i) != 0,
+                };
+                (TDI, bit, edge)
+            });
+            let bit = u8::from(self.clock_cycle(out, in_edge));
+            input = match order {
+                BitOrder::Msb => (input << 1) | bit,
+                BitOrder::Lsb => (input >> 1) | (bit << 7),
+            };
+        }
+        input
+    }
+
+    fn clock_tms(&mut self, out_edge: Edge, in_edge: Edge, data: u8, tdi: bool, len: u8) -> u8 {
+        self.set_lower_bits(TDI, tdi);
+        self.propagate();
+
+        let mut input = 0u8;
+        for i in 0..len {
+            let bit = self.clock_cycle(Some((TMS, data & (1 << i) != 0, out_edge)), in_edge);
+            input = (input >> 1) | (u8::from(bit) << 7);
+        }
+        input
+    }
+
+    fn clock_until(
+        &mut self,
+        opcode: u8,
+        high: bool,
+        max_cycles: Option<u32>,
+    ) -> Result<(), Error> {
+        let mut cycles = 0;
+        while self.gpiol1() != high {
+            match max_cycles {
+                Some(max) if cycles == max => return Ok(()),
+                None if cycles == MAX_CLOCK_UNTIL => return Err(Error::Stalled(opcode)),
+                _ => {}
+            }
+            self.clock_cycle(None, Edge::Pos);
+            cycles += 1;
+        }
+        Ok(())
+    }
+
+    fn execute(&mut self, opcode: u8, instruction: &Instruction) -> Result<(), Error> {
+        match *instruction {
+            Instruction::ClockDataOut { order, edge, data } => {
+                for &byte in data {
+                    self.clock_bits(order, Some((byte, edge)), Edge::Pos, 8);
+                }
+            }
+            Instruction::ClockDataIn { order, edge, len } => {
+                for _ in 0..len {
+                    let byte = self.clock_bits(order, None, edge, 8);
+                    self.response.push_back(byte);
+                }
+            }
+            Instruction::ClockData {
+                order,
+                out_edge,
+                in_edge,
+                data,
+            } => {
+                for &byte in data {
+                    let byte = self.clock_bits(order, Some((byte, out_edge)), in_edge, 8);
+                    self.response.push_back(byte);
+                }
+            }
+            Instruction::ClockBitsOut {
+                order,
+                edge,
+                data,
+                len,
+            } => {
+                self.clock_bits(order, Some((data, edge)), Edge::Pos, len);
+            }
+            Instruction::ClockBitsIn { order, edge, len } => {
+                let byte = self.clock_bits(order, None, edge, len);
+                self.response.push_back(byte);
+            }
+            Instruction::ClockBits {
+                order,
+                out_edge,
+                in_edge,
+                data,
+                len,
+            } => {
+                let byte = self.clock_bits(order, Some((data, out_edge)), in_edge, len);
+                self.response.push_back(byte);
+            }
+            Instruction::ClockTmsOut {
+                edge,
+                data,
+                tdi,
+                len,
+            } => {
+                self.clock_tms(edge, Edge::Pos, data, tdi, len);
+            }
+            Instruction::ClockTms {
+                out_edge,
+                in_edge,
+                data,
+                tdi,
+                len,
+            } => {
+                let byte = self.clock_tms(out_edge, in_edge, data, tdi, len);
+                self.response.push_back(byte);
+            }
+            Instruction::SetGpioLower { value, direction } => {
+                self.lower = Bank { value, direction };
+                self.propagate();
+            }
+            Instruction::SetGpioUpper { value, direction } => {
+                self.upper = Bank { value, direction };
+                self.propagate();
+            }
+            Instruction::GpioLower => self.response.push_back(self.inputs() as u8),
+            Instruction::GpioUpper => self.response.push_back((self.inputs() >> 8) as u8),
+            Instruction::EnableLoopback => self.loopback = true,
+            Instruction::DisableLoopback => self.loopback = false,
+            Instruction::SetClockDivisor(divisor) => self.divisor = divisor,
+            Instruction::SendImmediate => {}
+            Instruction::WaitOnIoHigh | Instruction::WaitOnIoLow => {
+                let high = matches!(instruction, Instruction::WaitOnIoHigh);
+                if self.gpiol1() != high {
+                    return Err(Error::Stalled(opcode));
+                }
+            }
+            Instruction::DisableClockDivide => self.divide_by_5 = false,
+            Instruction::EnableClockDivide => self.divide_by_5 = true,
+            Instruction::EnableThreePhaseClocking => self.three_phase = true,
+            Instruction::DisableThreePhaseClocking => self.three_phase = false,
+            Instruction::ClockBitsNoData(len) => {
+                for _ in 0..len {
+                    self.clock_cycle(None, Edge::Pos);
+                }
+            }
+            Instruction::ClockBytesNoData(len) => {
+                for _ in 0..len * 8 {
+                    self.clock_cycle(None, Edge::Pos);
+                }
+            }
+            Instruction::ClockUntilIoHigh => self.clock_until(opcode, true, None)?,
+            Instruction::ClockUntilIoLow => self.clock_until(opcode, false, None)?,
+            Instruction::EnableAdaptiveClocking => self.adaptive = true,
+            Instruction::DisableAdaptiveClocking => self.adaptive = false,
+            Instruction::ClockBytesUntilIoHigh(len) => {
+                self.clock_until(opcode, true, Some(len as u32 * 8))?
+            }
+            Instruction::ClockBytesUntilIoLow(len) => {
+                self.clock_until(opcode, false, Some(len as u32 * 8))?
+            }
+            Instruction::DriveOnlyZero { lower, upper } => {
+                self.drive_only_zero = u16::from_le_bytes([lower, upper]);
+                self.propagate();
+            }
+            Instruction::CpuReadShort { .. }
+            | Instruction::CpuReadExtended { .. }
+            | Instruction::CpuWriteShort { .. }
+            | Instruction::CpuWriteExtended { .. } => return Err(Error::Unsupported(opcode)),
+        }
+        Ok(())
+    }
+
+    /// Executes the complete commands of the pending stream
+    ///
+    /// Like the real MPSSE, an invalid opcode is answered with `0xFA` followed
+    /// by the opcode, and a command split across writes is completed by the next
+    /// one.
+    fn process(&mut self) -> Result<(), Error> {
+        let data = std::mem::take(&mut self.pending);
+        let mut offset = 0;
+        while offset < data.len() {
+            let mut disassembler = Disassembler::new(&data[offset..]);
+            match disassembler.next() {
+                Some(Ok(command)) => {
+                    self.execute(command.opcode, &command.instruction)?;
+                    offset += disassembler.offset();
+                }
+                Some(Err(disasm::Error::Truncated { .. })) => {
+                    self.pending = data[offset..].to_vec();
+                    break;
+                }
+                Some(Err(disasm::Error::UnknownOpcode { opcode, .. })) => {
+                    self.response.extend(&[0xfa, opcode]);
+                    offset += 1;
+                }
+                Some(Err(e)) => return Err(e.into()),
+                None => break,
+            }
+        }
+        Ok(())
+    }
+}
+
+impl MpsseCmdExecutor for Emulator {
+    type Error = Error;
+
+    /// Resets the emulated MPSSE, keeping the attached peripherals.
+    fn init(&mut self, settings: &MpsseSettings) -> Result<(), Error> {
+        self.reset(settings.mask);
+
+        if let Some(frequency) = settings.clock_frequency {
+            let divisor = match frequency {
+                0 => None,
+                f => u16::try_from(BASE_CLOCK.div_ceil(f) - 1).ok(),
+            };
+            self.divisor = divisor.ok_or(Error::ClockOutOfRange(frequency))?;
+            self.divide_by_5 = false;
+        }
+
+        Ok(())
+    }
+
+    fn send(&mut self, data: &[u8]) -> Result<(), Error> {
+        self.pending.extend_from_slice(data);
+        self.process()
+    }
+
+    /// Reads the response, reporting leftover data the same way a `Device` does.
+    fn recv(&mut self, data: &mut [u8]) -> Result<(), Error> {
+        if self.response.len() < data.len() {
+            return Err(Error::ResponseUnderrun {
+                requested: data.len(),
+                available: self.response.len(),
+            });
+        }
+        for byte in data.iter_mut() {
+            *byte = self.response.pop_front().unwrap_or_default();
+        }
+
+        if self.response.is_empty() {
+            return Ok(());
+        }
+        let stream: Vec<u8> = data
+            .iter()
+            .copied()
+            .chain(self.response.drain(..))
+            .collect();
+        match stream.windows(2).find(|pair| pair[0] == 0xfa) {
+            Some(pair) => Err(Error::BadCommand(pair[1])),
+            None => Err(Error::UnexpectedResponse(stream.len() - data.len())),
+        }
+    }
+}
+
+/// An SPI slave shifting out queued bytes and recording the received ones
+///
+/// Defaults to mode 0 with SCK on AD0, MOSI on AD1, MISO on AD2 and an active
+/// low chip select on AD3. Bytes are transferred MSB first, and `0xFF` is
+/// shifted out once the queue is empty.
+pub struct SpiSlave {
+    sck: u16,
+    mosi: u16,
+    miso: u16,
+    cs: u16,
+    cpol: bool,
+    cpha: bool,
+    transmit: VecDeque<u8>,
+    received: Vec<u8>,
+    out_byte: u8,
+    out_bits: u8,
+    in_byte: u8,
+    in_bits: u8,
+    miso_low: bool,
+}
+
+impl Default for SpiSlave {
+    fn default() -> Self {
+        Self::new()
+    }
+}
+
+impl SpiSlave {
+    pub fn new() -> Self {
+        Self {
+            sck: TCK,
+            mosi: TDI,
+            miso: TDO,
+            cs: TMS,
+            cpol: false,
+            cpha: false,
+            transmit: VecDeque::new(),
+            received: Vec::new(),
+            out_byte: 0xff,
+            out_bits: 8,
+            in_byte: 0,
+            in_bits: 0,
+            miso_low: false,
+        }
+    }
+
+    /// Selects the SPI mode, `0..=3`
+    pub fn with_mode(mut self, mode: u8) -> Self {
+        assert!(mode < 4, "invalid SPI mode");
+        self.cpol = mode & 0x02 != 0;
+        self.cpha = mode & 0x01 != 0;
+        self
+    }
+
+    /// Changes the pins, given as indices with AD0 being 0 and AC0 being 8
+    pub fn with_pins(mut self, sck: u8, mosi: u8, miso: u8, cs: u8) -> Self {
+        self.sck = pin_mask(sck);
+        self.mosi = pin_mask(mosi);
+        self.miso = pin_mask(miso);
+        self.cs = pin_mask(cs);
+        self
+    }
+
+    /// Queues bytes to be shifted out
+    pub fn queue(&mut self, data: &[u8]) {
+        self.transmit.extend(data);
+    }
+
+    /// Bytes received so far
+    pub fn received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// Takes the bytes received so far
+    pub fn take_received(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.received)
+    }
+
+    fn output_next_bit(&mut self) {
+        if self.out_bits == 8 {
+            self.out_byte = self.transmit.pop_front().unwrap_or(0xff);
+            self.out_bits = 0;
+        }
+        self.miso_low = self.out_byte & (0x80 >> self.out_bits) == 0;
+        self.out_bits += 1;
+    }
+
+    fn sample(&mut self, mosi: bool) {
+        self.in_byte = (self.in_byte << 1) | u8::from(mosi);
+        self.in_bits += 1;
+        if self.in_bits == 8 {
+            self.received.push(self.in_byte);
+            self.in_bits = 0;
+        }
+    }
+}
+
+impl Peripheral for SpiSlave {
+    fn update(&mut self, previous: u16, current: u16) -> u16 {
+        let selected = current & self.cs == 0;
+        if !selected {
+            self.miso_low = false;
+            return 0;
+        }
+
+        if previous & self.cs != 0 {
+            self.out_bits = 8;
+            self.in_bits = 0;
+            self.miso_low = false;
+            if !self.cpha {
+                self.output_next_bit();
+            }
+        } else if (previous ^ current) & self.sck != 0 {
+            let leading = (current & self.sck != 0) != self.cpol;
+            if leading != self.cpha {
+                self.sample(current & self.mosi != 0);
+            } else {
+                self.output_next_bit();
+            }
+        }
+
+        if self.miso_low {
+            self.miso
+        } else {
+            0
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+enum I2cState {
+    Idle,
+    Address,
+    Receive,
+    Ack { read: bool },
+    Transmit,
+    ReceiveAck,
+}
+
+/// An I2C slave at a 7-bit address acknowledging its address and all written
+/// bytes
+///
+/// Defaults to SCL on AD0 and SDA on AD1 and AD2 tied together, as in the FTDI
+/// I2C application notes. Written bytes are recorded and reads shift out queued
+/// bytes, `0xFF` once the queue is empty.
+pub struct I2cSlave {
+    address: u8,
+    scl: u16,
+    sda: u16,
+    state: I2cState,
+    shift: u8,
+    bits: u8,
+    sda_low: bool,
+    transmit: VecDeque<u8>,
+    received: Vec<u8>,
+}
+
+impl I2cSlave {
+    pub fn new(address: u8) -> Self {
+        assert!(address < 0x80, "I2C address must be 7 bits");
+        Self {
+            address,
+            scl: TCK,
+            sda: TDI | TDO,
+            state: I2cState::Idle,
+            shift: 0,
+            bits: 0,
+            sda_low: false,
+            transmit: VecDeque::new(),
+            received: Vec::new(),
+        }
+    }
+
+    /// Changes the pins, given as indices with AD0 being 0 and AC0 being 8
+    ///
+    /// SDA may be given as several pins, which are then treated as tied together.
+    pub fn with_pins(mut self, scl: u8, sda: &[u8]) -> Self {
+        self.scl = pin_mask(scl);
+        self.sda = sda.iter().fold(0, |mask, &pin| mask | pin_mask(pin));
+        self
+    }
+
+    /// Queues bytes to be read by the master
+    pub fn queue(&mut self, data: &[u8]) {
+        self.transmit.extend(data);
+    }
+
+    /// Bytes written by the master so far, excluding the address bytes
+    pub fn received(&self) -> &[u8] {
+        &self.received
+    }
+
+    /// Takes the bytes written so far
+    pub fn take_received(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.received)
+    }
+
+    fn transmit_next_bit(&mut self) {
+        if self.bits == 0 {
+            self.shift = self.transmit.pop_front().unwrap_or(0xff);
+        }
+        self.sda_low = self.shift & (0x80 >> self.bits) == 0;
+        self.bits += 1;
+    }
+
+    fn falling_edge(&mut self) {
+        match self.state {
+            I2cState::Address | I2cState::Receive if self.bits == 8 => {
+                self.bits = 0;
+                if self.state == I2cState::Receive {
+                    self.received.push(self.shift);
+                    self.state = I2cState::Ack { read: false };
+                    self.sda_low = true;
+                } else if self.shift >> 1 == self.address {
+                    self.state = I2cState::Ack {
+                        read: self.shift & 1 != 0,
+                    };
+                    self.sda_low = true;
+                } else {
+                    self.state = I2cState::Idle;
+                }
+            }
+            I2cState::Ack { read } => {
+                self.sda_low = false;
+                if read {
+                    self.state = I2cState::Transmit;
+                    self.transmit_next_bit();
+                } else {
+                    self.state = I2cState::Receive;
+                    self.shift = 0;
+                }
+            }
+            I2cState::Transmit if self.bits == 8 => {
+                self.sda_low = false;
+                self.state = I2cState::ReceiveAck;
+            }
+            I2cState::Transmit => self.transmit_next_bit(),
+            _ => {}
+        }
+    }
+}
+
+impl Peripheral for I2cSlave {
+    fn update(&mut self, previous: u16, current: u16) -> u16 {
+        let scl = current & self.scl != 0;
+        let was_scl = previous & self.scl != 0;
+        let sda = current & self.sda == self.sda;
+        let was_sda = previous & self.sda == self.sda;
+
+        if scl && was_scl && sda != was_sda {
+            self.sda_low = false;
+            self.bits = 0;
+            self.shift = 0;
+            self.state = if sda {
+                I2cState::Idle
+            } else {
+                I2cState::Address
+            };
+        } else if scl && !was_scl {
+            match self.state {
+                I2cState::Address | I2cState::Receive => {
+                    self.shift = (self.shift << 1) | u8::from(sda);
+                    self.bits += 1;
+                }
+                I2cState::ReceiveAck => {
+                    self.state = if sda {
+                        I2cState::Idle
+                    } else {
+                        I2cState::Ack { read: true }
+                    };
+                    self.bits = 0;
+                }
+                _ => {}
+            }
+        } else if !scl && was_scl {
+            match self.state {
+                // the master's ACK has already been handled on the rising edge
+                I2cState::Ack { read: true } if !self.sda_low => {
+                    self.state = I2cState::Transmit;
+                    self.transmit_next_bit();
+                }
+                _ => self.falling_edge(),
+            }
+        }
+
+        if self.sda_low {
+            self.sda
+        } else {
+            0
+        }
+    }
+}
+
+enum Register {
+    Bypass,
+    IdCode,
+    Data(usize),
+}
+
+/// A JTAG TAP controller with an IDCODE register and user data registers
+///
+/// Defaults to TCK on AD0, TDI on AD1, TDO on AD2 and TMS on AD3, the same
+/// pinout as [`Jtag`](crate::jtag::Jtag). Instructions without a register,
+/// including all ones, select the 1-bit bypass register. After a reset the
+/// IDCODE register is selected if the TAP has one, and bypass otherwise.
+pub struct JtagTap {
+    tck: u16,
+    tdi: u16,
+    tdo: u16,
+    tms: u16,
+    state: TapState,
+    ir_len: usize,
+    ir: u64,
+    idcode: Option<(u64, u32)>,
+    registers: Vec<(u64, Vec<bool>)>,
+    shift: VecDeque<bool>,
+    tdo_low: bool,
+}
+
+impl JtagTap {
+    /// Creates a TAP with an instruction register of `ir_len` bits, `1..=64`
+    pub fn new(ir_len: usize) -> Self {
+        assert!((1..=64).contains(&ir_len), "invalid IR length");
+        Self {
+            tck: TCK,
+            tdi: TDI,
+            tdo: TDO,
+            tms: TMS,
+            state: TapState::TestLogicReset,
+            ir_len,
+            ir: Self::bypass_instruction(ir_len),
+            idcode: None,
+            registers: Vec::new(),
+            shift: VecDeque::new(),
+            tdo_low: false,
+        }
+    }
+
+    fn bypass_instruction(ir_len: usize) -> u64 {
+        u64::MAX >> (64 - ir_len)
+    }
+
+    /// Adds an IDCODE register selected by `instruction`
+    pub fn with_idcode(mut self, instruction: u64, idcode: u32) -> Self {
+        self.idcode = Some((instruction, idcode));
+        self.ir = instruction;
+        self
+    }
+
+    /// Adds a data register of `len` bits selected by `instruction`
+    ///
+    /// The register captures the value latched by its last update, initially
+    /// all zeroes.
+    pub fn with_register(mut self, instruction: u64, len: usize) -> Self {
+        self.registers.push((instruction, vec![false; len]));
+        self
+    }
+
+    /// Changes the pins, given as indices with AD0 being 0 and AC0 being 8
+    ///
+    /// Chains of several TAPs can be built by connecting the TDO of one TAP to
+    /// the TDI of the next one through spare pins.
+    pub fn with_pins(mut self, tck: u8, tdi: u8, tdo: u8, tms: u8) -> Self {
+        self.tck = pin_mask(tck);
+        self.tdi = pin_mask(tdi);
+        self.tdo = pin_mask(tdo);
+        self.tms = pin_mask(tms);
+        self
+    }
+
+    pub fn state(&self) -> TapState {
+        self.state
+    }
+
+    /// The current instruction
+    pub fn instruction(&self) -> u64 {
+        self.ir
+    }
+
+    /// The latched value of the data register selected by `instruction`, LSB first
+    pub fn register(&self, instruction: u64) -> Option<&[bool]> {
+        self.registers
+            .iter()
+            .find(|(i, _)| *i == instruction)
+            .map(|(_, value)| value.as_slice())
+    }
+
+    fn selected(&self) -> Register {
+        if let Some((instruction, _)) = self.idcode {
+            if instruction == self.ir {
+                return Register::IdCode;
+            }
+        }
+        match self.registers.iter().position(|(i, _)| *i == self.ir) {
+            Some(index) => Register::Data(index),
+            None => Register::Bypass,
+        }
+    }
+
+    fn capture_dr(&mut self) {
+        self.shift = match self.selected() {
+            Register::Bypass => VecDeque::from(vec![false]),
+            Register::IdCode => {
+                let idcode = self.idcode.map_or(0, |(_, idcode)| idcode);
+                (0..32).map(|i| idcode & (1 << i) != 0).collect()
+            }
+            Register::Data(index) => self.registers[index].1.iter().copied().collect(),
+        };
+    }
+
+    fn update_dr(&mut self) {
+        if let Register::Data(index) = self.selected() {
+            self.registers[index].1 = self.shift.iter().copied().collect();
+        }
+    }
+
+    fn rising_edge(&mut self, tdi: bool, tms: bool) {
+        match self.state {
+            TapState::TestLogicReset => {
+                self.ir = match self.idcode {
+                    Some((instruction, _)) => instruction,
+                    None => Self::bypass_instruction(self.ir_len),
+                };
+            }
+            TapState::CaptureDr => self.capture_dr(),
+            TapState::CaptureIr => {
+                self.shift = (0..self.ir_len).map(|i| i == 0).collect();
+            }
+            TapState::ShiftDr | TapState::ShiftIr => {
+                self.shift.pop_front();
+                self.shift.push_back(tdi);
+            }
+            _ => {}
+        }
+        self.state = self.state.next(tms);
+    }
+
+    fn falling_edge(&mut self) {
+        match self.state {
+            TapState::UpdateDr => self.update_dr(),
+            TapState::UpdateIr => {
+                self.ir = self
+                    .shift
+                    .iter()
+                    .rev()
+                    .fold(0, |ir, &bit| (ir << 1) | u64::from(bit));
+            }
+            _ => {}
+        }
+        self.tdo_low = match self.state {
+            TapState::ShiftDr | TapState::ShiftIr => self.shift.front() == Some(&false),
+            _ => false,
+        };
+    }
+}
+
+impl Peripheral for JtagTap {
+    fn update(&mut self, previous: u16, current: u16) -> u16 {
+        if (previous ^ current) & self.tck != 0 {
+            if current & self.tck != 0 {
+                self.rising_edge(current & self.tdi != 0, current & self.tms != 0);
+            } else {
+                self.falling_edge();
+            }
+        }
+
+        if self.tdo_low {
+            self.tdo
+        } else {
+            0
+        }
+    }
+}
+
+/// Connects pins to each other, driving each target to the level of its source
+#[derive(Default)]
+pub struct GpioLoopback {
+    connections: Vec<(u16, u16)>,
+}
+
+impl GpioLoopback {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Drives the `to` pin to the level of the `from` pin, given as indices
+    /// with AD0 being 0 and AC0 being 8
+    pub fn connect(mut self, from: u8, to: u8) -> Self {
+        self.connections.push((pin_mask(from), pin_mask(to)));
+        self
+    }
+}
+
+impl Peripheral for GpioLoopback {
+    fn update(&mut self, _previous: u16, current: u16) -> u16 {
+        self.connections
+            .iter()
+            .filter(|&&(from, _)| current & from == 0)
+            .fold(0, |pull, &(_, to)| pull | to)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpsse::{BitsHandle, ReadHandle, Transaction};
+    use ftdi_mpsse::{
+        ClockBitsIn, ClockBitsOut, ClockData, ClockDataIn, ClockDataOut, ClockTMSOut,
+    };
+
+    fn emulator(peripheral: impl Peripheral + 'static) -> Emulator {
+        let mut emulator = Emulator::new();
+        emulator.attach(peripheral);
+        emulator.init(&MpsseSettings::default()).unwrap();
+        emulator
+    }
+
+    #[test]
+    fn spi_transfer() {
+        let slave = Rc::new(RefCell::new(SpiSlave::new()));
+        slave.borrow_mut().queue(&[0x12, 0x34, 0x56]);
+        let mut emulator = emulator(slave.clone());
+
+        let mut transaction = Transaction::new();
+        transaction
+            .set_gpio_lower(0x08, 0x0b)
+            .set_gpio_lower(0x00, 0x0b)
+            .clock_data_out(ClockDataOut::MsbNeg, &[0x03]);
+        let data = transaction.clock_data_vec(ClockData::MsbPosIn, &[0xa5, 0x5a]);
+        transaction.set_gpio_lower(0x08, 0x0b);
+        let response = transaction.execute(&mut emulator).unwrap();
+
+        assert_eq!(response.get(data), [0x34, 0x56]);
+        assert_eq!(slave.borrow().received(), [0x03, 0xa5, 0x5a]);
+    }
+
+    #[test]
+    fn spi_slave_ignores_clocks_while_deselected() {
+        let slave = Rc::new(RefCell::new(SpiSlave::new()));
+        slave.borrow_mut().queue(&[0x00]);
+        let mut emulator = emulator(slave.clone());
+
+        let mut transaction = Transaction::new();
+        transaction.set_gpio_lower(0x08, 0x0b);
+        let data = transaction.clock_data::<1>(ClockData::MsbPosIn, &[0x9f]);
+        let response = transaction.execute(&mut emulator).unwrap();
+
+        assert_eq!(response.get(data), [0xff]);
+        assert!(slave.borrow().received().is_empty());
+    }
+
+    // SCL on AD0, SDA driven on AD1 and read back on AD2, both idling low
+    // between bits as in AN255
+
+    fn i2c_start(transaction: &mut Transaction) {
+        transaction
+            .set_gpio_lower(0x03, 0x03)
+            .set_gpio_lower(0x01, 0x03)
+            .set_gpio_lower(0x00, 0x03);
+    }
+
+    fn i2c_stop(transaction: &mut Transaction) {
+        transaction
+            .set_gpio_lower(0x00, 0x03)
+            .set_gpio_lower(0x01, 0x03)
+            .set_gpio_lower(0x03, 0x03);
+    }
+
+    /// Writes a byte and returns the handle of the ACK bit, low when acknowledged
+    fn i2c_write(transaction: &mut Transaction, byte: u8) -> BitsHandle {
+        transaction
+            .set_gpio_lower(0x00, 0x03)
+            .clock_data_out(ClockDataOut::MsbNeg, &[byte])
+            .set_gpio_lower(0x00, 0x01);
+        transaction.clock_bits_in(ClockBitsIn::MsbPos, 1)
+    }
+
+    fn i2c_read(transaction: &mut Transaction, ack: bool) -> ReadHandle<[u8; 1]> {
+        transaction.set_gpio_lower(0x00, 0x01);
+        let byte = transaction.clock_data_in::<1>(ClockDataIn::MsbPos);
+        let sda = if ack { 0x00 } else { 0x02 };
+        transaction
+            .set_gpio_lower(sda, 0x03)
+            .clock_bits_out(ClockBitsOut::MsbNeg, sda << 6, 1);
+        byte
+    }
+
+    #[test]
+    fn i2c_write_then_read() {
+        let slave = Rc::new(RefCell::new(I2cSlave::new(0x50)));
+        slave.borrow_mut().queue(&[0xc3, 0x3c]);
+        let mut emulator = emulator(slave.clone());
+
+        let mut transaction = Transaction::new();
+        i2c_start(&mut transaction);
+        let address_ack = i2c_write(&mut transaction, 0x50 << 1);
+        let data_ack = i2c_write(&mut transaction, 0x42);
+        i2c_start(&mut transaction);
+        let read_ack = i2c_write(&mut transaction, 0x50 << 1 | 1);
+        let first = i2c_read(&mut transaction, true);
+        let second = i2c_read(&mut transaction, false);
+        i2c_stop(&mut transaction);
+        let response = transaction.execute(&mut emulator).unwrap();
+
+        assert_eq!(response.get(address_ack), 0);
+        assert_eq!(response.get(data_ack), 0);
+        assert_eq!(response.get(read_ack), 0);
+        assert_eq!(response.get(first), [0xc3]);
+        assert_eq!(response.get(second), [0x3c]);
+        assert_eq!(slave.borrow().received(), [0x42]);
+    }
+
+    #[test]
+    fn i2c_other_address_is_not_acknowledged() {
+        let slave = Rc::new(RefCell::new(I2cSlave::new(0x50)));
+        let mut emulator = emulator(slave.clone());
+
+        let mut transaction = Transaction::new();
+        i2c_start(&mut transaction);
+        let ack = i2c_write(&mut transaction, 0x51 << 1);
+        let data_ack = i2c_write(&mut transaction, 0x42);
+        i2c_stop(&mut transaction);
+        let response = transaction.execute(&mut emulator).unwrap();
+
+        assert_eq!(response.get(ack), 1);
+        assert_eq!(response.get(data_ack), 1);
+        assert!(slave.borrow().received().is_empty());
+    }
+
+    #[test]
+    fn jtag_idcode_is_shifted_out() {
+        let tap = Rc::new(RefCell::new(JtagTap::new(4).with_idcode(0x1, 0x4ba00477)));
+        let mut emulator = emulator(tap.clone());
+
+        let mut transaction = Transaction::new();
+        transaction
+            .set_gpio_lower(0x08, 0x0b)
+            // Test-Logic-Reset, then Run-Test/Idle and Shift-DR
+            .clock_tms_out(ClockTMSOut::NegEdge, 0x1f, false, 5)
+            .clock_tms_out(ClockTMSOut::NegEdge, 0x02, false, 4);
+        let idcode = transaction.clock_data_in::<4>(ClockDataIn::LsbPos);
+        let response = transaction.execute(&mut emulator).unwrap();
+
+        assert_eq!(u32::from_le_bytes(response.get(idcode)), 0x4ba00477);
+        assert_eq!(tap.borrow().state(), TapState::ShiftDr);
+        assert_eq!(tap.borrow().instruction(), 0x1);
+    }
+
+    #[test]
+    fn gpio_loopback_and_tdi_loopback() {
+        let mut emulator = emulator(GpioLoopback::new().connect(4, 13));
+
+        let mut transaction = Transaction::new();
+        transaction
+            .set_gpio_lower(0x00, 0x1b)
+            .set_gpio_upper(0x00, 0x00);
+        let upper = transaction.gpio_upper();
+        transaction.enable_loopback();
+        let echo = transaction.clock_data::<2>(ClockData::MsbPosIn, &[0xa5, 0x0f]);
+        let response = transaction.execute(&mut emulator).unwrap();
+
+        assert_eq!(response.get(upper), 0xdf);
+        assert_eq!(response.get(echo), [0xa5, 0x0f]);
+        assert!(emulator.is_loopback_enabled());
+    }
+
+    #[test]
+    fn bad_commands_are_echoed() {
+        let mut emulator = emulator(GpioLoopback::new());
+        emulator.send(&[0x81, 0xab]).unwrap();
+        assert_eq!(emulator.pending_response(), 3);
+        match emulator.recv(&mut [0]) {
+            Err(Error::BadCommand(0xab)) => {}
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn commands_split_across_writes_are_completed() {
+        let mut emulator = emulator(GpioLoopback::new());
+        emulator.send(&[0x86, 0x05]).unwrap();
+        emulator.send(&[0x00, 0x8a]).unwrap();
+        assert_eq!(emulator.clock_frequency(), 5_000_000);
+    }
+}
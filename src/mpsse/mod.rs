@@ -0,0 +1,355 @@
+//! Helpers for building and executing MPSSE command streams
+//!
+//! [`Transaction`] accumulates commands on top of [`MpsseCmdBuilder`], keeping
+//! track of the response bytes each of them produces. The whole transaction is
+//! sent with a single USB write, and every reading command returns a handle
+//! for extracting its typed result from the [`Response`].
+//!
+//! [`disasm`] decodes a command stream back into readable commands, and
+//! [`emulator`] executes it against simulated peripherals.
+
+pub mod disasm;
+pub mod emulator;
+
+use std::marker::PhantomData;
+
+use ftdi_mpsse::{
+    ClockBits, ClockBitsIn, ClockBitsOut, ClockData, ClockDataIn, ClockDataOut, ClockTMS,
+    ClockTMSOut, MpsseCmdBuilder, MpsseCmdExecutor,
+};
+
+/// Maximum number of bytes clocked by a single data command
+const MAX_DATA_LEN: usize = 65536;
+
+/// A type that can be decoded from the response bytes of a command
+pub trait FromResponse: Sized {
+    fn from_response(data: &[u8]) -> Self;
+}
+
+impl FromResponse for u8 {
+    fn from_response(data: &[u8]) -> Self {
+        data[0]
+    }
+}
+
+impl<const N: usize> FromResponse for [u8; N] {
+    fn from_response(data: &[u8]) -> Self {
+        let mut array = [0u8; N];
+        array.copy_from_slice(data);
+        array
+    }
+}
+
+impl FromResponse for Vec<u8> {
+    fn from_response(data: &[u8]) -> Self {
+        data.to_vec()
+    }
+}
+
+/// A handle to a result of a command in a [`Response`]
+pub trait Handle {
+    type Output;
+
+    fn decode(&self, response: &[u8]) -> Self::Output;
+}
+
+/// A handle to the response bytes of a command
+#[derive(Debug)]
+pub struct ReadHandle<T> {
+    offset: usize,
+    len: usize,
+    _marker: PhantomData<fn() -> T>,
+}
+
+impl<T> Clone for ReadHandle<T> {
+    fn clone(&self) -> Self {
+        *self
+    }
+}
+
+impl<T> Copy for ReadHandle<T> {}
+
+impl<T: FromResponse> Handle for ReadHandle<T> {
+    type Output = T;
+
+    fn decode(&self, response: &[u8]) -> T {
+        T::from_response(&response[self.offset..self.offset + self.len])
+    }
+}
+
+/// A handle to the result of a bit-mode read, decoded with the bits right-aligned
+#[derive(Clone, Copy, Debug)]
+pub struct BitsHandle {
+    offset: usize,
+    len: u8,
+    lsb_first: bool,
+}
+
+impl Handle for BitsHandle {
+    type Output = u8;
+
+    fn decode(&self, response: &[u8]) -> u8 {
+        match self.len {
+            0 => 0,
+            len if self.lsb_first => response[self.offset] >> (8 - len),
+            len => response[self.offset] & (0xff >> (8 - len)),
+        }
+    }
+}
+
+/// The response bytes of an executed [`Transaction`]
+#[derive(Clone, Debug)]
+pub struct Response {
+    data: Vec<u8>,
+}
+
+impl Response {
+    pub fn get<H: Handle>(&self, handle: H) -> H::Output {
+        handle.decode(&self.data)
+    }
+
+    pub fn as_slice(&self) -> &[u8] {
+        &self.data
+    }
+
+    pub fn into_inner(self) -> Vec<u8> {
+        self.data
+    }
+}
+
+/// A batch of MPSSE commands executed with a single USB write
+///
+/// # Example
+///
+/// ```no_run
+/// use ftdi::mpsse::Transaction;
+/// use ftdi_mpsse::{ClockData, ClockDataIn};
+///
+/// # fn run<B: ftdi::Backend>(device: &mut ftdi::Device<B>) -> std::io::Result<()> {
+/// let mut transaction = Transaction::new();
+/// transaction.set_gpio_lower(0x00, 0x0b);
+/// let id = transaction.clock_data(ClockData::MsbPosIn, &[0x9f, 0, 0, 0]);
+/// let status = transaction.clock_data_in::<1>(ClockDataIn::MsbPos);
+/// transaction.set_gpio_lower(0x08, 0x0b);
+/// let pins = transaction.gpio_lower();
+///
+/// let response = transaction.execute(device)?;
+/// let id: [u8; 4] = response.get(id);
+/// let status: [u8; 1] = response.get(status);
+/// let pins: u8 = response.get(pins);
+/// # Ok(())
+/// # }
+/// ```
+#[derive(Default)]
+pub struct Transaction {
+    cmd: MpsseCmdBuilder,
+    response_len: usize,
+}
+
+impl Transaction {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Number of response bytes the transaction will produce
+    pub fn response_len(&self) -> usize {
+        self.response_len
+    }
+
+    /// The commands accumulated so far
+    pub fn as_slice(&self) -> &[u8] {
+        self.cmd.as_slice()
+    }
+
+    /// Sends all the commands and reads back their responses
+    pub fn execute<E: MpsseCmdExecutor>(self, executor: &mut E) -> Result<Response, E::Error> {
+        let mut data = vec![0u8; self.response_len];
+        if self.response_len == 0 {
+            executor.send(self.cmd.as_slice())?;
+        } else {
+            let cmd = self.cmd.send_immediate();
+            executor.xfer(cmd.as_slice(), &mut data)?;
+        }
+        Ok(Response { data })
+    }
+
+    fn push(&mut self, f: impl FnOnce(MpsseCmdBuilder) -> MpsseCmdBuilder) {
+        self.cmd = f(std::mem::take(&mut self.cmd));
+    }
+
+    fn read<T>(&mut self, len: usize) -> ReadHandle<T> {
+        let handle = ReadHandle {
+            offset: self.response_len,
+            len,
+            _marker: PhantomData,
+        };
+        self.response_len += len;
+        handle
+    }
+
+    /// A bit-mode read result; LSB-first reads shift the bits in from the top
+    fn read_bits(&mut self, lsb_first: bool, len: u8) -> BitsHandle {
+        let handle = BitsHandle {
+            offset: self.response_len,
+            len,
+            lsb_first,
+        };
+        if len > 0 {
+            self.response_len += 1;
+        }
+        handle
+    }
+
+    fn is_lsb_first(mode: u8) -> bool {
+        mode & 0x08 != 0
+    }
+
+    pub fn set_clock(&mut self, divisor: u32, clkdiv: Option<bool>) -> &mut Self {
+        self.push(|cmd| cmd.set_clock(divisor, clkdiv));
+        self
+    }
+
+    pub fn enable_loopback(&mut self) -> &mut Self {
+        self.push(MpsseCmdBuilder::enable_loopback);
+        self
+    }
+
+    pub fn disable_loopback(&mut self) -> &mut Self {
+        self.push(MpsseCmdBuilder::disable_loopback);
+        self
+    }
+
+    pub fn set_gpio_lower(&mut self, state: u8, direction: u8) -> &mut Self {
+        self.push(|cmd| cmd.set_gpio_lower(state, direction));
+        self
+    }
+
+    pub fn set_gpio_upper(&mut self, state: u8, direction: u8) -> &mut Self {
+        self.push(|cmd| cmd.set_gpio_upper(state, direction));
+        self
+    }
+
+    pub fn gpio_lower(&mut self) -> ReadHandle<u8> {
+        self.push(MpsseCmdBuilder::gpio_lower);
+        self.read(1)
+    }
+
+    pub fn gpio_upper(&mut self) -> ReadHandle<u8> {
+        self.push(MpsseCmdBuilder::gpio_upper);
+        self.read(1)
+    }
+
+    pub fn wait_on_io_high(&mut self) -> &mut Self {
+        self.push(MpsseCmdBuilder::wait_on_io_high);
+        self
+    }
+
+    pub fn wait_on_io_low(&mut self) -> &mut Self {
+        self.push(MpsseCmdBuilder::wait_on_io_low);
+        self
+    }
+
+    /// Clocks out `data`, split into several commands if it is longer than one command allows
+    pub fn clock_data_out(&mut self, mode: ClockDataOut, data: &[u8]) -> &mut Self {
+        for chunk in data.chunks(MAX_DATA_LEN) {
+            self.push(|cmd| cmd.clock_data_out(mode, chunk));
+        }
+        self
+    }
+
+    /// Clocks in `N` bytes
+    pub fn clock_data_in<const N: usize>(&mut self, mode: ClockDataIn) -> ReadHandle<[u8; N]> {
+        self.push(|cmd| cmd.clock_data_in(mode, N));
+        self.read(N)
+    }
+
+    /// Clocks in a number of bytes known only at runtime
+    ///
+    /// Reads longer than one command allows are split into several commands.
+    pub fn clock_data_in_vec(&mut self, mode: ClockDataIn, len: usize) -> ReadHandle<Vec<u8>> {
+        let mut remaining = len;
+        while remaining > 0 {
+            let chunk = remaining.min(MAX_DATA_LEN);
+            self.push(|cmd| cmd.clock_data_in(mode, chunk));
+            remaining -= chunk;
+        }
+        self.read(len)
+    }
+
+    pub fn clock_data<const N: usize>(
+        &mut self,
+        mode: ClockData,
+        data: &[u8; N],
+    ) -> ReadHandle<[u8; N]> {
+        self.push(|cmd| cmd.clock_data(mode, data));
+        self.read(N)
+    }
+
+    /// Clocks `data` out and in, split into several commands if it is longer than one
+    /// command allows
+    pub fn clock_data_vec(&mut self, mode: ClockData, data: &[u8]) -> ReadHandle<Vec<u8>> {
+        for chunk in data.chunks(MAX_DATA_LEN) {
+            self.push(|cmd| cmd.clock_data(mode, chunk));
+        }
+        self.read(data.len())
+    }
+
+    pub fn clock_bits_out(&mut self, mode: ClockBitsOut, data: u8, len: u8) -> &mut Self {
+        self.push(|cmd| cmd.clock_bits_out(mode, data, len));
+        self
+    }
+
+    pub fn clock_bits_in(&mut self, mode: ClockBitsIn, len: u8) -> BitsHandle {
+        self.push(|cmd| cmd.clock_bits_in(mode, len));
+        self.read_bits(Self::is_lsb_first(mode.into()), len)
+    }
+
+    pub fn clock_bits(&mut self, mode: ClockBits, data: u8, len: u8) -> BitsHandle {
+        self.push(|cmd| cmd.clock_bits(mode, data, len));
+        self.read_bits(Self::is_lsb_first(mode.into()), len)
+    }
+
+    pub fn clock_tms_out(&mut self, mode: ClockTMSOut, data: u8, tdi: bool, len: u8) -> &mut Self {
+        self.push(|cmd| cmd.clock_tms_out(mode, data, tdi, len));
+        self
+    }
+
+    /// Clocks TMS out while reading TDO, the TDO bits being shifted in from the top
+    pub fn clock_tms(&mut self, mode: ClockTMS, data: u8, tdi: bool, len: u8) -> BitsHandle {
+        self.push(|cmd| cmd.clock_tms(mode, data, tdi, len));
+        self.read_bits(true, len)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn long_reads_are_split_into_several_commands() {
+        let mut transaction = Transaction::new();
+        let data = transaction.clock_data_in_vec(ClockDataIn::MsbPos, 70000);
+        let opcode = ClockDataIn::MsbPos as u8;
+        assert_eq!(
+            transaction.as_slice(),
+            [opcode, 0xff, 0xff, opcode, 0x6f, 0x11]
+        );
+        assert_eq!(transaction.response_len(), 70000);
+
+        let response = Response {
+            data: (0..70000).map(|i| i as u8).collect(),
+        };
+        assert_eq!(response.get(data), response.as_slice());
+    }
+
+    #[test]
+    fn long_writes_are_split_into_several_commands() {
+        let mut transaction = Transaction::new();
+        transaction.clock_data_out(ClockDataOut::MsbNeg, &[0x5a; 65537]);
+        let cmd = transaction.as_slice();
+        let opcode = ClockDataOut::MsbNeg as u8;
+        assert_eq!(cmd.len(), 3 + 65536 + 3 + 1);
+        assert_eq!(cmd[..3], [opcode, 0xff, 0xff]);
+        assert_eq!(cmd[3 + 65536..], [opcode, 0x00, 0x00, 0x5a]);
+    }
+}
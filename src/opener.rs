@@ -1,11 +1,35 @@
-use std::ffi::CString;
+use std::ffi::{CStr, CString};
+use std::os::raw::c_char;
+use std::thread;
+use std::time::{Duration, Instant};
 
-use super::{ffi, Device, Error, Interface, Result};
+use super::error::ErrorContext;
+#[cfg(feature = "libusb1-sys")]
+use super::ChipType;
+use super::{ffi, trace, Device, DeviceId, Error, Interface, ModuleDetachMode, Result};
 
 pub trait Target {
     fn open_in_context(self, context: *mut ffi::ftdi_context) -> Result<()>;
+
+    /// What is known about the device before opening it, for error reporting
+    fn device_id(&self) -> DeviceId {
+        DeviceId::default()
+    }
+}
+
+fn open_failed(
+    context: *mut ffi::ftdi_context,
+    operation: &'static str,
+    code: i32,
+    device: &DeviceId,
+) -> ErrorContext {
+    ErrorContext::new(operation)
+        .with_code(code)
+        .with_libftdi_message(context)
+        .with_device(device)
 }
 
+#[derive(Clone)]
 pub struct BusAddress {
     bus: u8,
     address: u8,
@@ -14,6 +38,8 @@ pub struct BusAddress {
 impl Target for BusAddress {
     fn open_in_context(self, context: *mut ffi::ftdi_context) -> Result<()> {
         let result = unsafe { ffi::ftdi_usb_open_bus_addr(context, self.bus, self.address) };
+        let device = self.device_id();
+        let failed = |code| open_failed(context, "ftdi_usb_open_bus_addr", code, &device);
         match result {
             0 => Ok(()),
             -1 => Err(Error::EnumerationFailed), // usb_find_busses() failed
@@ -21,18 +47,26 @@ impl Target for BusAddress {
             -3 => Err(Error::DeviceNotFound),    // usb device not found
             -4 => Err(Error::AccessFailed),      // unable to open device
             -5 => Err(Error::ClaimFailed),       // unable to claim device
-            -6 => Err(Error::RequestFailed),     // reset failed
-            -7 => Err(Error::RequestFailed),     // set baudrate failed
+            -6 => Err(Error::request_failed(failed(result))), // reset failed
+            -7 => Err(Error::request_failed(failed(result))), // set baudrate failed
             -8 => Err(Error::EnumerationFailed), // get product description failed
             -9 => Err(Error::EnumerationFailed), // get serial number failed
-            -10 => Err(Error::unknown(context)), // unable to close device
-            -11 => unreachable!("uninitialized context"), // ftdi context invalid
+            -10 => Err(Error::unknown(failed(result))), // unable to close device
             -12 => Err(Error::EnumerationFailed), // libusb_get_device_list() failed
-            _ => Err(Error::unknown(context)),
+            _ => Err(Error::unknown(failed(result))),
+        }
+    }
+
+    fn device_id(&self) -> DeviceId {
+        DeviceId {
+            bus: Some(self.bus),
+            address: Some(self.address),
+            ..DeviceId::default()
         }
     }
 }
 
+#[derive(Clone)]
 pub struct UsbProperties {
     vid: u16,
     pid: u16,
@@ -43,11 +77,16 @@ pub struct UsbProperties {
 
 impl Target for UsbProperties {
     fn open_in_context(self, context: *mut ffi::ftdi_context) -> Result<()> {
+        let device = self.device_id();
+        let failed = |code| open_failed(context, "ftdi_usb_open_desc_index", code, &device);
         let description = self
             .description
-            .map(|s| s.as_ptr())
-            .unwrap_or(std::ptr::null());
-        let serial = self.serial.map(|s| s.as_ptr()).unwrap_or(std::ptr::null());
+            .as_ref()
+            .map_or(std::ptr::null(), |s| s.as_ptr());
+        let serial = self
+            .serial
+            .as_ref()
+            .map_or(std::ptr::null(), |s| s.as_ptr());
         let index = self.index.unwrap_or(0).into();
         let result = unsafe {
             ffi::ftdi_usb_open_desc_index(
@@ -66,21 +105,38 @@ impl Target for UsbProperties {
             -3 => Err(Error::DeviceNotFound),    // usb device not found
             -4 => Err(Error::AccessFailed),      // unable to open device
             -5 => Err(Error::ClaimFailed),       // unable to claim device
-            -6 => Err(Error::RequestFailed),     // reset failed
-            -7 => Err(Error::RequestFailed),     // set baudrate failed
+            -6 => Err(Error::request_failed(failed(result))), // reset failed
+            -7 => Err(Error::request_failed(failed(result))), // set baudrate failed
             -8 => Err(Error::EnumerationFailed), // get product description failed
             -9 => Err(Error::EnumerationFailed), // get serial number failed
-            -10 => Err(Error::unknown(context)), // unable to close device
-            -11 => unreachable!("uninitialized context"), // ftdi context invalid
+            -10 => Err(Error::unknown(failed(result))), // unable to close device
             -12 => Err(Error::EnumerationFailed), // libusb_get_device_list() failed
-            _ => Err(Error::unknown(context)),
+            _ => Err(Error::unknown(failed(result))),
+        }
+    }
+
+    fn device_id(&self) -> DeviceId {
+        DeviceId {
+            vid: Some(self.vid),
+            pid: Some(self.pid),
+            serial: self
+                .serial
+                .as_ref()
+                .map(|serial| serial.to_string_lossy().into_owned()),
+            ..DeviceId::default()
         }
     }
 }
 
+/// A builder for opening a device
+///
+/// Invalid settings do not panic. The first one is remembered and reported
+/// as an error by [`open`](Opener::open).
 pub struct Opener<T: Target> {
     target: T,
     interface: Option<Interface>,
+    detach_mode: Option<ModuleDetachMode>,
+    error: Option<Error>,
 }
 
 impl<T: Target> Opener<T> {
@@ -88,55 +144,164 @@ impl<T: Target> Opener<T> {
         Self {
             target,
             interface: None,
+            detach_mode: None,
+            error: None,
         }
     }
 
+    fn fail(mut self, message: &'static str) -> Self {
+        self.error.get_or_insert(Error::InvalidInput(message));
+        self
+    }
+
     pub fn interface(mut self, interface: Interface) -> Self {
-        assert!(self.interface.is_none(), "interface already set");
+        if self.interface.is_some() {
+            return self.fail("interface already set");
+        }
         self.interface = Some(interface);
         self
     }
 
+    /// Sets what happens to the kernel driver bound to the interface
+    ///
+    /// With [`ModuleDetachMode::AutoDetachReattach`], a channel used as a
+    /// `/dev/ttyUSB*` serial port is handed back to the kernel when the
+    /// [`Device`] is dropped.
+    pub fn module_detach_mode(mut self, mode: ModuleDetachMode) -> Self {
+        if self.detach_mode.is_some() {
+            return self.fail("module detach mode already set");
+        }
+        self.detach_mode = Some(mode);
+        self
+    }
+
     pub fn open(self) -> Result<Device> {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
         let context = unsafe { ffi::ftdi_new() };
 
         if context.is_null() {
             return Err(Error::AllocationFailed);
         }
 
-        if let Some(interface) = self.interface {
+        let device = self.target.device_id();
+        let span = trace::Span::enter(Some(&device), "open");
+        if let Some(mode) = self.detach_mode {
+            unsafe { (*context).module_detach_mode = mode.into() };
+        }
+        let result = Self::open_in_context(self.target, self.interface, context);
+        let result = span.finish(self.interface, result);
+        match result {
+            Ok(()) => Ok(Device::from_context(context, device)),
+            Err(error) => {
+                unsafe { ffi::ftdi_free(context) };
+                Err(error)
+            }
+        }
+    }
+
+    /// Opens the device, waiting up to `timeout` for it to become available
+    ///
+    /// A device that is not found, cannot be accessed or has its interface
+    /// claimed is polled for again with an increasing delay, as happens while an
+    /// adapter re-enumerates after a USB reset or a firmware update. The error of
+    /// the last attempt is returned when the timeout expires.
+    pub fn open_timeout(self, timeout: Duration) -> Result<Device>
+    where
+        T: Clone,
+    {
+        if let Some(error) = self.error {
+            return Err(error);
+        }
+
+        let deadline = Instant::now() + timeout;
+        let mut delay = Duration::from_millis(10);
+        loop {
+            match self.attempt().open() {
+                Err(Error::DeviceNotFound) | Err(Error::AccessFailed) | Err(Error::ClaimFailed)
+                    if Instant::now() < deadline =>
+                {
+                    thread::sleep(delay.min(deadline.saturating_duration_since(Instant::now())));
+                    delay = (delay * 2).min(Duration::from_millis(250));
+                }
+                result => return result,
+            }
+        }
+    }
+
+    /// A copy of the settings for another attempt at opening the device
+    pub(crate) fn attempt(&self) -> Self
+    where
+        T: Clone,
+    {
+        Opener {
+            target: self.target.clone(),
+            interface: self.interface,
+            detach_mode: self.detach_mode,
+            error: None,
+        }
+    }
+
+    /// The settings of `opener`, with the interface and detach mode of this opener
+    pub(crate) fn retarget<U: Target>(&self, opener: Opener<U>) -> Opener<U> {
+        Opener {
+            interface: self.interface,
+            detach_mode: self.detach_mode,
+            ..opener
+        }
+    }
+
+    fn open_in_context(
+        target: T,
+        interface: Option<Interface>,
+        context: *mut ffi::ftdi_context,
+    ) -> Result<()> {
+        if let Some(interface) = interface {
             let result = unsafe { ffi::ftdi_set_interface(context, interface.into()) };
             match result {
                 0 => Ok(()),
-                -1 => unreachable!("unknown interface from ftdi.h"),
-                -2 => unreachable!("missing context"),
-                -3 => unreachable!("device already opened in Builder"),
-                _ => Err(Error::unknown(context)),
+                -1 => Err(Error::InvalidInput("unknown interface")),
+                _ => Err(Error::unknown(
+                    ErrorContext::new("ftdi_set_interface")
+                        .with_code(result)
+                        .with_libftdi_message(context),
+                )),
             }?;
         }
 
-        self.target.open_in_context(context)?;
-
-        Ok(Device { context })
+        target.open_in_context(context)
     }
 }
 
 impl Opener<UsbProperties> {
     pub fn description(mut self, description: &str) -> Self {
-        assert!(self.target.description.is_none(), "description already set");
-        self.target.description =
-            Some(CString::new(description).expect("serial should not contain NUL"));
+        if self.target.description.is_some() {
+            return self.fail("description already set");
+        }
+        match CString::new(description) {
+            Ok(description) => self.target.description = Some(description),
+            Err(_) => return self.fail("description should not contain NUL"),
+        }
         self
     }
 
     pub fn serial(mut self, serial: &str) -> Self {
-        assert!(self.target.serial.is_none(), "serial already set");
-        self.target.serial = Some(CString::new(serial).expect("serial should not contain NUL"));
+        if self.target.serial.is_some() {
+            return self.fail("serial already set");
+        }
+        match CString::new(serial) {
+            Ok(serial) => self.target.serial = Some(serial),
+            Err(_) => return self.fail("serial should not contain NUL"),
+        }
         self
     }
 
     pub fn nth(mut self, index: u32) -> Self {
-        assert!(self.target.index.is_none(), "index already set");
+        if self.target.index.is_some() {
+            return self.fail("index already set");
+        }
         self.target.index = Some(index);
         self
     }
@@ -156,10 +321,281 @@ pub fn find_by_bus_address(bus: u8, address: u8) -> Opener<BusAddress> {
     Opener::new(BusAddress { bus, address })
 }
 
-#[cfg(feature = "libusb1-sys")]
+/// A device selected by a URL, see [`find_by_url`]
+#[derive(Clone)]
+pub enum UrlTarget {
+    UsbProperties(UsbProperties),
+    BusAddress(BusAddress),
+    /// Any FTDI product of the vendor
+    ///
+    /// The index counts the devices of all the known products, listed product by
+    /// product in the order of their IDs.
+    AnyProduct {
+        vid: u16,
+        serial: Option<CString>,
+        index: Option<u32>,
+    },
+}
+
+impl UrlTarget {
+    fn with_product(vid: u16, pid: u16, serial: Option<CString>, index: Option<u32>) -> Self {
+        UrlTarget::UsbProperties(UsbProperties {
+            vid,
+            pid,
+            description: None,
+            serial,
+            index,
+        })
+    }
+}
+
+impl Target for UrlTarget {
+    fn open_in_context(self, context: *mut ffi::ftdi_context) -> Result<()> {
+        let device_id = self.device_id();
+        match self {
+            UrlTarget::UsbProperties(target) => target.open_in_context(context),
+            UrlTarget::BusAddress(target) => target.open_in_context(context),
+            UrlTarget::AnyProduct { vid, serial, index } => {
+                let mut lists = Vec::new();
+                let result = find_any_product(context, vid, &mut lists).and_then(|devices| {
+                    let matches = |&device: &*mut libusb_device| match &serial {
+                        Some(serial) => has_serial(context, device, serial),
+                        None => Ok(true),
+                    };
+                    match nth_match(devices, matches, index.unwrap_or(0))? {
+                        Some(device) => open_libusb_device(context, device, &device_id),
+                        None => Err(Error::DeviceNotFound),
+                    }
+                });
+                for mut list in lists {
+                    unsafe { ffi::ftdi_list_free(&mut list) };
+                }
+                result
+            }
+        }
+    }
+
+    fn device_id(&self) -> DeviceId {
+        match self {
+            UrlTarget::UsbProperties(target) => target.device_id(),
+            UrlTarget::BusAddress(target) => target.device_id(),
+            UrlTarget::AnyProduct { vid, serial, .. } => DeviceId {
+                vid: Some(*vid),
+                serial: serial
+                    .as_ref()
+                    .map(|serial| serial.to_string_lossy().into_owned()),
+                ..DeviceId::default()
+            },
+        }
+    }
+}
+
+const VENDORS: [(&str, u16); 1] = [("ftdi", 0x0403)];
+
+/// Product aliases, grouped by product ID
+const PRODUCTS: [(&str, u16); 12] = [
+    ("232", 0x6001),
+    ("232r", 0x6001),
+    ("245r", 0x6001),
+    ("2232", 0x6010),
+    ("2232d", 0x6010),
+    ("2232h", 0x6010),
+    ("4232", 0x6011),
+    ("4232h", 0x6011),
+    ("232h", 0x6014),
+    ("230x", 0x6015),
+    ("231x", 0x6015),
+    ("234x", 0x6015),
+];
+
+/// The known product IDs, in ascending order
+fn product_ids() -> Vec<u16> {
+    let mut pids: Vec<u16> = PRODUCTS.iter().map(|&(_, pid)| pid).collect();
+    pids.dedup();
+    pids
+}
+
+/// Lists the devices of the vendor with any of the known product IDs
+///
+/// The lists returned by `libftdi` are added to `lists` even on failure, to be
+/// freed by the caller once done with the devices.
+fn find_any_product(
+    context: *mut ffi::ftdi_context,
+    vid: u16,
+    lists: &mut Vec<*mut ffi::ftdi_device_list>,
+) -> Result<Vec<*mut libusb_device>> {
+    let mut devices = Vec::new();
+    for pid in product_ids() {
+        let mut list = std::ptr::null_mut();
+        let count = unsafe { ffi::ftdi_usb_find_all(context, &mut list, vid.into(), pid.into()) };
+        lists.push(list);
+        if count < 0 {
+            return Err(Error::EnumerationFailed);
+        }
+        let mut node = list;
+        while !node.is_null() {
+            unsafe {
+                devices.push((*node).dev);
+                node = (*node).next;
+            }
+        }
+    }
+    Ok(devices)
+}
+
+fn has_serial(
+    context: *mut ffi::ftdi_context,
+    device: *mut libusb_device,
+    serial: &CStr,
+) -> Result<bool> {
+    let mut buf = [0 as c_char; 128];
+    let result = unsafe {
+        ffi::ftdi_usb_get_strings(
+            context,
+            device,
+            std::ptr::null_mut(),
+            0,
+            std::ptr::null_mut(),
+            0,
+            buf.as_mut_ptr(),
+            buf.len() as i32,
+        )
+    };
+    if result < 0 {
+        return Err(Error::EnumerationFailed);
+    }
+    Ok(unsafe { CStr::from_ptr(buf.as_ptr()) } == serial)
+}
+
+/// Picks the `index`-th of the devices that match, in the order given
+fn nth_match<D>(
+    devices: impl IntoIterator<Item = D>,
+    mut matches: impl FnMut(&D) -> Result<bool>,
+    index: u32,
+) -> Result<Option<D>> {
+    let mut remaining = index;
+    for device in devices {
+        if !matches(&device)? {
+            continue;
+        }
+        if remaining == 0 {
+            return Ok(Some(device));
+        }
+        remaining -= 1;
+    }
+    Ok(None)
+}
+
+/// Parses a vendor or product, given either as an alias or as a hexadecimal ID
+fn parse_id(value: &str, aliases: &[(&str, u16)]) -> Option<u16> {
+    let value = value.to_ascii_lowercase();
+    // Product aliases may also be spelled with the `ft` prefix, as in `ft2232h`
+    let unprefixed = value.strip_prefix("ft");
+    if let Some(&(_, id)) = aliases
+        .iter()
+        .find(|&&(name, _)| name == value || Some(name) == unprefixed)
+    {
+        return Some(id);
+    }
+    let hex = value.strip_prefix("0x").unwrap_or(&value);
+    u16::from_str_radix(hex, 16).ok()
+}
+
+fn parse_number(value: &str) -> Option<u8> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
+fn parse_url(url: &str) -> std::result::Result<(UrlTarget, Option<Interface>), &'static str> {
+    let rest = url
+        .strip_prefix("ftdi://")
+        .ok_or("URL scheme should be ftdi://")?;
+    let (locator, interface) = rest.split_once('/').unwrap_or((rest, ""));
+    let interface = match interface.trim_end_matches('/') {
+        "" => None,
+        "1" => Some(Interface::A),
+        "2" => Some(Interface::B),
+        "3" => Some(Interface::C),
+        "4" => Some(Interface::D),
+        _ => return Err("URL interface should be 1 to 4"),
+    };
+
+    let parts: Vec<&str> = locator.split(':').collect();
+    let vid = match parts[0] {
+        "" => 0x0403,
+        vendor => parse_id(vendor, &VENDORS).ok_or("unknown vendor in URL")?,
+    };
+    let pid = match parts.get(1).copied().unwrap_or("") {
+        "" => None,
+        product => Some(parse_id(product, &PRODUCTS).ok_or("unknown product in URL")?),
+    };
+
+    let target = match parts.get(2..).unwrap_or(&[]) {
+        [] => None,
+        [serial_or_index] if serial_or_index.bytes().all(|b| b.is_ascii_digit()) => {
+            let index = serial_or_index
+                .parse()
+                .map_err(|_| "invalid index in URL")?;
+            Some((None, Some(index)))
+        }
+        [serial] => {
+            let serial = CString::new(*serial).map_err(|_| "serial should not contain NUL")?;
+            Some((Some(serial), None))
+        }
+        [bus, address] => {
+            let bus = parse_number(bus).ok_or("invalid bus in URL")?;
+            let address = parse_number(address).ok_or("invalid address in URL")?;
+            return Ok((
+                UrlTarget::BusAddress(BusAddress { bus, address }),
+                interface,
+            ));
+        }
+        _ => return Err("too many parts in URL"),
+    };
+    let (serial, index) = target.unwrap_or((None, None));
+
+    let target = match pid {
+        Some(pid) => UrlTarget::with_product(vid, pid, serial, index),
+        None => UrlTarget::AnyProduct { vid, serial, index },
+    };
+    Ok((target, interface))
+}
+
+/// Selects a device with a URL in the syntax of `pyftdi`
+///
+/// The URL has the form `ftdi://[vendor][:[product][:serial|:index|:bus:address]][/interface]`:
+///
+/// - the vendor is `ftdi` or a hexadecimal vendor ID, FTDI if omitted;
+/// - the product is an alias such as `232h`, `2232h` or `ft4232h`, or a hexadecimal
+///   product ID, any known FTDI product if omitted;
+/// - a decimal number selects the n-th matching device, anything else is a serial
+///   number, and two numbers are the bus and address of the device; without a
+///   product, the devices of all the known products are counted;
+/// - the interface is numbered from 1 for interface A.
+///
+/// # Example
+///
+/// ```no_run
+/// # fn main() -> ftdi::Result<()> {
+/// let device = ftdi::find_by_url("ftdi://ftdi:2232h:FT1ABCDE/2")?.open()?;
+/// # Ok(())
+/// # }
+/// ```
+pub fn find_by_url(url: &str) -> Result<Opener<UrlTarget>> {
+    let (target, interface) = parse_url(url).map_err(Error::InvalidInput)?;
+    let opener = Opener::new(target);
+    Ok(match interface {
+        Some(interface) => opener.interface(interface),
+        None => opener,
+    })
+}
+
 use ffi::libusb1_sys::libusb_device;
 
 #[cfg(feature = "libusb1-sys")]
+#[derive(Clone)]
 pub struct LibusbDevice {
     device: *mut libusb_device,
 }
@@ -167,25 +603,250 @@ pub struct LibusbDevice {
 #[cfg(feature = "libusb1-sys")]
 impl Target for LibusbDevice {
     fn open_in_context(self, context: *mut ffi::ftdi_context) -> Result<()> {
-        let result = unsafe { ffi::ftdi_usb_open_dev(context, self.device) };
-        match result {
-            0 => Ok(()),
-            -3 => Err(Error::AccessFailed), // unable to config device
-            -4 => Err(Error::AccessFailed), // unable to open device
-            -5 => Err(Error::ClaimFailed),  // unable to claim device
-            -6 => Err(Error::RequestFailed), // reset failed
-            -7 => Err(Error::RequestFailed), // set baudrate failed
-            -8 => unreachable!("uninitialized context"), // ftdi context invalid
-            -9 => Err(Error::AccessFailed), // libusb_get_device_descriptor() failed
-            -10 => Err(Error::AccessFailed), // libusb_get_config_descriptor() failed
-            -11 => Err(Error::AccessFailed), // libusb_detach_kernel_driver() failed
-            -12 => Err(Error::AccessFailed), // libusb_get_configuration() failed
-            _ => Err(Error::unknown(context)),
+        open_libusb_device(context, self.device, &DeviceId::default())
+    }
+}
+
+fn open_libusb_device(
+    context: *mut ffi::ftdi_context,
+    device: *mut libusb_device,
+    device_id: &DeviceId,
+) -> Result<()> {
+    let result = unsafe { ffi::ftdi_usb_open_dev(context, device) };
+    let failed = |code| open_failed(context, "ftdi_usb_open_dev", code, device_id);
+    match result {
+        0 => Ok(()),
+        -3 => Err(Error::AccessFailed), // unable to config device
+        -4 => Err(Error::AccessFailed), // unable to open device
+        -5 => Err(Error::ClaimFailed),  // unable to claim device
+        -6 => Err(Error::request_failed(failed(result))), // reset failed
+        -7 => Err(Error::request_failed(failed(result))), // set baudrate failed
+        -9 => Err(Error::AccessFailed), // libusb_get_device_descriptor() failed
+        -10 => Err(Error::AccessFailed), // libusb_get_config_descriptor() failed
+        -11 => Err(Error::AccessFailed), // libusb_detach_kernel_driver() failed
+        -12 => Err(Error::AccessFailed), // libusb_get_configuration() failed
+        _ => Err(Error::unknown(failed(result))),
+    }
+}
+
+/// A device identified by the chain of hub ports it is plugged into
+#[cfg(feature = "libusb1-sys")]
+#[derive(Clone)]
+pub struct PortPath {
+    bus: u8,
+    ports: Vec<u8>,
+}
+
+#[cfg(feature = "libusb1-sys")]
+impl PortPath {
+    fn matches(&self, device: *mut libusb_device) -> bool {
+        use ffi::libusb1_sys as usb;
+
+        // USB 3 allows up to 7 tiers of hubs
+        let mut ports = [0u8; 7];
+        let len =
+            unsafe { usb::libusb_get_port_numbers(device, ports.as_mut_ptr(), ports.len() as i32) };
+        len >= 0
+            && unsafe { usb::libusb_get_bus_number(device) } == self.bus
+            && ports[..len as usize] == self.ports[..]
+    }
+}
+
+#[cfg(feature = "libusb1-sys")]
+impl Target for PortPath {
+    fn open_in_context(self, context: *mut ffi::ftdi_context) -> Result<()> {
+        use ffi::libusb1_sys as usb;
+
+        let mut list = std::ptr::null();
+        let count = unsafe { usb::libusb_get_device_list((*context).usb_ctx, &mut list) };
+        if count < 0 {
+            return Err(Error::EnumerationFailed);
+        }
+        let devices = unsafe { std::slice::from_raw_parts(list, count as usize) };
+        let result = match devices.iter().find(|&&device| self.matches(device)) {
+            Some(&device) => open_libusb_device(context, device, &self.device_id()),
+            None => Err(Error::DeviceNotFound),
+        };
+        unsafe { usb::libusb_free_device_list(list, 1) };
+        result
+    }
+
+    fn device_id(&self) -> DeviceId {
+        DeviceId {
+            bus: Some(self.bus),
+            port_path: Some(self.ports.clone()),
+            ..DeviceId::default()
         }
     }
 }
 
+/// Opens the device plugged into the given chain of hub ports on a bus
+///
+/// Unlike the address, the port path of a device stays the same when it is
+/// re-enumerated, so it can be used to select one of several identical devices
+/// by their physical location. The ports are the ones reported by
+/// `libusb_get_port_numbers`, the first one being the port of the root hub.
+#[cfg(feature = "libusb1-sys")]
+pub fn find_by_port_path(bus: u8, ports: &[u8]) -> Opener<PortPath> {
+    Opener::new(PortPath {
+        bus,
+        ports: ports.to_vec(),
+    })
+}
+
 #[cfg(feature = "libusb1-sys")]
 pub unsafe fn find_by_raw_libusb_device(device: *mut libusb_device) -> Opener<LibusbDevice> {
     Opener::new(LibusbDevice { device })
 }
+
+#[cfg(feature = "libusb1-sys")]
+impl<T: Target> Opener<T> {
+    /// Opens every interface of the selected chip, in order from interface A
+    ///
+    /// The chip is first opened on interface A, and its other interfaces are then
+    /// opened on the same USB device, so that all the devices returned are
+    /// channels of one physical chip even when several identical adapters are
+    /// connected. Single channel chips yield a single device.
+    pub fn open_all_interfaces(mut self) -> Result<Vec<Device>> {
+        if self.interface.is_some() {
+            self = self.fail("interface set when opening all interfaces");
+        }
+        self.interface = Some(Interface::A);
+        let detach_mode = self.detach_mode;
+        let mut first = self.open()?;
+
+        let others: &[Interface] = match first.chip_type() {
+            Some(ChipType::Ft2232C) | Some(ChipType::Ft2232H) => &[Interface::B],
+            Some(ChipType::Ft4232H) => &[Interface::B, Interface::C, Interface::D],
+            _ => &[],
+        };
+        // The device stays referenced while the first interface keeps it open
+        let device =
+            unsafe { ffi::libusb1_sys::libusb_get_device((*first.libftdi_context()).usb_dev) };
+        let mut devices = vec![first];
+        for &interface in others {
+            let mut opener = Opener::new(LibusbDevice { device }).interface(interface);
+            opener.detach_mode = detach_mode;
+            devices.push(opener.open()?);
+        }
+        Ok(devices)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn parse(url: &str) -> (UrlTarget, Option<Interface>) {
+        match parse_url(url) {
+            Ok(parsed) => parsed,
+            Err(message) => panic!("{}: {}", url, message),
+        }
+    }
+
+    fn usb_properties(url: &str) -> (UsbProperties, Option<Interface>) {
+        match parse(url) {
+            (UrlTarget::UsbProperties(target), interface) => (target, interface),
+            _ => panic!("{} should select by USB properties", url),
+        }
+    }
+
+    #[test]
+    fn url_with_ids_and_serial() {
+        let (target, interface) = usb_properties("ftdi://0403:6010:FT1ABCDE/2");
+        assert_eq!((target.vid, target.pid), (0x0403, 0x6010));
+        assert_eq!(
+            target
+                .serial
+                .as_ref()
+                .map(|serial| serial.to_str().unwrap()),
+            Some("FT1ABCDE")
+        );
+        assert_eq!(target.index, None);
+        assert_eq!(interface, Some(Interface::B));
+    }
+
+    #[test]
+    fn url_with_aliases() {
+        let (target, interface) = usb_properties("ftdi://ftdi:2232h/1");
+        assert_eq!((target.vid, target.pid), (0x0403, 0x6010));
+        assert!(target.serial.is_none());
+        assert_eq!(interface, Some(Interface::A));
+
+        let (target, _) = usb_properties("ftdi://FTDI:FT4232H");
+        assert_eq!(target.pid, 0x6011);
+        let (target, _) = usb_properties("ftdi://0x403:232h:3");
+        assert_eq!(
+            (target.vid, target.pid, target.index),
+            (0x0403, 0x6014, Some(3))
+        );
+    }
+
+    #[test]
+    fn url_without_product() {
+        for url in ["ftdi:///1", "ftdi://ftdi/1", "ftdi://ftdi::FT1ABCDE/1"] {
+            match parse(url) {
+                (UrlTarget::AnyProduct { vid, .. }, Some(Interface::A)) => assert_eq!(vid, 0x0403),
+                _ => panic!("{} should select any product", url),
+            }
+        }
+    }
+
+    #[test]
+    fn any_product_index_counts_the_devices_of_all_products() {
+        // As listed by `find_any_product`, product by product
+        let devices = [(0x6001, "A"), (0x6010, "B"), (0x6010, "C"), (0x6014, "A")];
+        let nth = |serial: Option<&str>, index| {
+            let matches = |&(_, found): &(u16, &str)| Ok(serial.map_or(true, |s| s == found));
+            nth_match(devices.iter().copied(), matches, index).unwrap()
+        };
+        assert_eq!(nth(None, 0), Some((0x6001, "A")));
+        assert_eq!(nth(None, 2), Some((0x6010, "C")));
+        assert_eq!(nth(None, 3), Some((0x6014, "A")));
+        assert_eq!(nth(None, 4), None);
+        assert_eq!(nth(Some("A"), 1), Some((0x6014, "A")));
+        assert_eq!(nth(Some("D"), 0), None);
+        assert_eq!(product_ids(), [0x6001, 0x6010, 0x6011, 0x6014, 0x6015]);
+    }
+
+    #[test]
+    fn url_with_bus_address() {
+        match parse("ftdi://ftdi:232h:1:0x1f") {
+            (UrlTarget::BusAddress(target), None) => {
+                assert_eq!((target.bus, target.address), (1, 0x1f))
+            }
+            _ => panic!("should select by bus and address"),
+        }
+    }
+
+    #[test]
+    fn retarget_keeps_the_interface_and_detach_mode() {
+        let opener = find_by_vid_pid(0x0403, 0x6010)
+            .interface(Interface::B)
+            .module_detach_mode(ModuleDetachMode::DontDetach);
+        let narrowed = opener.retarget(find_by_vid_pid(0x0403, 0x6010).serial("FT1ABCDE"));
+        assert_eq!(narrowed.interface, Some(Interface::B));
+        assert_eq!(narrowed.detach_mode, Some(ModuleDetachMode::DontDetach));
+        assert_eq!(
+            narrowed.target.device_id().serial.as_deref(),
+            Some("FT1ABCDE")
+        );
+
+        let invalid = opener.retarget(find_by_vid_pid(0x0403, 0x6010).serial("FT\0"));
+        assert!(matches!(invalid.error, Some(Error::InvalidInput(_))));
+    }
+
+    #[test]
+    fn invalid_urls() {
+        for url in [
+            "usb://ftdi:232h",
+            "ftdi://acme:232h",
+            "ftdi://ftdi:fancy",
+            "ftdi://ftdi:232h/5",
+            "ftdi://ftdi:232h:1:2:3",
+            "ftdi://ftdi:232h:1:bus",
+        ] {
+            assert!(parse_url(url).is_err(), "{} should be rejected", url);
+        }
+    }
+}
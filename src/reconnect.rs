@@ -0,0 +1,408 @@
+//! Automatic reconnection of a device after it was unplugged
+//!
+//! A [`ReconnectingDevice`] keeps the [`Opener`] it was created from and the
+//! configuration applied through it. When a read, write or configuration call
+//! reports the device as disconnected, the device is closed and the error is
+//! returned. The next call reopens the same adapter once it is back, applies the
+//! configuration again and carries on, the time spent without a device being
+//! available from [`ReconnectingDevice::take_reconnection`].
+//!
+//! # Example
+//!
+//! ```no_run
+//! use ftdi::reconnect::ReconnectingDevice;
+//! use std::io::{ErrorKind, Read};
+//! use std::time::Duration;
+//!
+//! # fn main() -> ftdi::Result<()> {
+//! let opener = ftdi::find_by_vid_pid(0x0403, 0x6014).interface(ftdi::Interface::A);
+//! let mut device = ReconnectingDevice::new(opener)?;
+//! device.set_reconnect_timeout(Duration::from_secs(1));
+//! device.set_baud_rate(115200)?;
+//!
+//! let mut buf = [0; 512];
+//! loop {
+//!     match device.read(&mut buf) {
+//!         Ok(len) => println!("{:02x?}", &buf[..len]),
+//!         Err(err) if err.kind() == ErrorKind::NotConnected => continue,
+//!         Err(err) => return Err(err.into()),
+//!     }
+//!     if let Some(reconnection) = device.take_reconnection() {
+//!         println!("device was gone for {:?}", reconnection.gap);
+//!     }
+//! }
+//! # }
+//! ```
+
+use std::io::{self, Read, Write};
+use std::time::{Duration, Instant};
+
+use crate::error::libftdi_to_io;
+#[cfg(feature = "libusb1-sys")]
+use crate::opener::find_by_port_path;
+use crate::opener::{find_by_vid_pid, Opener, Target};
+use crate::{trace, BitMode, Bits, Device, DeviceId, Error, FlowControl, Parity, Result, StopBits};
+
+/// The configuration to apply again after reopening the device
+#[derive(Clone, Copy, Debug, Default)]
+struct Config {
+    baud_rate: Option<u32>,
+    line_property: Option<(Bits, StopBits, Parity)>,
+    flow_control: Option<FlowControl>,
+    latency_timer: Option<u8>,
+    bitmode: Option<(u8, BitMode)>,
+}
+
+impl Config {
+    fn apply(&self, device: &mut Device) -> Result<()> {
+        if let Some(rate) = self.baud_rate {
+            device.set_baud_rate(rate)?;
+        }
+        if let Some((bits, stop_bits, parity)) = self.line_property {
+            device.configure(bits, stop_bits, parity)?;
+        }
+        if let Some(flowctrl) = self.flow_control {
+            device.set_flow_control(flowctrl)?;
+        }
+        if let Some(value) = self.latency_timer {
+            device.set_latency_timer(value)?;
+        }
+        if let Some((output_mask, mode)) = self.bitmode {
+            device.set_bitmode(output_mask, mode)?;
+        }
+        Ok(())
+    }
+}
+
+/// Whether a reopened device is the adapter that was disconnected
+///
+/// The serial number identifies the adapter when it has one, the hub ports it
+/// is plugged into otherwise. Unknown parts of the identity are not compared.
+fn same_adapter(expected: &DeviceId, found: &DeviceId) -> bool {
+    fn matches<T: PartialEq>(expected: &Option<T>, found: &Option<T>) -> bool {
+        match (expected, found) {
+            (Some(expected), Some(found)) => expected == found,
+            _ => true,
+        }
+    }
+
+    matches(&expected.vid, &found.vid)
+        && matches(&expected.pid, &found.pid)
+        && matches(&expected.serial, &found.serial)
+        && (expected.serial.is_some() || matches(&expected.port_path, &found.port_path))
+}
+
+/// Whether an error means the device is not available, rather than it failing
+fn is_unavailable(err: &Error) -> bool {
+    matches!(
+        err,
+        Error::Disconnected | Error::DeviceNotFound | Error::AccessFailed | Error::ClaimFailed
+    )
+}
+
+/// Whether a setting is remembered after trying to apply it
+///
+/// Settings the device rejected are dropped, while the ones that could not be
+/// applied because the device is gone are applied once it is reopened.
+fn keeps_setting(result: &Result<()>) -> bool {
+    match result {
+        Ok(()) => true,
+        Err(err) => is_unavailable(err),
+    }
+}
+
+/// A reconnection of the device after it was disconnected
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reconnection {
+    /// Time from the disconnection being detected to the device being reopened
+    pub gap: Duration,
+    /// The identity of the reopened device, whose address has usually changed
+    pub device: Option<DeviceId>,
+}
+
+/// A device that is reopened and reconfigured after being disconnected
+pub struct ReconnectingDevice<T: Target + Clone> {
+    opener: Opener<T>,
+    identity: Option<DeviceId>,
+    config: Config,
+    reconnect_timeout: Duration,
+    device: Option<Device>,
+    disconnected_at: Option<Instant>,
+    reconnection: Option<Reconnection>,
+}
+
+impl<T: Target + Clone> ReconnectingDevice<T> {
+    /// Opens the device, failing if it is not available right away
+    pub fn new(opener: Opener<T>) -> Result<Self> {
+        let template = opener.attempt();
+        let device = opener.open()?;
+        Ok(ReconnectingDevice {
+            opener: template,
+            identity: device.device_id().cloned(),
+            config: Config::default(),
+            reconnect_timeout: Duration::ZERO,
+            device: Some(device),
+            disconnected_at: None,
+            reconnection: None,
+        })
+    }
+
+    /// Sets how long each call waits for a disconnected device to come back
+    ///
+    /// The default of zero makes a single attempt at reopening the device, so that
+    /// calls fail right away while it is gone.
+    pub fn set_reconnect_timeout(&mut self, timeout: Duration) {
+        self.reconnect_timeout = timeout;
+    }
+
+    pub fn is_connected(&self) -> bool {
+        self.device.is_some()
+    }
+
+    /// The currently open device
+    ///
+    /// Calls made directly on it are not watched for disconnection, and
+    /// configuration applied through it is not restored after reconnecting.
+    pub fn device_mut(&mut self) -> Option<&mut Device> {
+        self.device.as_mut()
+    }
+
+    /// Takes the report of the last reconnection, if any since the last call
+    pub fn take_reconnection(&mut self) -> Option<Reconnection> {
+        self.reconnection.take()
+    }
+
+    /// Reopens the device if it was disconnected
+    pub fn reconnect(&mut self) -> Result<&mut Device> {
+        let device = match self.device.take() {
+            Some(device) => device,
+            None => {
+                let span = trace::Span::enter(self.identity.as_ref(), "reconnect");
+                let result = self.reopen();
+                span.finish(self.reconnect_timeout, result)?
+            }
+        };
+        Ok(self.device.insert(device))
+    }
+
+    /// Opens the adapter that was disconnected
+    ///
+    /// The opener is narrowed down to the serial number of the adapter, or the hub
+    /// ports it was plugged into, so that other adapters matching the opener are
+    /// left alone.
+    fn open_adapter(&self) -> Result<Device> {
+        let timeout = self.reconnect_timeout;
+        match &self.identity {
+            Some(DeviceId {
+                vid: Some(vid),
+                pid: Some(pid),
+                serial: Some(serial),
+                ..
+            }) => {
+                let opener = find_by_vid_pid(*vid, *pid).serial(serial);
+                self.opener.retarget(opener).open_timeout(timeout)
+            }
+            #[cfg(feature = "libusb1-sys")]
+            Some(DeviceId {
+                bus: Some(bus),
+                port_path: Some(ports),
+                ..
+            }) => {
+                let opener = find_by_port_path(*bus, ports);
+                self.opener.retarget(opener).open_timeout(timeout)
+            }
+            _ => self.opener.attempt().open_timeout(timeout),
+        }
+    }
+
+    fn reopen(&mut self) -> Result<Device> {
+        let mut device = self.open_adapter()?;
+        if let (Some(expected), Some(found)) = (&self.identity, device.device_id()) {
+            if !same_adapter(expected, found) {
+                return Err(Error::DeviceNotFound);
+            }
+        }
+        self.config.apply(&mut device)?;
+
+        let gap = self
+            .disconnected_at
+            .take()
+            .map_or(Duration::ZERO, |at| at.elapsed());
+        self.reconnection = Some(Reconnection {
+            gap,
+            device: device.device_id().cloned(),
+        });
+        Ok(device)
+    }
+
+    fn disconnected(&mut self) {
+        self.device = None;
+        self.disconnected_at.get_or_insert_with(Instant::now);
+    }
+
+    /// Applies a setting, reconnecting the device first if needed
+    ///
+    /// The setting is remembered as described in [`keeps_setting`].
+    fn apply_setting(
+        &mut self,
+        update: impl FnOnce(&mut Config),
+        f: impl FnOnce(&mut Device) -> Result<()>,
+    ) -> Result<()> {
+        let result = self.reconnect().and_then(f);
+        if keeps_setting(&result) {
+            update(&mut self.config);
+        }
+        if let Err(Error::Disconnected) = result {
+            self.disconnected();
+        }
+        result
+    }
+
+    pub fn set_baud_rate(&mut self, rate: u32) -> Result<()> {
+        self.apply_setting(
+            |config| config.baud_rate = Some(rate),
+            |device| device.set_baud_rate(rate),
+        )
+    }
+
+    pub fn configure(&mut self, bits: Bits, stop_bits: StopBits, parity: Parity) -> Result<()> {
+        self.apply_setting(
+            |config| config.line_property = Some((bits, stop_bits, parity)),
+            |device| device.configure(bits, stop_bits, parity),
+        )
+    }
+
+    pub fn set_flow_control(&mut self, flowctrl: FlowControl) -> Result<()> {
+        self.apply_setting(
+            |config| config.flow_control = Some(flowctrl),
+            |device| device.set_flow_control(flowctrl),
+        )
+    }
+
+    pub fn set_latency_timer(&mut self, value: u8) -> Result<()> {
+        self.apply_setting(
+            |config| config.latency_timer = Some(value),
+            |device| device.set_latency_timer(value),
+        )
+    }
+
+    pub fn set_bitmode(&mut self, output_mask: u8, mode: BitMode) -> Result<()> {
+        self.apply_setting(
+            |config| config.bitmode = Some((output_mask, mode)),
+            |device| device.set_bitmode(output_mask, mode),
+        )
+    }
+
+    /// Runs an I/O call on the device, reconnecting it first if needed
+    fn io<R>(&mut self, f: impl FnOnce(&mut Device) -> io::Result<R>) -> io::Result<R> {
+        let device = self.reconnect().map_err(|err| {
+            if is_unavailable(&err) {
+                io::Error::new(io::ErrorKind::NotConnected, err)
+            } else {
+                libftdi_to_io(err)
+            }
+        })?;
+        let result = f(device);
+        if let Err(err) = &result {
+            if err.kind() == io::ErrorKind::NotConnected {
+                self.disconnected();
+            }
+        }
+        result
+    }
+}
+
+impl<T: Target + Clone> Read for ReconnectingDevice<T> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        self.io(|device| device.read(buf))
+    }
+}
+
+impl<T: Target + Clone> Write for ReconnectingDevice<T> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        self.io(|device| device.write(buf))
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::error::ErrorContext;
+
+    fn adapter(serial: Option<&str>, port_path: Option<&[u8]>) -> DeviceId {
+        DeviceId {
+            vid: Some(0x0403),
+            pid: Some(0x6014),
+            bus: Some(1),
+            address: Some(7),
+            serial: serial.map(String::from),
+            port_path: port_path.map(<[u8]>::to_vec),
+        }
+    }
+
+    #[test]
+    fn serial_takes_precedence_over_the_port_path() {
+        let expected = adapter(Some("FT1ABCDE"), Some(&[1, 2]));
+        assert!(same_adapter(
+            &expected,
+            &adapter(Some("FT1ABCDE"), Some(&[3]))
+        ));
+        assert!(!same_adapter(
+            &expected,
+            &adapter(Some("FT2ABCDE"), Some(&[1, 2]))
+        ));
+    }
+
+    #[test]
+    fn port_path_identifies_adapters_without_serial() {
+        let expected = adapter(None, Some(&[1, 2]));
+        assert!(same_adapter(&expected, &adapter(None, Some(&[1, 2]))));
+        assert!(!same_adapter(&expected, &adapter(None, Some(&[1, 3]))));
+        assert!(!same_adapter(&expected, &adapter(None, Some(&[1]))));
+    }
+
+    #[test]
+    fn unknown_parts_are_not_compared() {
+        let expected = adapter(None, None);
+        assert!(same_adapter(
+            &expected,
+            &adapter(Some("FT1ABCDE"), Some(&[4]))
+        ));
+        assert!(same_adapter(
+            &adapter(None, Some(&[1])),
+            &adapter(None, None)
+        ));
+        assert!(same_adapter(&DeviceId::default(), &expected));
+
+        let other_product = DeviceId {
+            pid: Some(0x6010),
+            ..adapter(None, None)
+        };
+        assert!(!same_adapter(&expected, &other_product));
+    }
+
+    #[test]
+    fn address_is_not_compared() {
+        let expected = adapter(Some("FT1ABCDE"), None);
+        let reenumerated = DeviceId {
+            address: Some(12),
+            ..expected.clone()
+        };
+        assert!(same_adapter(&expected, &reenumerated));
+    }
+
+    #[test]
+    fn settings_are_kept_unless_rejected() {
+        assert!(keeps_setting(&Ok(())));
+        assert!(keeps_setting(&Err(Error::Disconnected)));
+        assert!(keeps_setting(&Err(Error::DeviceNotFound)));
+        assert!(!keeps_setting(&Err(Error::InvalidInput("baud rate"))));
+        assert!(!keeps_setting(&Err(Error::request_failed(
+            ErrorContext::new("set_baud_rate")
+        ))));
+    }
+}
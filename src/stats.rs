@@ -0,0 +1,179 @@
+//! I/O statistics of a [`Device`](crate::Device)
+//!
+//! Every read and write call reaching the backend is counted, along with its
+//! outcome and latency. Backends that make the USB transfers themselves also
+//! report how many they made, see [`Stats::transfers`]. The statistics are cheap to keep and can be used to tune
+//! the latency timer and chunk sizes, or to notice a link getting worse over time.
+//!
+//! # Example
+//!
+//! ```
+//! use ftdi::backend::mock::Mock;
+//! use ftdi::stats::Transfers;
+//! use ftdi::Device;
+//! use std::io::{Read, Write};
+//!
+//! let mut mock = Mock::new();
+//! mock.expect_write(&[0x87]).expect_read(&[]).expect_read(&[0x55]);
+//! let mut device = Device::with_backend(mock);
+//! device.write_all(&[0x87]).unwrap();
+//! let mut buf = [0];
+//! assert_eq!(device.read(&mut buf).unwrap(), 0);
+//! assert_eq!(device.read(&mut buf).unwrap(), 1);
+//!
+//! let stats = device.stats();
+//! assert_eq!(stats.bytes_written, 1);
+//! assert_eq!(stats.bytes_read, 1);
+//! assert_eq!(stats.read_calls, 2);
+//! assert_eq!(stats.zero_length_reads, 1);
+//! assert_eq!(stats.read_latency.count(), 2);
+//! assert_eq!(stats.transfers, Some(Transfers { read: 2, write: 1 }));
+//!
+//! device.reset_stats();
+//! assert_eq!(device.stats().read_calls, 0);
+//! assert_eq!(device.stats().transfers, Some(Transfers::default()));
+//! ```
+
+use std::io;
+use std::time::Duration;
+
+/// Upper bounds of the histogram buckets, the last bucket being unbounded
+const BUCKET_BOUNDS: [Duration; 13] = [
+    Duration::from_micros(100),
+    Duration::from_micros(250),
+    Duration::from_micros(500),
+    Duration::from_millis(1),
+    Duration::from_millis(2),
+    Duration::from_millis(4),
+    Duration::from_millis(8),
+    Duration::from_millis(16),
+    Duration::from_millis(32),
+    Duration::from_millis(64),
+    Duration::from_millis(128),
+    Duration::from_millis(256),
+    Duration::from_secs(1),
+];
+
+/// A histogram of call latencies
+///
+/// The buckets are spaced to resolve the 1 ms steps of the latency timer.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Histogram {
+    counts: [u64; BUCKET_BOUNDS.len() + 1],
+    total: Duration,
+    max: Duration,
+}
+
+impl Histogram {
+    fn record(&mut self, latency: Duration) {
+        let bucket = BUCKET_BOUNDS
+            .iter()
+            .position(|&bound| latency < bound)
+            .unwrap_or(BUCKET_BOUNDS.len());
+        self.counts[bucket] += 1;
+        self.total += latency;
+        self.max = self.max.max(latency);
+    }
+
+    /// The number of recorded calls
+    pub fn count(&self) -> u64 {
+        self.counts.iter().sum()
+    }
+
+    /// The time spent in all the recorded calls
+    pub fn total(&self) -> Duration {
+        self.total
+    }
+
+    pub fn max(&self) -> Duration {
+        self.max
+    }
+
+    pub fn mean(&self) -> Option<Duration> {
+        match self.count() {
+            0 => None,
+            count => Some(self.total.div_f64(count as f64)),
+        }
+    }
+
+    /// The buckets as `(upper bound, count)` pairs, `None` standing for no bound
+    pub fn buckets(&self) -> impl Iterator<Item = (Option<Duration>, u64)> + '_ {
+        BUCKET_BOUNDS
+            .iter()
+            .copied()
+            .map(Some)
+            .chain(std::iter::once(None))
+            .zip(self.counts.iter().copied())
+    }
+}
+
+/// Bulk transfers made by a backend, including the failed ones
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Transfers {
+    pub read: u64,
+    pub write: u64,
+}
+
+impl Transfers {
+    pub(crate) fn since(self, baseline: Transfers) -> Transfers {
+        Transfers {
+            read: self.read.saturating_sub(baseline.read),
+            write: self.write.saturating_sub(baseline.write),
+        }
+    }
+}
+
+/// A snapshot of the I/O statistics of a device
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct Stats {
+    pub bytes_read: u64,
+    pub bytes_written: u64,
+    /// Read calls made to the backend
+    ///
+    /// This counts calls, not USB transfers: a call may be answered from the data
+    /// the backend buffered, or take several bulk transfers.
+    pub read_calls: u64,
+    /// Write calls made to the backend, each of which may take several bulk
+    /// transfers of the write chunk size
+    pub write_calls: u64,
+    /// Reads that succeeded without returning any data
+    pub zero_length_reads: u64,
+    /// Failed reads and writes that timed out
+    pub timeouts: u64,
+    /// Failed reads and writes, including the ones that timed out
+    pub errors: u64,
+    pub read_latency: Histogram,
+    pub write_latency: Histogram,
+    /// USB transfers, if the backend reports them
+    ///
+    /// `LibFtdi` does not, as `libftdi` makes the transfers internally.
+    pub transfers: Option<Transfers>,
+}
+
+impl Stats {
+    pub(crate) fn record_read(&mut self, result: &io::Result<usize>, latency: Duration) {
+        self.read_calls += 1;
+        self.read_latency.record(latency);
+        match result {
+            Ok(0) => self.zero_length_reads += 1,
+            Ok(len) => self.bytes_read += *len as u64,
+            Err(error) => self.record_error(error),
+        }
+    }
+
+    pub(crate) fn record_write(&mut self, result: &io::Result<usize>, latency: Duration) {
+        self.write_calls += 1;
+        self.write_latency.record(latency);
+        match result {
+            Ok(len) => self.bytes_written += *len as u64,
+            Err(error) => self.record_error(error),
+        }
+    }
+
+    fn record_error(&mut self, error: &io::Error) {
+        self.errors += 1;
+        if error.kind() == io::ErrorKind::TimedOut {
+            self.timeouts += 1;
+        }
+    }
+}
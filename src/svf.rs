@@ -0,0 +1,800 @@
+//! Serial Vector Format (SVF) parser and player
+//!
+//! SVF files are a textual description of JTAG operations exported by vendor tools
+//! for programming CPLDs and FPGAs. [`parse`] turns a file into a list of
+//! [`Statement`]s and [`Player`] executes them on a [`Jtag`] driver, comparing
+//! the captured TDO data where the file asks for it.
+//!
+//! `PIO` and `PIOMAP` statements are not supported.
+
+use std::fmt;
+use std::time::Duration;
+
+use ftdi_mpsse::MpsseCmdExecutor;
+use thiserror::Error;
+
+use crate::jtag::{self, Jtag, TapState};
+
+#[derive(Debug, Error)]
+#[error("line {line}: {message}")]
+pub struct ParseError {
+    pub line: usize,
+    pub message: String,
+}
+
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error("line {line}: TDO mismatch, expected {expected} (mask {mask}), got {actual}")]
+    TdoMismatch {
+        line: usize,
+        expected: HexData,
+        actual: HexData,
+        mask: HexData,
+    },
+    #[error("line {line}: {message}")]
+    InvalidStatement { line: usize, message: &'static str },
+    #[error("JTAG operation failed")]
+    Jtag(#[source] jtag::Error<E>),
+}
+
+/// Bit vector data, LSB first, displayed as an SVF hex string
+#[derive(Clone, PartialEq, Eq, Hash)]
+pub struct HexData {
+    pub bits: usize,
+    pub data: Vec<u8>,
+}
+
+impl fmt::Display for HexData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "(")?;
+        for digit in (0..self.bits.div_ceil(4)).rev() {
+            let nibble = (self.data[digit / 2] >> ((digit % 2) * 4)) & 0xf;
+            write!(f, "{:X}", nibble)?;
+        }
+        write!(f, ")")
+    }
+}
+
+impl fmt::Debug for HexData {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "HexData({}, {})", self.bits, self)
+    }
+}
+
+/// The data of a `HDR`, `HIR`, `SDR`, `SIR`, `TDR` or `TIR` statement
+///
+/// Omitted `TDI`, `MASK` and `SMASK` values are inherited from the previous
+/// statement of the same kind if the length is unchanged.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Shift {
+    pub length: usize,
+    pub tdi: Option<Vec<u8>>,
+    pub tdo: Option<Vec<u8>>,
+    pub mask: Option<Vec<u8>>,
+    pub smask: Option<Vec<u8>>,
+}
+
+/// The clock a `RUNTEST` count refers to
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum RunClock {
+    Tck,
+    Sck,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub struct RunTest {
+    pub run_state: Option<TapState>,
+    pub run_count: Option<(u32, RunClock)>,
+    pub min_time: Option<f64>,
+    pub max_time: Option<f64>,
+    pub end_state: Option<TapState>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum TrstMode {
+    On,
+    Off,
+    Z,
+    Absent,
+}
+
+#[derive(Clone, Debug, PartialEq)]
+pub enum Command {
+    EndDr(TapState),
+    EndIr(TapState),
+    /// Maximum TCK frequency in Hz, `None` meaning full speed
+    Frequency(Option<f64>),
+    Hdr(Shift),
+    Hir(Shift),
+    Sdr(Shift),
+    Sir(Shift),
+    Tdr(Shift),
+    Tir(Shift),
+    RunTest(RunTest),
+    State(Vec<TapState>),
+    Trst(TrstMode),
+}
+
+/// A parsed SVF command with the line it starts on
+#[derive(Clone, Debug, PartialEq)]
+pub struct Statement {
+    pub line: usize,
+    pub command: Command,
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Hex(String),
+}
+
+/// Parses the text of an SVF file
+pub fn parse(source: &str) -> Result<Vec<Statement>, ParseError> {
+    let mut statements = vec![];
+    let mut tokens = vec![];
+    let mut start_line = 1;
+    let mut line = 1;
+    let mut chars = source.chars().peekable();
+
+    while let Some(c) = chars.next() {
+        match c {
+            '\n' => line += 1,
+            '!' => skip_line(&mut chars, &mut line),
+            '/' if chars.peek() == Some(&'/') => skip_line(&mut chars, &mut line),
+            ';' => {
+                if !tokens.is_empty() {
+                    let command = parse_command(std::mem::take(&mut tokens), start_line)?;
+                    statements.push(Statement {
+                        line: start_line,
+                        command,
+                    });
+                }
+            }
+            '(' => {
+                let open_line = line;
+                let mut hex = String::new();
+                loop {
+                    match chars.next() {
+                        Some(')') => break,
+                        Some('\n') => line += 1,
+                        Some(c) if c.is_whitespace() => {}
+                        Some(c) => hex.push(c),
+                        None => return Err(error(open_line, "unterminated hex string")),
+                    }
+                }
+                tokens.push(Token::Hex(hex));
+            }
+            c if c.is_whitespace() => {}
+            c => {
+                if tokens.is_empty() {
+                    start_line = line;
+                }
+                let mut word = c.to_string();
+                while let Some(&c) = chars.peek() {
+                    if c.is_whitespace() || c == ';' || c == '(' {
+                        break;
+                    }
+                    word.push(c);
+                    chars.next();
+                }
+                tokens.push(Token::Word(word.to_ascii_uppercase()));
+            }
+        }
+    }
+
+    if !tokens.is_empty() {
+        return Err(error(start_line, "missing ';' at the end of file"));
+    }
+    Ok(statements)
+}
+
+fn skip_line(chars: &mut std::iter::Peekable<std::str::Chars<'_>>, line: &mut usize) {
+    for c in chars {
+        if c == '\n' {
+            *line += 1;
+            break;
+        }
+    }
+}
+
+fn error(line: usize, message: impl Into<String>) -> ParseError {
+    ParseError {
+        line,
+        message: message.into(),
+    }
+}
+
+fn parse_command(tokens: Vec<Token>, line: usize) -> Result<Command, ParseError> {
+    let mut tokens = tokens.into_iter();
+    let keyword = match tokens.next() {
+        Some(Token::Word(word)) => word,
+        _ => return Err(error(line, "expected a command")),
+    };
+    let mut words = vec![];
+    let mut hex = vec![];
+    for token in tokens {
+        match token {
+            Token::Word(word) => words.push(word),
+            Token::Hex(value) => {
+                let key = words
+                    .pop()
+                    .ok_or_else(|| error(line, "hex data without a parameter name"))?;
+                hex.push((key, value));
+            }
+        }
+    }
+
+    let is_shift = matches!(
+        keyword.as_str(),
+        "HDR" | "HIR" | "SDR" | "SIR" | "TDR" | "TIR"
+    );
+    if !hex.is_empty() && !is_shift {
+        return Err(error(line, "unexpected hex data"));
+    }
+
+    let command = match keyword.as_str() {
+        "ENDDR" | "ENDIR" => {
+            let state = match words.as_slice() {
+                [state] => parse_state(state, line)?,
+                _ => return Err(error(line, "expected a single state")),
+            };
+            if !state.is_stable() {
+                return Err(error(line, "end state must be stable"));
+            }
+            if keyword == "ENDDR" {
+                Command::EndDr(state)
+            } else {
+                Command::EndIr(state)
+            }
+        }
+        "FREQUENCY" => match words.as_slice() {
+            [] => Command::Frequency(None),
+            [value, unit] if unit == "HZ" => Command::Frequency(Some(parse_number(value, line)?)),
+            _ => return Err(error(line, "expected a frequency in HZ")),
+        },
+        "HDR" | "HIR" | "SDR" | "SIR" | "TDR" | "TIR" => {
+            let shift = parse_shift(&words, hex, line)?;
+            match keyword.as_str() {
+                "HDR" => Command::Hdr(shift),
+                "HIR" => Command::Hir(shift),
+                "SDR" => Command::Sdr(shift),
+                "SIR" => Command::Sir(shift),
+                "TDR" => Command::Tdr(shift),
+                _ => Command::Tir(shift),
+            }
+        }
+        "RUNTEST" => Command::RunTest(parse_runtest(&words, line)?),
+        "STATE" => {
+            let states = words
+                .iter()
+                .map(|word| parse_state(word, line))
+                .collect::<Result<Vec<_>, _>>()?;
+            match states.last() {
+                Some(state) if state.is_stable() => Command::State(states),
+                Some(_) => return Err(error(line, "final state must be stable")),
+                None => return Err(error(line, "expected at least one state")),
+            }
+        }
+        "TRST" => match words.as_slice() {
+            [mode] => Command::Trst(match mode.as_str() {
+                "ON" => TrstMode::On,
+                "OFF" => TrstMode::Off,
+                "Z" => TrstMode::Z,
+                "ABSENT" => TrstMode::Absent,
+                _ => return Err(error(line, format!("unknown TRST mode {}", mode))),
+            }),
+            _ => return Err(error(line, "expected a TRST mode")),
+        },
+        "PIO" | "PIOMAP" => return Err(error(line, format!("{} is not supported", keyword))),
+        _ => return Err(error(line, format!("unknown command {}", keyword))),
+    };
+    Ok(command)
+}
+
+fn parse_state(word: &str, line: usize) -> Result<TapState, ParseError> {
+    Ok(match word {
+        "RESET" => TapState::TestLogicReset,
+        "IDLE" => TapState::RunTestIdle,
+        "DRSELECT" => TapState::SelectDrScan,
+        "DRCAPTURE" => TapState::CaptureDr,
+        "DRSHIFT" => TapState::ShiftDr,
+        "DREXIT1" => TapState::Exit1Dr,
+        "DRPAUSE" => TapState::PauseDr,
+        "DREXIT2" => TapState::Exit2Dr,
+        "DRUPDATE" => TapState::UpdateDr,
+        "IRSELECT" => TapState::SelectIrScan,
+        "IRCAPTURE" => TapState::CaptureIr,
+        "IRSHIFT" => TapState::ShiftIr,
+        "IREXIT1" => TapState::Exit1Ir,
+        "IRPAUSE" => TapState::PauseIr,
+        "IREXIT2" => TapState::Exit2Ir,
+        "IRUPDATE" => TapState::UpdateIr,
+        _ => return Err(error(line, format!("unknown state {}", word))),
+    })
+}
+
+fn parse_number(word: &str, line: usize) -> Result<f64, ParseError> {
+    word.parse::<f64>()
+        .ok()
+        .filter(|value| value.is_finite() && *value >= 0.0)
+        .ok_or_else(|| error(line, format!("invalid number {}", word)))
+}
+
+fn parse_shift(
+    words: &[String],
+    hex: Vec<(String, String)>,
+    line: usize,
+) -> Result<Shift, ParseError> {
+    let length = match words {
+        [length] => length
+            .parse::<usize>()
+            .map_err(|_| error(line, format!("invalid length {}", length)))?,
+        _ => return Err(error(line, "expected a length")),
+    };
+    let mut shift = Shift {
+        length,
+        tdi: None,
+        tdo: None,
+        mask: None,
+        smask: None,
+    };
+    for (key, value) in hex {
+        let data = parse_hex(&value, length, line)?;
+        let slot = match key.as_str() {
+            "TDI" => &mut shift.tdi,
+            "TDO" => &mut shift.tdo,
+            "MASK" => &mut shift.mask,
+            "SMASK" => &mut shift.smask,
+            _ => return Err(error(line, format!("unknown parameter {}", key))),
+        };
+        if slot.replace(data).is_some() {
+            return Err(error(line, format!("duplicate parameter {}", key)));
+        }
+    }
+    Ok(shift)
+}
+
+fn parse_hex(value: &str, length: usize, line: usize) -> Result<Vec<u8>, ParseError> {
+    let mut data = vec![0u8; length.div_ceil(8)];
+    for (digit, c) in value.chars().rev().enumerate() {
+        let nibble = c
+            .to_digit(16)
+            .ok_or_else(|| error(line, format!("invalid hex digit {:?}", c)))?
+            as u8;
+        for bit in 0..4 {
+            if nibble & (1 << bit) == 0 {
+                continue;
+            }
+            let index = digit * 4 + bit;
+            if index >= length {
+                return Err(error(line, "hex data does not fit into the length"));
+            }
+            data[index / 8] |= 1 << (index % 8);
+        }
+    }
+    Ok(data)
+}
+
+fn parse_runtest(words: &[String], line: usize) -> Result<RunTest, ParseError> {
+    let mut runtest = RunTest {
+        run_state: None,
+        run_count: None,
+        min_time: None,
+        max_time: None,
+        end_state: None,
+    };
+    let mut words = words.iter().map(String::as_str).peekable();
+
+    if let Some(word) = words.peek() {
+        if let Ok(state) = parse_state(word, line) {
+            runtest.run_state = Some(state);
+            words.next();
+        }
+    }
+    while let Some(word) = words.next() {
+        match (word, words.next()) {
+            ("MAXIMUM", Some(value)) => {
+                runtest.max_time = Some(parse_number(value, line)?);
+                if words.next() != Some("SEC") {
+                    return Err(error(line, "expected SEC after the maximum time"));
+                }
+            }
+            ("ENDSTATE", Some(state)) => runtest.end_state = Some(parse_state(state, line)?),
+            (value, Some(unit))
+                if (unit == "TCK" || unit == "SCK") && runtest.run_count.is_none() =>
+            {
+                let count = value
+                    .parse::<u32>()
+                    .map_err(|_| error(line, format!("invalid clock count {}", value)))?;
+                let clock = if unit == "TCK" {
+                    RunClock::Tck
+                } else {
+                    RunClock::Sck
+                };
+                runtest.run_count = Some((count, clock));
+            }
+            (value, Some("SEC")) if runtest.min_time.is_none() => {
+                runtest.min_time = Some(parse_number(value, line)?)
+            }
+            _ => return Err(error(line, "malformed RUNTEST")),
+        }
+    }
+
+    if runtest.run_count.is_none() && runtest.min_time.is_none() {
+        return Err(error(line, "RUNTEST needs a clock count or a minimum time"));
+    }
+    if let Some(state) = runtest
+        .run_state
+        .into_iter()
+        .chain(runtest.end_state)
+        .find(|s| !s.is_stable())
+    {
+        return Err(error(line, format!("{:?} is not a stable state", state)));
+    }
+    Ok(runtest)
+}
+
+#[derive(Clone, Debug, Default)]
+struct Pattern {
+    length: usize,
+    tdi: Option<Vec<u8>>,
+    tdo: Option<Vec<u8>>,
+    mask: Vec<u8>,
+}
+
+impl Pattern {
+    fn update(&mut self, shift: &Shift) -> Result<(), &'static str> {
+        if shift.length != self.length {
+            *self = Pattern {
+                length: shift.length,
+                tdi: None,
+                tdo: None,
+                mask: ones(shift.length),
+            };
+        }
+        if let Some(tdi) = &shift.tdi {
+            self.tdi = Some(tdi.clone());
+        }
+        if let Some(mask) = &shift.mask {
+            self.mask = mask.clone();
+        }
+        self.tdo = shift.tdo.clone();
+        if self.length > 0 && self.tdi.is_none() {
+            return Err("TDI must be specified when the length changes");
+        }
+        Ok(())
+    }
+}
+
+fn ones(bits: usize) -> Vec<u8> {
+    let mut data = vec![0u8; bits.div_ceil(8)];
+    put_bits(&mut data, 0, &vec![0xff; bits.div_ceil(8)], bits);
+    data
+}
+
+fn put_bits(dst: &mut [u8], offset: usize, src: &[u8], bits: usize) {
+    for i in 0..bits {
+        if (src[i / 8] >> (i % 8)) & 1 != 0 {
+            dst[(offset + i) / 8] |= 1 << ((offset + i) % 8);
+        }
+    }
+}
+
+type FrequencyHandler<'a, E> =
+    Box<dyn FnMut(&mut E, Option<f64>) -> Result<(), <E as MpsseCmdExecutor>::Error> + 'a>;
+
+/// Executes SVF statements on a JTAG driver
+///
+/// `FREQUENCY` statements are passed to the handler set with
+/// [`on_frequency`](Player::on_frequency) and used to convert the `RUNTEST`
+/// minimum time into TCK cycles. Without a known frequency the player sleeps
+/// for the minimum time instead. `SCK` counts are clocked on TCK and
+/// `TRST ON` is emulated with a TMS reset, as MPSSE has no dedicated pins for them.
+pub struct Player<'a, E: MpsseCmdExecutor> {
+    jtag: &'a mut Jtag<E>,
+    frequency_handler: Option<FrequencyHandler<'a, E>>,
+    frequency: Option<f64>,
+    end_dr: TapState,
+    end_ir: TapState,
+    run_state: TapState,
+    run_end_state: TapState,
+    hdr: Pattern,
+    hir: Pattern,
+    sdr: Pattern,
+    sir: Pattern,
+    tdr: Pattern,
+    tir: Pattern,
+}
+
+impl<'a, E: MpsseCmdExecutor> Player<'a, E> {
+    pub fn new(jtag: &'a mut Jtag<E>) -> Self {
+        Player {
+            jtag,
+            frequency_handler: None,
+            frequency: None,
+            end_dr: TapState::RunTestIdle,
+            end_ir: TapState::RunTestIdle,
+            run_state: TapState::RunTestIdle,
+            run_end_state: TapState::RunTestIdle,
+            hdr: Pattern::default(),
+            hir: Pattern::default(),
+            sdr: Pattern::default(),
+            sir: Pattern::default(),
+            tdr: Pattern::default(),
+            tir: Pattern::default(),
+        }
+    }
+
+    /// Sets the handler applying `FREQUENCY` statements to the executor
+    pub fn on_frequency(
+        mut self,
+        handler: impl FnMut(&mut E, Option<f64>) -> Result<(), E::Error> + 'a,
+    ) -> Self {
+        self.frequency_handler = Some(Box::new(handler));
+        self
+    }
+
+    /// Parses and executes the text of an SVF file
+    pub fn play(&mut self, source: &str) -> Result<(), Error<E::Error>> {
+        self.run(&parse(source)?)
+    }
+
+    pub fn run(&mut self, statements: &[Statement]) -> Result<(), Error<E::Error>> {
+        statements
+            .iter()
+            .try_for_each(|statement| self.execute(statement))
+    }
+
+    pub fn execute(&mut self, statement: &Statement) -> Result<(), Error<E::Error>> {
+        let line = statement.line;
+        let invalid = |message| Error::InvalidStatement { line, message };
+        match &statement.command {
+            Command::EndDr(state) => self.end_dr = *state,
+            Command::EndIr(state) => self.end_ir = *state,
+            Command::Frequency(frequency) => {
+                self.frequency = *frequency;
+                if let Some(handler) = &mut self.frequency_handler {
+                    handler(self.jtag.executor_mut(), *frequency)
+                        .map_err(|err| Error::Jtag(jtag::Error::Executor(err)))?;
+                }
+            }
+            Command::Hdr(shift) => self.hdr.update(shift).map_err(invalid)?,
+            Command::Hir(shift) => self.hir.update(shift).map_err(invalid)?,
+            Command::Tdr(shift) => self.tdr.update(shift).map_err(invalid)?,
+            Command::Tir(shift) => self.tir.update(shift).map_err(invalid)?,
+            Command::Sdr(shift) => {
+                self.sdr.update(shift).map_err(invalid)?;
+                let end = self.end_dr;
+                self.scan(line, false, end)?;
+            }
+            Command::Sir(shift) => {
+                self.sir.update(shift).map_err(invalid)?;
+                let end = self.end_ir;
+                self.scan(line, true, end)?;
+            }
+            Command::RunTest(runtest) => self.runtest(runtest)?,
+            Command::State(states) => {
+                for state in states {
+                    self.jtag.goto(*state).map_err(Error::Jtag)?;
+                }
+            }
+            Command::Trst(TrstMode::On) => self.jtag.reset().map_err(Error::Jtag)?,
+            Command::Trst(_) => {}
+        }
+        Ok(())
+    }
+
+    fn scan(&mut self, line: usize, ir: bool, end: TapState) -> Result<(), Error<E::Error>> {
+        let segments = if ir {
+            [&self.hir, &self.sir, &self.tir]
+        } else {
+            [&self.hdr, &self.sdr, &self.tdr]
+        };
+        let bits: usize = segments.iter().map(|p| p.length).sum();
+        let mut tdi = vec![0u8; bits.div_ceil(8)];
+        let mut expected = vec![0u8; bits.div_ceil(8)];
+        let mut mask = vec![0u8; bits.div_ceil(8)];
+        let mut compare = false;
+        let mut offset = 0;
+        for pattern in segments.iter() {
+            if let Some(data) = &pattern.tdi {
+                put_bits(&mut tdi, offset, data, pattern.length);
+            }
+            if let Some(data) = &pattern.tdo {
+                put_bits(&mut expected, offset, data, pattern.length);
+                put_bits(&mut mask, offset, &pattern.mask, pattern.length);
+                compare = true;
+            }
+            offset += pattern.length;
+        }
+
+        if !compare {
+            let result = if ir {
+                self.jtag.write_ir(&tdi, bits, end)
+            } else {
+                self.jtag.write_dr(&tdi, bits, end)
+            };
+            return result.map_err(Error::Jtag);
+        }
+
+        let actual = if ir {
+            self.jtag.shift_ir(&tdi, bits, end)
+        } else {
+            self.jtag.shift_dr(&tdi, bits, end)
+        }
+        .map_err(Error::Jtag)?;
+        let matches = actual
+            .iter()
+            .zip(&expected)
+            .zip(&mask)
+            .all(|((a, e), m)| (a ^ e) & m == 0);
+        if !matches {
+            return Err(Error::TdoMismatch {
+                line,
+                expected: HexData {
+                    bits,
+                    data: expected,
+                },
+                actual: HexData { bits, data: actual },
+                mask: HexData { bits, data: mask },
+            });
+        }
+        Ok(())
+    }
+
+    fn runtest(&mut self, runtest: &RunTest) -> Result<(), Error<E::Error>> {
+        let run_state = runtest.run_state.unwrap_or(self.run_state);
+        let end_state = match (runtest.end_state, runtest.run_state) {
+            (Some(state), _) | (None, Some(state)) => state,
+            (None, None) => self.run_end_state,
+        };
+        self.run_state = run_state;
+        self.run_end_state = end_state;
+
+        let mut cycles = runtest.run_count.map_or(0, |(count, _)| count);
+        let mut sleep = None;
+        if let Some(min_time) = runtest.min_time {
+            match self.frequency {
+                Some(frequency) => {
+                    let min_cycles = (min_time * frequency).ceil();
+                    cycles = std::cmp::max(cycles, min_cycles.min(f64::from(u32::MAX)) as u32);
+                }
+                None => sleep = Some(Duration::from_secs_f64(min_time)),
+            }
+        }
+
+        self.jtag.goto(run_state).map_err(Error::Jtag)?;
+        self.jtag.clock(cycles).map_err(Error::Jtag)?;
+        if let Some(duration) = sleep {
+            std::thread::sleep(duration);
+        }
+        self.jtag.goto(end_state).map_err(Error::Jtag)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpsse::emulator::{Emulator, JtagTap};
+    use ftdi_mpsse::MpsseSettings;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn parse_statements() {
+        let statements = parse(
+            "! comment\n\
+             FREQUENCY 1E6 HZ;\n\
+             // another comment\n\
+             SDR 12 TDI (a\n5 5) TDO (0ff)\n  MASK (f00);\n\
+             runtest idle 100 tck 1e-3 sec endstate reset;\n",
+        )
+        .unwrap();
+        assert_eq!(
+            statements,
+            [
+                Statement {
+                    line: 2,
+                    command: Command::Frequency(Some(1e6)),
+                },
+                Statement {
+                    line: 4,
+                    command: Command::Sdr(Shift {
+                        length: 12,
+                        tdi: Some(vec![0x55, 0x0a]),
+                        tdo: Some(vec![0xff, 0x00]),
+                        mask: Some(vec![0x00, 0x0f]),
+                        smask: None,
+                    }),
+                },
+                Statement {
+                    line: 7,
+                    command: Command::RunTest(RunTest {
+                        run_state: Some(TapState::RunTestIdle),
+                        run_count: Some((100, RunClock::Tck)),
+                        min_time: Some(1e-3),
+                        max_time: None,
+                        end_state: Some(TapState::TestLogicReset),
+                    }),
+                },
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors_report_the_line() {
+        for (source, line) in [
+            ("SIR 4 TDI (2);\nSDR 4 TDI (1f);", 2),
+            ("ENDDR DRSHIFT;\nENDIR DREXIT1;", 2),
+            ("\nSTATE IDLE DRSELECT;", 2),
+            ("SIR 4 TDI (2)", 1),
+            ("TRST ON;\nPIO (HLX);", 2),
+            ("SDR 8 TDI (12\n", 1),
+        ] {
+            match parse(source) {
+                Err(err) => assert_eq!(err.line, line, "{:?}: {}", source, err),
+                Ok(statements) => panic!("{:?} parsed as {:?}", source, statements),
+            }
+        }
+    }
+
+    /// TDI -> a TAP with an 8-bit register (AD1 to AD4) -> a TAP in bypass
+    /// (AD4 to AD2) -> TDO
+    fn chain() -> (Jtag<Emulator>, Rc<RefCell<JtagTap>>) {
+        let tap = Rc::new(RefCell::new(
+            JtagTap::new(4).with_register(0x2, 8).with_pins(0, 1, 4, 3),
+        ));
+        let mut emulator = Emulator::new();
+        emulator
+            .attach(tap.clone())
+            .attach(JtagTap::new(5).with_pins(0, 4, 2, 3));
+        emulator.init(&MpsseSettings::default()).unwrap();
+        (Jtag::new(emulator).unwrap(), tap)
+    }
+
+    #[test]
+    fn play_with_header_for_the_bypassed_device() {
+        let (mut jtag, tap) = chain();
+        Player::new(&mut jtag)
+            .play(
+                "TRST OFF;\n\
+                 ENDIR IDLE;\n\
+                 ENDDR DRPAUSE;\n\
+                 STATE RESET;\n\
+                 HIR 5 TDI (1f);\n\
+                 HDR 1 TDI (0);\n\
+                 SIR 4 TDI (2);\n\
+                 SDR 8 TDI (a5);\n\
+                 SDR 8 TDI (00) TDO (a5) MASK (ff);\n\
+                 RUNTEST 10 TCK;\n",
+            )
+            .unwrap();
+        assert_eq!(tap.borrow().instruction(), 0x2);
+        assert_eq!(tap.borrow().register(0x2), Some(&[false; 8][..]));
+        assert_eq!(jtag.state(), TapState::RunTestIdle);
+    }
+
+    #[test]
+    fn tdo_mismatch_reports_the_line() {
+        let (mut jtag, _) = chain();
+        let result = Player::new(&mut jtag).play(
+            "HIR 5 TDI (1f);\n\
+             HDR 1 TDI (0);\n\
+             SIR 4 TDI (2);\n\
+             SDR 8 TDI (3c);\n\
+             SDR 8 TDI (00) TDO (30) MASK (f0);\n\
+             SDR 8 TDI (00) TDO (ff) MASK (0f);\n",
+        );
+        match result {
+            Err(Error::TdoMismatch { line, actual, .. }) => {
+                assert_eq!(line, 6);
+                // the header bit of the bypassed device comes first
+                assert_eq!(actual.bits, 9);
+                assert_eq!(actual.data, [0x00, 0x00]);
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}
@@ -0,0 +1,458 @@
+//! ARM Serial Wire Debug (SWD) host over MPSSE
+//!
+//! SWCLK is on ADBUS0 and SWDIO is resistor-coupled: ADBUS1 drives it through
+//! a series resistor (typically 470 Ω) and ADBUS2 senses it directly. ADBUS1 is
+//! switched to an input while the target drives the line. Data is transferred
+//! LSB first, written and sampled on the falling edge of SWCLK.
+
+use ftdi_mpsse::{
+    ClockBitsIn, ClockBitsOut, ClockDataIn, ClockDataOut, MpsseCmdBuilder, MpsseCmdExecutor,
+};
+use thiserror::Error;
+
+/// Debug port register addresses
+pub mod dp {
+    pub const DPIDR: u8 = 0x0;
+    pub const ABORT: u8 = 0x0;
+    pub const CTRL_STAT: u8 = 0x4;
+    pub const SELECT: u8 = 0x8;
+    pub const RDBUFF: u8 = 0xc;
+}
+
+/// MEM-AP register addresses, the upper nibble being the register bank
+pub mod mem_ap {
+    pub const CSW: u8 = 0x00;
+    pub const TAR: u8 = 0x04;
+    pub const DRW: u8 = 0x0c;
+    pub const IDR: u8 = 0xfc;
+}
+
+const PINS_DRIVE: u8 = 0x03;
+const PINS_RELEASE: u8 = 0x01;
+const SWDIO_HIGH: u8 = 0x02;
+
+const ACK_OK: u8 = 0b001;
+const ACK_WAIT: u8 = 0b010;
+const ACK_FAULT: u8 = 0b100;
+
+/// Idle cycles clocked after every transfer to let the target finish it
+const IDLE_CYCLES: u8 = 8;
+
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    #[error("target responded with FAULT")]
+    Fault,
+    #[error("target still responded with WAIT after {0} retries")]
+    WaitTimeout(u32),
+    #[error("invalid ACK {0:#05b}")]
+    Protocol(u8),
+    #[error("read data parity error")]
+    Parity,
+    #[error("invalid register address {0:#04x}")]
+    InvalidAddress(u8),
+    #[error("debug power-up was not acknowledged")]
+    PowerUpFailed,
+    #[error("MPSSE command execution failed")]
+    Executor(#[source] E),
+}
+
+/// The target of an SWD transfer
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum Port {
+    /// The debug port
+    Dp,
+    /// The currently selected access port
+    Ap,
+}
+
+/// An SWD host
+pub struct Swd<E> {
+    executor: E,
+    wait_retries: u32,
+    select: Option<u32>,
+}
+
+impl<E: MpsseCmdExecutor> Swd<E> {
+    /// Configures the SWD pins
+    ///
+    /// The executor should already be in MPSSE mode with the desired SWCLK frequency.
+    pub fn new(executor: E) -> Result<Self, Error<E::Error>> {
+        let mut swd = Swd {
+            executor,
+            wait_retries: 100,
+            select: None,
+        };
+        let cmd = MpsseCmdBuilder::new().set_gpio_lower(SWDIO_HIGH, PINS_DRIVE);
+        swd.send(cmd)?;
+        Ok(swd)
+    }
+
+    /// Sets how many times a transfer is retried when the target responds with WAIT
+    pub fn set_wait_retries(&mut self, retries: u32) {
+        self.wait_retries = retries;
+    }
+
+    pub fn executor_mut(&mut self) -> &mut E {
+        &mut self.executor
+    }
+
+    pub fn into_inner(self) -> E {
+        self.executor
+    }
+
+    /// Clocks at least 50 cycles with SWDIO high followed by idle cycles
+    pub fn line_reset(&mut self) -> Result<(), Error<E::Error>> {
+        let cmd = Self::line_reset_cmd(MpsseCmdBuilder::new());
+        self.select = None;
+        self.send(cmd)
+    }
+
+    /// Switches a SWJ-DP from JTAG to SWD and resets the line
+    pub fn jtag_to_swd(&mut self) -> Result<(), Error<E::Error>> {
+        let mut cmd = Self::line_reset_cmd(MpsseCmdBuilder::new());
+        cmd = cmd.clock_data_out(ClockDataOut::LsbNeg, &0xe79eu16.to_le_bytes());
+        cmd = Self::line_reset_cmd(cmd);
+        self.select = None;
+        self.send(cmd)
+    }
+
+    /// Switches to SWD and reads `DPIDR`, which also takes the DP out of the reset state
+    pub fn connect(&mut self) -> Result<u32, Error<E::Error>> {
+        self.jtag_to_swd()?;
+        self.read_dp(dp::DPIDR)
+    }
+
+    /// Clears the sticky error flags of the DP through `ABORT`
+    pub fn clear_errors(&mut self) -> Result<(), Error<E::Error>> {
+        self.write_dp(dp::ABORT, 0x1e)
+    }
+
+    /// Requests debug and system power-up and waits for the acknowledgement
+    pub fn power_up(&mut self) -> Result<(), Error<E::Error>> {
+        const REQUEST: u32 = 0x5000_0000;
+        const ACK: u32 = 0xa000_0000;
+        self.write_dp(dp::CTRL_STAT, REQUEST)?;
+        for _ in 0..100 {
+            if self.read_dp(dp::CTRL_STAT)? & ACK == ACK {
+                return Ok(());
+            }
+        }
+        Err(Error::PowerUpFailed)
+    }
+
+    pub fn read_dp(&mut self, addr: u8) -> Result<u32, Error<E::Error>> {
+        self.transfer(Port::Dp, addr, None)
+    }
+
+    pub fn write_dp(&mut self, addr: u8, value: u32) -> Result<(), Error<E::Error>> {
+        self.transfer(Port::Dp, addr, Some(value))?;
+        if addr == dp::SELECT {
+            self.select = Some(value);
+        }
+        Ok(())
+    }
+
+    /// Reads an AP register, `addr` containing the bank in its upper nibble
+    ///
+    /// As AP reads are posted, the value is fetched through `RDBUFF`.
+    pub fn read_ap(&mut self, ap: u8, addr: u8) -> Result<u32, Error<E::Error>> {
+        self.select_ap(ap, addr)?;
+        self.transfer(Port::Ap, addr & 0x0c, None)?;
+        self.read_dp(dp::RDBUFF)
+    }
+
+    /// Writes an AP register, `addr` containing the bank in its upper nibble
+    pub fn write_ap(&mut self, ap: u8, addr: u8, value: u32) -> Result<(), Error<E::Error>> {
+        self.select_ap(ap, addr)?;
+        self.transfer(Port::Ap, addr & 0x0c, Some(value))
+            .map(|_| ())
+    }
+
+    /// Reads a 32-bit word of target memory through the given MEM-AP
+    pub fn read_mem32(&mut self, ap: u8, address: u32) -> Result<u32, Error<E::Error>> {
+        self.setup_mem_ap(ap, address)?;
+        self.read_ap(ap, mem_ap::DRW)
+    }
+
+    /// Writes a 32-bit word of target memory through the given MEM-AP
+    pub fn write_mem32(&mut self, ap: u8, address: u32, value: u32) -> Result<(), Error<E::Error>> {
+        self.setup_mem_ap(ap, address)?;
+        self.write_ap(ap, mem_ap::DRW, value)?;
+        // Make sure the write has completed
+        self.read_dp(dp::RDBUFF).map(|_| ())
+    }
+
+    fn setup_mem_ap(&mut self, ap: u8, address: u32) -> Result<(), Error<E::Error>> {
+        // 32-bit accesses without address auto-increment
+        const CSW_WORD: u32 = 0x2300_0002;
+        self.write_ap(ap, mem_ap::CSW, CSW_WORD)?;
+        self.write_ap(ap, mem_ap::TAR, address)
+    }
+
+    fn select_ap(&mut self, ap: u8, addr: u8) -> Result<(), Error<E::Error>> {
+        let select = (u32::from(ap) << 24) | u32::from(addr & 0xf0);
+        if self.select != Some(select) {
+            self.write_dp(dp::SELECT, select)?;
+        }
+        Ok(())
+    }
+
+    /// Performs a single transfer, retrying on WAIT
+    ///
+    /// Reads if `value` is `None` and writes it otherwise.
+    pub fn transfer(
+        &mut self,
+        port: Port,
+        addr: u8,
+        value: Option<u32>,
+    ) -> Result<u32, Error<E::Error>> {
+        if addr & !0x0c != 0 {
+            return Err(Error::InvalidAddress(addr));
+        }
+        let request = Self::request(port, value.is_none(), addr);
+
+        let mut attempt = 0;
+        loop {
+            let cmd = MpsseCmdBuilder::new()
+                .set_gpio_lower(SWDIO_HIGH, PINS_DRIVE)
+                .clock_bits_out(ClockBitsOut::LsbNeg, request, 8)
+                .set_gpio_lower(SWDIO_HIGH, PINS_RELEASE)
+                .clock_bits_in(ClockBitsIn::LsbNeg, 4)
+                .send_immediate();
+            let mut response = [0u8];
+            self.executor
+                .xfer(cmd.as_slice(), &mut response)
+                .map_err(Error::Executor)?;
+            // Turnaround in bit 4, then the three ACK bits
+            let ack = response[0] >> 5;
+
+            match ack {
+                ACK_OK => break,
+                ACK_WAIT if attempt < self.wait_retries => {
+                    attempt += 1;
+                    self.send(Self::turnaround_to_host(MpsseCmdBuilder::new()))?;
+                }
+                ACK_WAIT => {
+                    self.send(Self::turnaround_to_host(MpsseCmdBuilder::new()))?;
+                    return Err(Error::WaitTimeout(self.wait_retries));
+                }
+                ACK_FAULT => {
+                    self.send(Self::turnaround_to_host(MpsseCmdBuilder::new()))?;
+                    return Err(Error::Fault);
+                }
+                ack => {
+                    // The target is out of sync, it has to be reset
+                    self.line_reset()?;
+                    return Err(Error::Protocol(ack));
+                }
+            }
+        }
+
+        match value {
+            Some(value) => {
+                let parity = (value.count_ones() & 1) as u8;
+                let cmd = MpsseCmdBuilder::new()
+                    .clock_bits_out(ClockBitsOut::LsbNeg, 0, 1)
+                    .set_gpio_lower(SWDIO_HIGH, PINS_DRIVE)
+                    .clock_data_out(ClockDataOut::LsbNeg, &value.to_le_bytes())
+                    .clock_bits_out(ClockBitsOut::LsbNeg, parity, 1)
+                    .clock_bits_out(ClockBitsOut::LsbNeg, 0, IDLE_CYCLES);
+                self.send(cmd)?;
+                Ok(value)
+            }
+            None => {
+                // 32 data bits, parity and turnaround
+                let cmd = MpsseCmdBuilder::new()
+                    .clock_data_in(ClockDataIn::LsbNeg, 4)
+                    .clock_bits_in(ClockBitsIn::LsbNeg, 2);
+                let cmd = Self::turnaround_done(cmd).send_immediate();
+                let mut response = [0u8; 5];
+                self.executor
+                    .xfer(cmd.as_slice(), &mut response)
+                    .map_err(Error::Executor)?;
+                let data = u32::from_le_bytes([response[0], response[1], response[2], response[3]]);
+                let parity = (response[4] >> 6) & 1;
+                if u32::from(parity) != data.count_ones() & 1 {
+                    return Err(Error::Parity);
+                }
+                Ok(data)
+            }
+        }
+    }
+
+    fn request(port: Port, read: bool, addr: u8) -> u8 {
+        let ap = port == Port::Ap;
+        let a2 = addr & 0x04 != 0;
+        let a3 = addr & 0x08 != 0;
+        let parity = ap ^ read ^ a2 ^ a3;
+        // Start, APnDP, RnW, A[2:3], parity, stop (0), park (1)
+        0x81 | (u8::from(ap) << 1)
+            | (u8::from(read) << 2)
+            | (u8::from(a2) << 3)
+            | (u8::from(a3) << 4)
+            | (u8::from(parity) << 5)
+    }
+
+    fn line_reset_cmd(cmd: MpsseCmdBuilder) -> MpsseCmdBuilder {
+        cmd.set_gpio_lower(SWDIO_HIGH, PINS_DRIVE)
+            .clock_data_out(ClockDataOut::LsbNeg, &[0xff; 7])
+            .clock_bits_out(ClockBitsOut::LsbNeg, 0, IDLE_CYCLES)
+    }
+
+    /// Clocks the turnaround cycle after the target stopped driving SWDIO
+    fn turnaround_to_host(cmd: MpsseCmdBuilder) -> MpsseCmdBuilder {
+        Self::turnaround_done(cmd.clock_bits_out(ClockBitsOut::LsbNeg, 0, 1))
+    }
+
+    fn turnaround_done(cmd: MpsseCmdBuilder) -> MpsseCmdBuilder {
+        cmd.set_gpio_lower(SWDIO_HIGH, PINS_DRIVE).clock_bits_out(
+            ClockBitsOut::LsbNeg,
+            0,
+            IDLE_CYCLES,
+        )
+    }
+
+    fn send(&mut self, cmd: MpsseCmdBuilder) -> Result<(), Error<E::Error>> {
+        self.executor.send(cmd.as_slice()).map_err(Error::Executor)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use ftdi_mpsse::MpsseSettings;
+    use std::collections::VecDeque;
+    use std::convert::Infallible;
+
+    /// An executor answering reads with scripted responses
+    #[derive(Default)]
+    struct Script {
+        responses: VecDeque<Vec<u8>>,
+        sent: Vec<u8>,
+    }
+
+    impl MpsseCmdExecutor for Script {
+        type Error = Infallible;
+
+        fn init(&mut self, _settings: &MpsseSettings) -> Result<(), Infallible> {
+            Ok(())
+        }
+
+        fn send(&mut self, data: &[u8]) -> Result<(), Infallible> {
+            self.sent.extend_from_slice(data);
+            Ok(())
+        }
+
+        fn recv(&mut self, data: &mut [u8]) -> Result<(), Infallible> {
+            let response = self.responses.pop_front().expect("unexpected read");
+            data.copy_from_slice(&response);
+            Ok(())
+        }
+    }
+
+    /// The byte read back for an ACK, the turnaround and ACK bits being shifted
+    /// in from the top
+    fn ack(ack: u8) -> Vec<u8> {
+        vec![ack << 5]
+    }
+
+    /// The bytes read back for a data phase, with the parity bit inverted if `bad_parity`
+    fn data(value: u32, bad_parity: bool) -> Vec<u8> {
+        let parity = (value.count_ones() & 1 != 0) != bad_parity;
+        let mut response = value.to_le_bytes().to_vec();
+        response.push(u8::from(parity) << 6);
+        response
+    }
+
+    fn swd(responses: Vec<Vec<u8>>) -> Swd<Script> {
+        let script = Script {
+            responses: responses.into(),
+            sent: Vec::new(),
+        };
+        Swd::new(script).unwrap()
+    }
+
+    fn sent(swd: &mut Swd<Script>) -> Vec<u8> {
+        std::mem::take(&mut swd.executor_mut().sent)
+    }
+
+    #[test]
+    fn request_parity() {
+        assert_eq!(Swd::<Script>::request(Port::Dp, true, dp::DPIDR), 0xa5);
+        assert_eq!(Swd::<Script>::request(Port::Dp, false, dp::ABORT), 0x81);
+        assert_eq!(Swd::<Script>::request(Port::Dp, true, dp::RDBUFF), 0xbd);
+        assert_eq!(Swd::<Script>::request(Port::Dp, false, dp::SELECT), 0xb1);
+        assert_eq!(Swd::<Script>::request(Port::Ap, true, mem_ap::DRW), 0x9f);
+        assert_eq!(Swd::<Script>::request(Port::Ap, false, mem_ap::TAR), 0x8b);
+    }
+
+    #[test]
+    fn read_checks_the_data_parity() {
+        let mut swd = swd(vec![
+            ack(ACK_OK),
+            data(0x2ba01477, false),
+            ack(ACK_OK),
+            data(0x2ba01477, true),
+        ]);
+        assert_eq!(swd.read_dp(dp::DPIDR).unwrap(), 0x2ba01477);
+        assert!(matches!(swd.read_dp(dp::DPIDR), Err(Error::Parity)));
+    }
+
+    #[test]
+    fn write_sends_the_data_parity() {
+        let mut swd = swd(vec![ack(ACK_OK)]);
+        sent(&mut swd);
+        swd.write_dp(dp::SELECT, 0x0100_00f0).unwrap();
+        let sent = sent(&mut swd);
+        let data = [0x19, 0x03, 0x00, 0xf0, 0x00, 0x00, 0x01];
+        let parity = [0x1b, 0x00, 0x01];
+        let position = sent
+            .windows(data.len())
+            .position(|window| window == data)
+            .expect("data phase not sent");
+        assert_eq!(sent[position + data.len()..][..3], parity);
+    }
+
+    #[test]
+    fn wait_is_retried() {
+        let mut swd = swd(vec![
+            ack(ACK_WAIT),
+            ack(ACK_WAIT),
+            ack(ACK_OK),
+            data(0x1234, false),
+        ]);
+        assert_eq!(swd.read_dp(dp::CTRL_STAT).unwrap(), 0x1234);
+        assert!(swd.executor_mut().responses.is_empty());
+    }
+
+    #[test]
+    fn wait_retries_are_limited() {
+        let mut swd = swd(vec![ack(ACK_WAIT), ack(ACK_WAIT), ack(ACK_WAIT)]);
+        swd.set_wait_retries(2);
+        assert!(matches!(
+            swd.read_dp(dp::CTRL_STAT),
+            Err(Error::WaitTimeout(2))
+        ));
+    }
+
+    #[test]
+    fn fault_is_reported() {
+        let mut swd = swd(vec![ack(ACK_FAULT)]);
+        assert!(matches!(swd.write_dp(dp::CTRL_STAT, 0), Err(Error::Fault)));
+    }
+
+    #[test]
+    fn invalid_ack_resets_the_line() {
+        let mut swd = swd(vec![ack(0b111)]);
+        sent(&mut swd);
+        assert!(matches!(
+            swd.read_dp(dp::DPIDR),
+            Err(Error::Protocol(0b111))
+        ));
+        let sent = sent(&mut swd);
+        assert!(sent.ends_with(&[
+            0x80, SWDIO_HIGH, PINS_DRIVE, 0x19, 0x06, 0x00, 0xff, 0xff, 0xff, 0xff, 0xff, 0xff,
+            0xff, 0x1b, 0x07, 0x00
+        ]));
+    }
+}
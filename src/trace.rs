@@ -0,0 +1,168 @@
+//! Instrumentation of device operations with `tracing`
+//!
+//! Every operation runs in a span named after it and tagged with the identity
+//! of the device, and ends with an event reporting its duration or error. Reads
+//! and writes report their sizes; their payloads are emitted as hex at the
+//! `TRACE` level under the separate `ftdi::data` target, so that they can be
+//! enabled independently. Without the `tracing` feature all of this compiles
+//! down to plain calls.
+
+use std::fmt::{Debug, Display};
+use std::io;
+
+use crate::backend::Backend;
+use crate::DeviceId;
+
+/// Displays a possibly unknown device identity
+#[cfg(feature = "tracing")]
+struct Id<'a>(Option<&'a DeviceId>);
+
+#[cfg(feature = "tracing")]
+impl Display for Id<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        match self.0 {
+            Some(device) => Display::fmt(device, f),
+            None => f.write_str("unknown device"),
+        }
+    }
+}
+
+#[cfg(feature = "tracing")]
+struct Hex<'a>(&'a [u8]);
+
+#[cfg(feature = "tracing")]
+impl Display for Hex<'_> {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        for byte in self.0 {
+            write!(f, "{:02x}", byte)?;
+        }
+        Ok(())
+    }
+}
+
+/// The span of an operation, reporting its duration or error when finished
+#[cfg(feature = "tracing")]
+pub(crate) struct Span {
+    operation: &'static str,
+    started: std::time::Instant,
+    _entered: tracing::span::EnteredSpan,
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) struct Span;
+
+impl Span {
+    #[cfg(feature = "tracing")]
+    pub(crate) fn enter(device: Option<&DeviceId>, operation: &'static str) -> Self {
+        let span = tracing::debug_span!("ftdi", device = %Id(device), operation);
+        Span {
+            operation,
+            started: std::time::Instant::now(),
+            _entered: span.entered(),
+        }
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn enter(_device: Option<&DeviceId>, _operation: &'static str) -> Self {
+        Span
+    }
+
+    #[cfg(feature = "tracing")]
+    pub(crate) fn finish<T, E: Display>(
+        self,
+        args: impl Debug,
+        result: Result<T, E>,
+    ) -> Result<T, E> {
+        let elapsed_us = self.started.elapsed().as_micros() as u64;
+        match &result {
+            Ok(_) => tracing::debug!(?args, elapsed_us, "{} done", self.operation),
+            Err(error) => tracing::warn!(?args, elapsed_us, %error, "{} failed", self.operation),
+        }
+        result
+    }
+
+    #[cfg(not(feature = "tracing"))]
+    pub(crate) fn finish<T, E: Display>(
+        self,
+        _args: impl Debug,
+        result: Result<T, E>,
+    ) -> Result<T, E> {
+        result
+    }
+}
+
+/// Reads from the backend, empty polls being reported at the `TRACE` level only
+#[cfg(feature = "tracing")]
+pub(crate) fn read<B: Backend>(backend: &mut B, buf: &mut [u8]) -> io::Result<usize> {
+    let started = std::time::Instant::now();
+    let result = backend.read(buf);
+    let elapsed_us = started.elapsed().as_micros() as u64;
+    let device = Id(backend.device_id());
+    match &result {
+        Ok(0) => tracing::trace!(%device, requested = buf.len(), elapsed_us, "read nothing"),
+        Ok(len) => {
+            tracing::debug!(%device, requested = buf.len(), len, elapsed_us, "read");
+            tracing::trace!(target: "ftdi::data", %device, data = %Hex(&buf[..*len]), "read");
+        }
+        Err(error) => {
+            tracing::warn!(%device, requested = buf.len(), elapsed_us, %error, "read failed")
+        }
+    }
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn read<B: Backend>(backend: &mut B, buf: &mut [u8]) -> io::Result<usize> {
+    backend.read(buf)
+}
+
+#[cfg(feature = "tracing")]
+pub(crate) fn write<B: Backend>(backend: &mut B, buf: &[u8]) -> io::Result<usize> {
+    let started = std::time::Instant::now();
+    let result = backend.write(buf);
+    let elapsed_us = started.elapsed().as_micros() as u64;
+    let device = Id(backend.device_id());
+    match &result {
+        Ok(len) => {
+            tracing::debug!(%device, requested = buf.len(), len, elapsed_us, "write");
+            tracing::trace!(target: "ftdi::data", %device, data = %Hex(&buf[..*len]), "write");
+        }
+        Err(error) => {
+            tracing::warn!(%device, requested = buf.len(), elapsed_us, %error, "write failed")
+        }
+    }
+    result
+}
+
+#[cfg(not(feature = "tracing"))]
+pub(crate) fn write<B: Backend>(backend: &mut B, buf: &[u8]) -> io::Result<usize> {
+    backend.write(buf)
+}
+
+/// Reports an asynchronous write being submitted
+#[cfg(all(feature = "tracing", feature = "libftdi1-sys"))]
+pub(crate) fn async_submitted(device: Option<&DeviceId>, data: &[u8], submitted: bool) {
+    let device = Id(device);
+    if submitted {
+        tracing::debug!(%device, len = data.len(), "async write submitted");
+        tracing::trace!(target: "ftdi::data", %device, data = %Hex(data), "async write");
+    } else {
+        tracing::warn!(%device, len = data.len(), "async write submission failed");
+    }
+}
+
+#[cfg(all(not(feature = "tracing"), feature = "libftdi1-sys"))]
+pub(crate) fn async_submitted(_device: Option<&DeviceId>, _data: &[u8], _submitted: bool) {}
+
+/// Reports an asynchronous write having completed
+#[cfg(all(feature = "tracing", feature = "libftdi1-sys"))]
+pub(crate) fn async_completed(device: Option<&DeviceId>, result: &io::Result<usize>) {
+    let device = Id(device);
+    match result {
+        Ok(len) => tracing::debug!(%device, len, "async write completed"),
+        Err(error) => tracing::warn!(%device, %error, "async write failed"),
+    }
+}
+
+#[cfg(all(not(feature = "tracing"), feature = "libftdi1-sys"))]
+pub(crate) fn async_completed(_device: Option<&DeviceId>, _result: &io::Result<usize>) {}
@@ -0,0 +1,627 @@
+//! Xilinx compact Serial Vector Format (XSVF) player
+//!
+//! XSVF is the binary counterpart of SVF described in Xilinx XAPP503. [`parse`]
+//! decodes a file into a list of [`Instruction`]s and [`Player`] executes them
+//! on a [`Jtag`] driver, including the `XREPEAT` retry sequence for failed
+//! `XSDR`/`XSDRTDO` comparisons.
+//!
+//! `XSDRINC` is not supported.
+
+use std::convert::TryFrom;
+use std::time::Duration;
+
+use ftdi_mpsse::MpsseCmdExecutor;
+use thiserror::Error;
+
+use crate::jtag::{self, Jtag, TapState};
+use crate::svf::HexData;
+
+#[derive(Debug, Error)]
+#[error("offset {offset}: {message}")]
+pub struct ParseError {
+    pub offset: usize,
+    pub message: &'static str,
+}
+
+#[derive(Debug, Error)]
+pub enum Error<E> {
+    #[error(transparent)]
+    Parse(#[from] ParseError),
+    #[error("instruction {index}: TDO mismatch, expected {expected} (mask {mask}), got {actual}")]
+    TdoMismatch {
+        index: usize,
+        expected: HexData,
+        actual: HexData,
+        mask: HexData,
+    },
+    #[error("JTAG operation failed")]
+    Jtag(#[source] jtag::Error<E>),
+}
+
+/// A decoded XSVF instruction
+///
+/// All bit vectors are converted to the LSB-first layout used by [`Jtag`].
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum Instruction {
+    Complete,
+    TdoMask(Vec<u8>),
+    /// `XSIR` and `XSIR2`
+    Sir {
+        length: usize,
+        tdi: Vec<u8>,
+    },
+    Sdr(Vec<u8>),
+    /// Run-Test/Idle time in microseconds
+    RunTest(u32),
+    Repeat(u8),
+    SdrSize(u32),
+    SdrTdo {
+        tdi: Vec<u8>,
+        tdo: Vec<u8>,
+    },
+    SetSdrMasks {
+        address: Vec<u8>,
+        data: Vec<u8>,
+    },
+    SdrB(Vec<u8>),
+    SdrC(Vec<u8>),
+    SdrE(Vec<u8>),
+    SdrTdoB {
+        tdi: Vec<u8>,
+        tdo: Vec<u8>,
+    },
+    SdrTdoC {
+        tdi: Vec<u8>,
+        tdo: Vec<u8>,
+    },
+    SdrTdoE {
+        tdi: Vec<u8>,
+        tdo: Vec<u8>,
+    },
+    State(TapState),
+    EndIr(TapState),
+    EndDr(TapState),
+    Comment(String),
+    Wait {
+        wait_state: TapState,
+        end_state: TapState,
+        /// Wait time in microseconds
+        time: u32,
+    },
+}
+
+struct Reader<'a> {
+    data: &'a [u8],
+    offset: usize,
+}
+
+impl<'a> Reader<'a> {
+    fn error(&self, message: &'static str) -> ParseError {
+        ParseError {
+            offset: self.offset,
+            message,
+        }
+    }
+
+    fn bytes(&mut self, len: usize) -> Result<&'a [u8], ParseError> {
+        if self.data.len() - self.offset < len {
+            return Err(self.error("unexpected end of file"));
+        }
+        let bytes = &self.data[self.offset..self.offset + len];
+        self.offset += len;
+        Ok(bytes)
+    }
+
+    fn u8(&mut self) -> Result<u8, ParseError> {
+        Ok(self.bytes(1)?[0])
+    }
+
+    fn u16(&mut self) -> Result<u16, ParseError> {
+        let bytes = self.bytes(2)?;
+        Ok(u16::from_be_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn u32(&mut self) -> Result<u32, ParseError> {
+        let bytes = self.bytes(4)?;
+        Ok(u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+    }
+
+    /// Reads a big-endian bit vector, returning it LSB first
+    fn vector(&mut self, bits: usize) -> Result<Vec<u8>, ParseError> {
+        let mut data = self.bytes(bits.div_ceil(8))?.to_vec();
+        data.reverse();
+        Ok(data)
+    }
+
+    fn state(&mut self) -> Result<TapState, ParseError> {
+        let state = match self.u8()? {
+            0x00 => TapState::TestLogicReset,
+            0x01 => TapState::RunTestIdle,
+            0x02 => TapState::SelectDrScan,
+            0x03 => TapState::CaptureDr,
+            0x04 => TapState::ShiftDr,
+            0x05 => TapState::Exit1Dr,
+            0x06 => TapState::PauseDr,
+            0x07 => TapState::Exit2Dr,
+            0x08 => TapState::UpdateDr,
+            0x09 => TapState::SelectIrScan,
+            0x0a => TapState::CaptureIr,
+            0x0b => TapState::ShiftIr,
+            0x0c => TapState::Exit1Ir,
+            0x0d => TapState::PauseIr,
+            0x0e => TapState::Exit2Ir,
+            0x0f => TapState::UpdateIr,
+            _ => return Err(self.error("invalid TAP state")),
+        };
+        Ok(state)
+    }
+}
+
+/// Decodes the contents of an XSVF file
+///
+/// Decoding stops after the first `XCOMPLETE` instruction.
+pub fn parse(data: &[u8]) -> Result<Vec<Instruction>, ParseError> {
+    let mut reader = Reader { data, offset: 0 };
+    let mut instructions = vec![];
+    let mut sdr_size = 0usize;
+
+    while reader.offset < data.len() {
+        let instruction = match reader.u8()? {
+            0x00 => Instruction::Complete,
+            0x01 => Instruction::TdoMask(reader.vector(sdr_size)?),
+            0x02 => {
+                let length = usize::from(reader.u8()?);
+                let tdi = reader.vector(length)?;
+                Instruction::Sir { length, tdi }
+            }
+            0x03 => Instruction::Sdr(reader.vector(sdr_size)?),
+            0x04 => Instruction::RunTest(reader.u32()?),
+            0x07 => Instruction::Repeat(reader.u8()?),
+            0x08 => {
+                let size = reader.u32()?;
+                sdr_size = size as usize;
+                Instruction::SdrSize(size)
+            }
+            0x09 => Instruction::SdrTdo {
+                tdi: reader.vector(sdr_size)?,
+                tdo: reader.vector(sdr_size)?,
+            },
+            0x0a => Instruction::SetSdrMasks {
+                address: reader.vector(sdr_size)?,
+                data: reader.vector(sdr_size)?,
+            },
+            0x0b => return Err(reader.error("XSDRINC is not supported")),
+            0x0c => Instruction::SdrB(reader.vector(sdr_size)?),
+            0x0d => Instruction::SdrC(reader.vector(sdr_size)?),
+            0x0e => Instruction::SdrE(reader.vector(sdr_size)?),
+            0x0f => Instruction::SdrTdoB {
+                tdi: reader.vector(sdr_size)?,
+                tdo: reader.vector(sdr_size)?,
+            },
+            0x10 => Instruction::SdrTdoC {
+                tdi: reader.vector(sdr_size)?,
+                tdo: reader.vector(sdr_size)?,
+            },
+            0x11 => Instruction::SdrTdoE {
+                tdi: reader.vector(sdr_size)?,
+                tdo: reader.vector(sdr_size)?,
+            },
+            0x12 => Instruction::State(reader.state()?),
+            0x13 => match reader.u8()? {
+                0 => Instruction::EndIr(TapState::RunTestIdle),
+                1 => Instruction::EndIr(TapState::PauseIr),
+                _ => return Err(reader.error("invalid XENDIR state")),
+            },
+            0x14 => match reader.u8()? {
+                0 => Instruction::EndDr(TapState::RunTestIdle),
+                1 => Instruction::EndDr(TapState::PauseDr),
+                _ => return Err(reader.error("invalid XENDDR state")),
+            },
+            0x15 => {
+                let length = usize::from(reader.u16()?);
+                let tdi = reader.vector(length)?;
+                Instruction::Sir { length, tdi }
+            }
+            0x16 => {
+                let rest = &data[reader.offset..];
+                let len = rest
+                    .iter()
+                    .position(|&b| b == 0)
+                    .ok_or_else(|| reader.error("unterminated XCOMMENT"))?;
+                let text = String::from_utf8_lossy(&reader.bytes(len + 1)?[..len]);
+                Instruction::Comment(text.into_owned())
+            }
+            0x17 => Instruction::Wait {
+                wait_state: reader.state()?,
+                end_state: reader.state()?,
+                time: reader.u32()?,
+            },
+            _ => {
+                reader.offset -= 1;
+                return Err(reader.error("unknown instruction"));
+            }
+        };
+        let complete = instruction == Instruction::Complete;
+        instructions.push(instruction);
+        if complete {
+            break;
+        }
+    }
+    Ok(instructions)
+}
+
+/// Executes XSVF instructions on a JTAG driver
+///
+/// Wait times are converted to TCK cycles if the TCK frequency is known
+/// (see [`tck_frequency`](Player::tck_frequency)), otherwise the player sleeps.
+pub struct Player<'a, E: MpsseCmdExecutor> {
+    jtag: &'a mut Jtag<E>,
+    tck_frequency: Option<u32>,
+    sdr_size: usize,
+    tdo_mask: Option<Vec<u8>>,
+    /// The TDO of the last `XSDRTDO*`, also expected by the following `XSDR`
+    tdo_expected: Option<Vec<u8>>,
+    repeat: u8,
+    run_test: u32,
+    end_ir: TapState,
+    end_dr: TapState,
+}
+
+impl<'a, E: MpsseCmdExecutor> Player<'a, E> {
+    pub fn new(jtag: &'a mut Jtag<E>) -> Self {
+        Player {
+            jtag,
+            tck_frequency: None,
+            sdr_size: 0,
+            tdo_mask: None,
+            tdo_expected: None,
+            repeat: 32,
+            run_test: 0,
+            end_ir: TapState::RunTestIdle,
+            end_dr: TapState::RunTestIdle,
+        }
+    }
+
+    /// Sets the TCK frequency used to convert wait times into clock cycles
+    pub fn tck_frequency(mut self, frequency: u32) -> Self {
+        self.tck_frequency = Some(frequency);
+        self
+    }
+
+    /// Decodes and executes the contents of an XSVF file
+    pub fn play(&mut self, data: &[u8]) -> Result<(), Error<E::Error>> {
+        self.run(&parse(data)?)
+    }
+
+    pub fn run(&mut self, instructions: &[Instruction]) -> Result<(), Error<E::Error>> {
+        for (index, instruction) in instructions.iter().enumerate() {
+            if *instruction == Instruction::Complete {
+                break;
+            }
+            self.execute(index, instruction)?;
+        }
+        Ok(())
+    }
+
+    /// Executes a single instruction, `index` being only used for error reporting
+    pub fn execute(
+        &mut self,
+        index: usize,
+        instruction: &Instruction,
+    ) -> Result<(), Error<E::Error>> {
+        match instruction {
+            Instruction::Complete | Instruction::Comment(_) | Instruction::SetSdrMasks { .. } => {}
+            Instruction::TdoMask(mask) => self.tdo_mask = Some(mask.clone()),
+            Instruction::Repeat(repeat) => self.repeat = *repeat,
+            Instruction::RunTest(time) => self.run_test = *time,
+            Instruction::SdrSize(size) => self.sdr_size = *size as usize,
+            Instruction::EndIr(state) => self.end_ir = *state,
+            Instruction::EndDr(state) => self.end_dr = *state,
+            Instruction::State(TapState::TestLogicReset) => {
+                self.jtag.reset().map_err(Error::Jtag)?
+            }
+            Instruction::State(state) => self.jtag.goto(*state).map_err(Error::Jtag)?,
+            Instruction::Sir { length, tdi } => {
+                let end = if self.run_test > 0 {
+                    TapState::RunTestIdle
+                } else {
+                    self.end_ir
+                };
+                self.jtag.write_ir(tdi, *length, end).map_err(Error::Jtag)?;
+                self.wait(self.run_test)?;
+            }
+            Instruction::Sdr(tdi) => {
+                let tdo = self.tdo_expected.clone();
+                self.shift_with_retry(index, tdi, tdo.as_deref())?
+            }
+            Instruction::SdrTdo { tdi, tdo } => {
+                self.tdo_expected = Some(tdo.clone());
+                self.shift_with_retry(index, tdi, Some(tdo))?
+            }
+            Instruction::SdrB(tdi) | Instruction::SdrC(tdi) => self
+                .jtag
+                .write_dr(tdi, self.sdr_size, TapState::ShiftDr)
+                .map_err(Error::Jtag)?,
+            Instruction::SdrE(tdi) => self
+                .jtag
+                .write_dr(tdi, self.sdr_size, self.end_dr)
+                .map_err(Error::Jtag)?,
+            Instruction::SdrTdoB { tdi, tdo } | Instruction::SdrTdoC { tdi, tdo } => {
+                self.tdo_expected = Some(tdo.clone());
+                self.shift_and_compare(index, tdi, tdo, TapState::ShiftDr)?
+            }
+            Instruction::SdrTdoE { tdi, tdo } => {
+                self.tdo_expected = Some(tdo.clone());
+                let end = self.end_dr;
+                self.shift_and_compare(index, tdi, tdo, end)?
+            }
+            Instruction::Wait {
+                wait_state,
+                end_state,
+                time,
+            } => {
+                self.jtag.goto(*wait_state).map_err(Error::Jtag)?;
+                self.wait(*time)?;
+                self.jtag.goto(*end_state).map_err(Error::Jtag)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// `XSDR`/`XSDRTDO` with the XAPP503 retry sequence on mismatch
+    fn shift_with_retry(
+        &mut self,
+        index: usize,
+        tdi: &[u8],
+        tdo: Option<&[u8]>,
+    ) -> Result<(), Error<E::Error>> {
+        let mut run_test = self.run_test;
+        let mut attempt = 0;
+        loop {
+            let result = match tdo {
+                Some(tdo) => self.shift_and_compare(index, tdi, tdo, TapState::Exit1Dr),
+                None => self
+                    .jtag
+                    .write_dr(tdi, self.sdr_size, TapState::Exit1Dr)
+                    .map_err(Error::Jtag),
+            };
+            match result {
+                Err(Error::TdoMismatch { .. }) if attempt < self.repeat => {
+                    attempt += 1;
+                    for &state in &[
+                        TapState::PauseDr,
+                        TapState::Exit2Dr,
+                        TapState::ShiftDr,
+                        TapState::Exit1Dr,
+                        TapState::UpdateDr,
+                        TapState::RunTestIdle,
+                    ] {
+                        self.jtag.goto(state).map_err(Error::Jtag)?;
+                    }
+                    // Each retry waits 25% longer
+                    run_test += run_test / 4;
+                    self.wait(run_test)?;
+                }
+                Err(err) => return Err(err),
+                Ok(()) => break,
+            }
+        }
+
+        if self.run_test > 0 {
+            self.jtag.goto(TapState::RunTestIdle).map_err(Error::Jtag)?;
+            self.wait(self.run_test)
+        } else {
+            self.jtag.goto(self.end_dr).map_err(Error::Jtag)
+        }
+    }
+
+    fn shift_and_compare(
+        &mut self,
+        index: usize,
+        tdi: &[u8],
+        tdo: &[u8],
+        end: TapState,
+    ) -> Result<(), Error<E::Error>> {
+        let bits = self.sdr_size;
+        let actual = self.jtag.shift_dr(tdi, bits, end).map_err(Error::Jtag)?;
+        // The mask is kept across XSDRSIZE, bits beyond it are compared
+        let mut mask = self.tdo_mask.clone().unwrap_or_default();
+        mask.resize(bits.div_ceil(8), 0xff);
+        let matches = actual
+            .iter()
+            .zip(tdo)
+            .zip(&mask)
+            .all(|((a, e), m)| (a ^ e) & m == 0);
+        if matches {
+            return Ok(());
+        }
+        Err(Error::TdoMismatch {
+            index,
+            expected: HexData {
+                bits,
+                data: tdo.to_vec(),
+            },
+            actual: HexData { bits, data: actual },
+            mask: HexData { bits, data: mask },
+        })
+    }
+
+    fn wait(&mut self, micros: u32) -> Result<(), Error<E::Error>> {
+        if micros == 0 {
+            return Ok(());
+        }
+        match self.tck_frequency {
+            Some(frequency) => {
+                let cycles = (u64::from(micros) * u64::from(frequency)).div_ceil(1_000_000);
+                let cycles = u32::try_from(cycles).unwrap_or(u32::MAX);
+                self.jtag.clock(cycles).map_err(Error::Jtag)
+            }
+            None => {
+                std::thread::sleep(Duration::from_micros(micros.into()));
+                Ok(())
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::mpsse::emulator::{Emulator, JtagTap};
+    use ftdi_mpsse::MpsseSettings;
+
+    fn jtag() -> Jtag<Emulator> {
+        let mut emulator = Emulator::new();
+        emulator.attach(JtagTap::new(4).with_register(0x2, 8));
+        emulator.init(&MpsseSettings::default()).unwrap();
+        Jtag::new(emulator).unwrap()
+    }
+
+    #[test]
+    fn xsdr_compares_against_the_last_xsdrtdo() {
+        let mut jtag = jtag();
+        let mut player = Player::new(&mut jtag);
+        let result = player.run(&[
+            Instruction::Repeat(0),
+            Instruction::Sir {
+                length: 4,
+                tdi: vec![0x2],
+            },
+            Instruction::SdrSize(8),
+            Instruction::SdrTdo {
+                tdi: vec![0x55],
+                tdo: vec![0x00],
+            },
+            // captures the 0x55 latched above instead of the expected 0x00
+            Instruction::Sdr(vec![0xaa]),
+        ]);
+        match result {
+            Err(Error::TdoMismatch { index, actual, .. }) => {
+                assert_eq!(index, 4);
+                assert_eq!(actual.data, [0x55]);
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+
+    #[test]
+    fn xsdrsize_keeps_the_tdo_mask() {
+        let mut jtag = jtag();
+        let mut player = Player::new(&mut jtag);
+        player
+            .run(&[
+                Instruction::Repeat(0),
+                Instruction::Sir {
+                    length: 4,
+                    tdi: vec![0x2],
+                },
+                Instruction::SdrSize(8),
+                Instruction::TdoMask(vec![0x0f]),
+                Instruction::SdrSize(8),
+                Instruction::SdrTdo {
+                    tdi: vec![0x50],
+                    tdo: vec![0x00],
+                },
+                // the upper nibble of the captured 0x50 is masked out
+                Instruction::SdrTdo {
+                    tdi: vec![0x00],
+                    tdo: vec![0x00],
+                },
+            ])
+            .unwrap();
+    }
+
+    #[test]
+    fn parse_converts_vectors_to_lsb_first() {
+        let instructions = parse(&[
+            0x07, 0x02, // XREPEAT 2
+            0x02, 0x0c, 0x0a, 0xbc, // XSIR 12 bits
+            0x08, 0x00, 0x00, 0x00, 0x10, // XSDRSIZE 16
+            0x09, 0x12, 0x34, 0x56, 0x78, // XSDRTDO
+            0x17, 0x01, 0x06, 0x00, 0x00, 0x03, 0xe8, // XWAIT
+            0x16, b'h', b'i', 0x00, // XCOMMENT
+            0x00, // XCOMPLETE
+            0xff, // ignored after XCOMPLETE
+        ])
+        .unwrap();
+        assert_eq!(
+            instructions,
+            [
+                Instruction::Repeat(2),
+                Instruction::Sir {
+                    length: 12,
+                    tdi: vec![0xbc, 0x0a],
+                },
+                Instruction::SdrSize(16),
+                Instruction::SdrTdo {
+                    tdi: vec![0x34, 0x12],
+                    tdo: vec![0x78, 0x56],
+                },
+                Instruction::Wait {
+                    wait_state: TapState::RunTestIdle,
+                    end_state: TapState::PauseDr,
+                    time: 1000,
+                },
+                Instruction::Comment("hi".to_string()),
+                Instruction::Complete,
+            ]
+        );
+    }
+
+    #[test]
+    fn parse_errors_report_the_offset() {
+        for (data, offset) in [
+            (&[0x07, 0x00, 0x42][..], 2),
+            (&[0x08, 0x00, 0x00, 0x00, 0x10, 0x03, 0x12], 6),
+            (&[0x12, 0x10], 2),
+            (&[0x0b], 1),
+            (&[0x16, b'h'], 1),
+        ] {
+            match parse(data) {
+                Err(err) => assert_eq!(err.offset, offset, "{:02x?}: {}", data, err),
+                Ok(instructions) => panic!("{:02x?} parsed as {:?}", data, instructions),
+            }
+        }
+    }
+
+    #[test]
+    fn play_writes_and_verifies_the_register() {
+        let mut jtag = jtag();
+        Player::new(&mut jtag)
+            .play(&[
+                0x07, 0x00, // XREPEAT 0
+                0x02, 0x04, 0x02, // XSIR 0x2
+                0x08, 0x00, 0x00, 0x00, 0x08, // XSDRSIZE 8
+                0x09, 0xa5, 0x00, // XSDRTDO, capturing the initial zeroes
+                0x09, 0x00, 0xa5, // XSDRTDO, capturing the 0xa5 written above
+                0x00, // XCOMPLETE
+            ])
+            .unwrap();
+        assert_eq!(jtag.state(), TapState::RunTestIdle);
+    }
+
+    #[test]
+    fn failed_comparison_is_retried() {
+        let mut jtag = jtag();
+        let result = Player::new(&mut jtag).play(&[
+            0x07, 0x03, // XREPEAT 3
+            0x02, 0x04, 0x02, // XSIR 0x2
+            0x08, 0x00, 0x00, 0x00, 0x08, // XSDRSIZE 8
+            0x09, 0xff, 0x01, // XSDRTDO never capturing the expected value
+            0x00, // XCOMPLETE
+        ]);
+        match result {
+            Err(Error::TdoMismatch {
+                index,
+                expected,
+                actual,
+                ..
+            }) => {
+                assert_eq!(index, 3);
+                assert_eq!(expected.data, [0x01]);
+                assert_eq!(actual.data, [0xff]);
+            }
+            result => panic!("unexpected {:?}", result),
+        }
+    }
+}